@@ -0,0 +1,257 @@
+//! A guided calibration of a device's pressure sensors against known loads,
+//! placed one after the other on a plate covering the insole.
+
+use crate::{
+    client,
+    mitch::{StreamMode, layout::PRESSURE_SENSORS},
+    protocol::{ClientCommand, DaemonResponse, GainCurve, read_frame},
+};
+use anyhow::{Result, anyhow};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+const STANDARD_GRAVITY: f32 = 9.806_65;
+
+/// Samples right after a load was placed are skipped while it settles.
+const SETTLE_TIME: f64 = 0.5;
+
+/// Sensors averaging fewer counts under the heaviest load are considered
+/// outside the plate and keep the nominal gain.
+const MIN_COUNTS: f32 = 5.0;
+
+/// Tares `name`, averages its sensors under each of `loads` in kilograms
+/// spread over a plate of `plate_area` square centimetres, and saves the
+/// gain curves fitted to them in the daemon.
+pub async fn run(
+    name: String,
+    mut loads: Vec<f32>,
+    plate_area: f32,
+    duration: Duration,
+    rate: u16,
+) -> Result<()> {
+    if loads.is_empty() || loads.iter().any(|load| *load <= 0.0) {
+        return Err(anyhow!("give at least one positive load"));
+    }
+    if plate_area <= 0.0 {
+        return Err(anyhow!("the plate area must be positive"));
+    }
+    loads.sort_by(f32::total_cmp);
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+
+    println!("Calibrating {name} with {} loads.", loads.len());
+    prompt(
+        &mut stdin,
+        "Remove everything from the insole and press Enter",
+    )
+    .await?;
+    match request(ClientCommand::Tare {
+        name: name.clone(),
+        duration,
+    })
+    .await?
+    {
+        DaemonResponse::Calibration(calibration) => println!("{calibration}"),
+        other => return Err(anyhow!("unexpected response {other:?}")),
+    }
+
+    let mut steps = Vec::new();
+    for load in &loads {
+        // The plate spreads the load's weight evenly over its area.
+        let kpa = load * STANDARD_GRAVITY / (plate_area * 1e-4) / 1e3;
+        prompt(
+            &mut stdin,
+            &format!("Place {load} kg on the plate ({kpa:.1} kPa) and press Enter"),
+        )
+        .await?;
+        let means = collect(&name, duration, rate).await?;
+        println!(
+            "  {:.0} counts on average",
+            means.iter().sum::<f32>() / means.len() as f32
+        );
+        steps.push((kpa, means));
+    }
+    prompt(&mut stdin, "Remove the plate and press Enter").await?;
+
+    let gains: Vec<Option<GainCurve>> = (0..PRESSURE_SENSORS.len())
+        .map(|i| {
+            let points: Vec<(f32, f32)> =
+                steps.iter().map(|(kpa, means)| (means[i], *kpa)).collect();
+            fit(&points)
+        })
+        .collect();
+    for (sensor, gain) in PRESSURE_SENSORS.iter().zip(&gains) {
+        match gain {
+            Some(gain) => println!(
+                "  {:<22} {:.4} kPa/count, {:+.2e} kPa/count²",
+                sensor.label, gain.linear, gain.quadratic
+            ),
+            None => println!("  {:<22} not loaded, keeps the nominal gain", sensor.label),
+        }
+    }
+
+    match request(ClientCommand::SetGains { name, gains }).await? {
+        DaemonResponse::Calibration(calibration) => {
+            println!("Saved: {calibration}");
+            Ok(())
+        }
+        other => Err(anyhow!("unexpected response {other:?}")),
+    }
+}
+
+async fn prompt<R>(stdin: &mut tokio::io::Lines<R>, text: &str) -> Result<()>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    println!("{text}");
+    stdin
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow!("calibration aborted"))?;
+    Ok(())
+}
+
+/// Sends a command answered by a single response, turning errors into ours.
+async fn request(command: ClientCommand) -> Result<DaemonResponse> {
+    let mut stream = client::send_command(command).await?;
+    match read_frame::<_, DaemonResponse>(&mut stream).await? {
+        Some(DaemonResponse::Error(e)) => Err(anyhow!(e)),
+        Some(response) => Ok(response),
+        None => Err(anyhow!("daemon closed the connection")),
+    }
+}
+
+/// Averages every sensor over `duration` once the load settled.
+async fn collect(name: &str, duration: Duration, rate: u16) -> Result<Vec<f32>> {
+    let mut stream = client::send_command(ClientCommand::Subscribe {
+        name: name.to_string(),
+        mode: StreamMode::Pressure,
+        rate,
+    })
+    .await?;
+    let mut sums = vec![0.0f64; PRESSURE_SENSORS.len()];
+    let mut count = 0u64;
+    let mut start = None;
+    loop {
+        let batch = match read_frame::<_, DaemonResponse>(&mut stream).await? {
+            Some(DaemonResponse::Samples(batch)) => batch,
+            Some(DaemonResponse::Error(e)) => return Err(anyhow!(e)),
+            Some(other) => return Err(anyhow!("unexpected response {other:?}")),
+            None => return Err(anyhow!("daemon closed the connection")),
+        };
+        if batch.mode != StreamMode::Pressure {
+            return Err(anyhow!("{name} streams {}, stop it first", batch.mode));
+        }
+        for sample in batch.samples {
+            let first = *start.get_or_insert(sample.timestamp);
+            let elapsed = sample.timestamp - first;
+            if elapsed < SETTLE_TIME {
+                continue;
+            }
+            if elapsed >= SETTLE_TIME + duration.as_secs_f64() && count > 0 {
+                return Ok(sums.iter().map(|sum| (sum / count as f64) as f32).collect());
+            }
+            for (sum, value) in sums.iter_mut().zip(&sample.values) {
+                *sum += *value as f64;
+            }
+            count += 1;
+        }
+    }
+}
+
+/// Fits `kpa = linear * counts + quadratic * counts²` through the origin,
+/// falling back to a line when there are too few points or the curve would
+/// bend back within the measured range.
+fn fit(points: &[(f32, f32)]) -> Option<GainCurve> {
+    let max = points.iter().map(|(c, _)| *c).fold(0.0, f32::max);
+    if max < MIN_COUNTS {
+        return None;
+    }
+    let (mut c2, mut c3, mut c4, mut pc, mut pc2) = (0.0f64, 0.0f64, 0.0f64, 0.0f64, 0.0f64);
+    for (c, p) in points {
+        let (c, p) = (*c as f64, *p as f64);
+        c2 += c * c;
+        c3 += c * c * c;
+        c4 += c * c * c * c;
+        pc += p * c;
+        pc2 += p * c * c;
+    }
+    let det = c2 * c4 - c3 * c3;
+    if points.len() >= 2 && det.abs() > f64::EPSILON * c2 * c4 {
+        let linear = (pc * c4 - pc2 * c3) / det;
+        let quadratic = (c2 * pc2 - c3 * pc) / det;
+        if linear > 0.0 && linear + 2.0 * quadratic * max as f64 > 0.0 {
+            return Some(GainCurve {
+                linear: linear as f32,
+                quadratic: quadratic as f32,
+            });
+        }
+    }
+    let linear = pc / c2;
+    (linear > 0.0).then_some(GainCurve {
+        linear: linear as f32,
+        quadratic: 0.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() <= 1e-4 * expected.abs().max(1.0),
+            "{actual} != {expected}"
+        );
+    }
+
+    #[test]
+    fn fits_a_line_exactly() {
+        let points: Vec<(f32, f32)> = [20.0, 60.0, 150.0].iter().map(|c| (*c, 2.5 * c)).collect();
+        let curve = fit(&points).unwrap();
+        assert_close(curve.linear, 2.5);
+        assert_close(curve.quadratic, 0.0);
+    }
+
+    #[test]
+    fn fits_a_quadratic() {
+        let points: Vec<(f32, f32)> = [10.0, 50.0, 100.0, 200.0]
+            .iter()
+            .map(|c| (*c, 2.0 * c + 0.01 * c * c))
+            .collect();
+        let curve = fit(&points).unwrap();
+        assert_close(curve.linear, 2.0);
+        assert_close(curve.quadratic, 0.01);
+    }
+
+    #[test]
+    fn single_load_fits_a_line() {
+        let curve = fit(&[(40.0, 100.0)]).unwrap();
+        assert_eq!(
+            curve,
+            GainCurve {
+                linear: 2.5,
+                quadratic: 0.0
+            }
+        );
+    }
+
+    #[test]
+    fn curve_bending_back_falls_back_to_a_line() {
+        // Saturating, a parabola through these peaks within the range.
+        let points = [(50.0, 100.0), (100.0, 150.0), (150.0, 155.0)];
+        let curve = fit(&points).unwrap();
+        assert_eq!(curve.quadratic, 0.0);
+        let (pc, c2) = points
+            .iter()
+            .fold((0.0, 0.0), |(pc, c2), (c, p)| (pc + p * c, c2 + c * c));
+        assert_close(curve.linear, pc / c2);
+    }
+
+    #[test]
+    fn sensor_outside_the_plate_keeps_nominal_gain() {
+        assert_eq!(fit(&[(1.0, 10.0), (4.0, 20.0)]), None);
+        assert_eq!(fit(&[]), None);
+        // Negative counts never make a gain.
+        assert_eq!(fit(&[(-20.0, 50.0), (6.0, -10.0)]), None);
+    }
+}
@@ -1,12 +1,128 @@
-use crate::protocol::{ClientCommand, DaemonResponse, IPC_SOCKET_PATH};
-use anyhow::Result;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::{
+    mitch::StreamMode,
+    protocol::{
+        ClientCommand, DaemonResponse, IPC_SOCKET_PATH, SampleBatch, read_frame, write_frame,
+    },
+};
+use anyhow::{Result, anyhow};
+use std::io::{self, Write};
+use tokio::io::AsyncWriteExt;
 
 #[cfg(unix)]
 use tokio::net::UnixStream;
 
 pub async fn run_client(command: ClientCommand) -> Result<()> {
-    #[cfg(unix)]
+    let mut stream = send_command(command).await?;
+
+    // Most commands answer with a single response, following commands keep
+    // sending until the daemon closes the stream.
+    while let Some(response) = read_frame::<_, DaemonResponse>(&mut stream).await? {
+        print_response(response);
+    }
+
+    Ok(())
+}
+
+/// How `stream` writes samples to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SampleFormat {
+    /// A header naming the channels, then one row per sample
+    Csv,
+    /// One JSON object per sample
+    Jsonl,
+}
+
+/// Writes `name`'s samples to stdout until interrupted or until whatever
+/// reads them goes away.
+pub async fn stream_samples(
+    name: String,
+    mode: StreamMode,
+    rate: u16,
+    format: SampleFormat,
+) -> Result<()> {
+    let mut stream = send_command(ClientCommand::Subscribe {
+        name: name.clone(),
+        mode,
+        rate,
+    })
+    .await?;
+    // Mode of the CSV header written, rows of another mode would not fit
+    // its columns.
+    let mut header_mode = None;
+    loop {
+        let batch = match read_frame::<_, DaemonResponse>(&mut stream).await? {
+            Some(DaemonResponse::Samples(batch)) => batch,
+            Some(DaemonResponse::Error(e)) => return Err(anyhow!(e)),
+            Some(other) => return Err(anyhow!("unexpected response {other:?}")),
+            None => return Ok(()),
+        };
+        if format == SampleFormat::Csv
+            && let Some(header_mode) = header_mode
+            && batch.mode != header_mode
+        {
+            return Err(anyhow!(
+                "{name} switched from {header_mode} to {}, stopping",
+                batch.mode
+            ));
+        }
+        let mut out = io::stdout().lock();
+        let res =
+            write_batch(&mut out, &batch, format, header_mode.is_none()).and_then(|()| out.flush());
+        match res {
+            Ok(()) => header_mode = Some(batch.mode),
+            // The reader, e.g. `head`, has seen enough.
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn write_batch(
+    out: &mut impl Write,
+    batch: &SampleBatch,
+    format: SampleFormat,
+    header: bool,
+) -> io::Result<()> {
+    let labels = batch.mode.channel_labels();
+    match format {
+        SampleFormat::Csv => {
+            if header {
+                writeln!(out, "timestamp,counter,{}", labels.join(","))?;
+            }
+            for sample in &batch.samples {
+                let values: Vec<String> = sample.values.iter().map(|v| v.to_string()).collect();
+                writeln!(
+                    out,
+                    "{:.6},{},{}",
+                    sample.timestamp,
+                    batch.counter,
+                    values.join(",")
+                )?;
+            }
+        }
+        SampleFormat::Jsonl => {
+            for sample in &batch.samples {
+                let channels: serde_json::Map<_, _> = labels
+                    .iter()
+                    .map(|label| label.to_string())
+                    .zip(sample.values.iter().map(|v| (*v).into()))
+                    .collect();
+                let line = serde_json::json!({
+                    "timestamp": sample.timestamp,
+                    "counter": batch.counter,
+                    "channels": channels,
+                });
+                writeln!(out, "{line}")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Connects to the daemon and sends `command`, returning the stream the
+/// responses arrive on.
+#[cfg(unix)]
+pub async fn send_command(command: ClientCommand) -> Result<UnixStream> {
     let mut stream = match UnixStream::connect(IPC_SOCKET_PATH).await {
         Ok(stream) => stream,
         Err(e) => {
@@ -16,21 +132,12 @@ pub async fn run_client(command: ClientCommand) -> Result<()> {
         }
     };
 
-    let command_json = serde_json::to_vec(&command)?;
-    let command_len = command_json.len() as u64;
-    stream.write_all(&command_len.to_le_bytes()).await?;
-    stream.write_all(&command_json).await?;
+    write_frame(&mut stream, &command).await?;
     stream.shutdown().await?;
+    Ok(stream)
+}
 
-    let mut len_buf = [0u8; 8];
-    stream.read_exact(&mut len_buf).await?;
-    let len = u64::from_le_bytes(len_buf) as usize;
-
-    let mut response_json = vec![0; len];
-    stream.read_exact(&mut response_json).await?;
-
-    let response: DaemonResponse = serde_json::from_slice(&response_json)?;
-
+fn print_response(response: DaemonResponse) {
     match response {
         DaemonResponse::Ok => println!("Success."),
         DaemonResponse::Error(err) => eprintln!("Daemon error: {}", err),
@@ -40,9 +147,36 @@ pub async fn run_client(command: ClientCommand) -> Result<()> {
             }
         }
         DaemonResponse::Status(device_status) => {
-            println!("{:?}", device_status)
+            if device_status.is_empty() {
+                println!("No devices connected.");
+            }
+            for status in device_status {
+                println!("{status}");
+            }
+        }
+        DaemonResponse::Event(event) => println!("{event}"),
+        DaemonResponse::Sessions(sessions) => {
+            if sessions.is_empty() {
+                println!("No sessions recorded.");
+            }
+            for session in sessions {
+                println!("{}", session.summary());
+            }
+        }
+        DaemonResponse::Session(session) => print!("{session}"),
+        DaemonResponse::Logs(logs) => {
+            if logs.is_empty() {
+                println!("No logs stored.");
+            }
+            for log in logs {
+                println!("{log}");
+            }
+        }
+        DaemonResponse::Calibration(calibration) => println!("{calibration}"),
+        DaemonResponse::Samples(batch) => {
+            for sample in batch.samples {
+                println!("{:.6} {:?}", sample.timestamp, sample.values);
+            }
         }
     }
-
-    Ok(())
 }
@@ -0,0 +1,119 @@
+//! Converts recordings into Parquet files for analysis in pandas, polars or
+//! anything else reading Arrow, with the same columns the daemon writes.
+
+use crate::{
+    daemon::{
+        capture::{self, CaptureKind},
+        decode::Decoder,
+        sink::ParquetSink,
+    },
+    mitch::Units,
+    replay::{self, Row},
+};
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Converts the CSV or XDF file or capture `input` into `output`, by default
+/// next to it with a `.parquet` extension. `stream` picks the device of an
+/// XDF file holding several.
+pub fn run(input: &Path, output: Option<PathBuf>, stream: Option<&str>) -> Result<()> {
+    let output = output.unwrap_or_else(|| input.with_extension("parquet"));
+    if output == input {
+        return Err(anyhow!("{} would overwrite itself", input.display()));
+    }
+    let rows = if capture::is_capture(input) {
+        convert_capture(input, &output)?
+    } else if replay::is_xdf(input) {
+        let (meta, rows) = replay::read_xdf(input, stream)?;
+        let sink = ParquetSink::create(&output, &meta.name, &meta, None, &[])?;
+        write_rows(sink, &rows)?;
+        rows.len()
+    } else {
+        convert_csv(input, &output)?
+    };
+    info!(
+        "Converted {} rows of {} into {}",
+        rows,
+        input.display(),
+        output.display()
+    );
+    Ok(())
+}
+
+fn convert_csv(input: &Path, output: &Path) -> Result<usize> {
+    let (mode, rows) = replay::read_csv(input)?;
+    let (meta, session) = match replay::session_entry(input) {
+        Some((log, device)) => {
+            info!("Using metadata of {} from its session log", device.name);
+            (replay::meta_from_session(device)?, Some(log))
+        }
+        None => {
+            warn!("No session log mentions {}, guessing", input.display());
+            (replay::guess_meta(input, mode, &rows)?, None)
+        }
+    };
+    let session_meta: Vec<(String, String)> = session
+        .as_ref()
+        .map(|log| log.meta.clone().into_iter().collect())
+        .unwrap_or_default();
+    let sink = ParquetSink::create(
+        output,
+        &meta.name,
+        &meta,
+        session.as_ref().map(|log| log.name.as_str()),
+        &session_meta,
+    )?;
+    write_rows(sink, &rows)?;
+    Ok(rows.len())
+}
+
+fn write_rows(mut sink: ParquetSink, rows: &[Row]) -> Result<()> {
+    for row in rows {
+        match row {
+            Row::Sample {
+                timestamp,
+                counter,
+                values,
+            } => sink.write_values(*timestamp, *counter, values)?,
+            Row::Marker(marker) => sink.write_marker(marker)?,
+        }
+    }
+    sink.close()
+}
+
+/// Decodes the notifications of a capture like the actor would have, the
+/// samples keep the raw counts.
+fn convert_capture(input: &Path, output: &Path) -> Result<usize> {
+    let records = capture::read_capture(input)?;
+    let stream = records
+        .iter()
+        .find(|record| record.kind == CaptureKind::Stream)
+        .map(|record| replay::stream_config(&record.data))
+        .transpose()?
+        .ok_or_else(|| anyhow!("{} never starts a stream", input.display()))?;
+    let meta = replay::file_meta(input, stream, Units::Raw);
+    let mut sink = ParquetSink::create(output, &meta.name, &meta, None, &[])?;
+    let mut decoder = Decoder::new(stream);
+    let mut rows = 0;
+    for record in &records {
+        match record.kind {
+            CaptureKind::Stream => {
+                if replay::stream_config(&record.data)? != stream {
+                    return Err(anyhow!("the capture switches to another stream"));
+                }
+                decoder = Decoder::new(stream);
+            }
+            CaptureKind::Write | CaptureKind::Read => {}
+            CaptureKind::Notification => match decoder.decode(&record.data, record.timestamp) {
+                Ok((counter, samples)) => {
+                    rows += samples.len();
+                    sink.write_samples(counter, &samples)?;
+                }
+                Err(e) => warn!("Dropping frame {:02x?}: {}", record.data, e),
+            },
+        }
+    }
+    sink.close()?;
+    Ok(rows)
+}
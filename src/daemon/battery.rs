@@ -0,0 +1,202 @@
+use clap::Args;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// How many readings are kept per device, a day's worth at the default
+/// polling interval.
+const HISTORY_LEN: usize = 1440;
+
+/// Readings older than this are not used to compute the charge trend.
+const TREND_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// The readings used for the trend must span at least this long, otherwise
+/// the 1% resolution of the charge makes the trend meaningless.
+const MIN_TREND_SPAN: Duration = Duration::from_secs(5 * 60);
+
+/// A threshold is only armed again once the charge rose this far above it,
+/// so a charge flickering around a threshold does not flood clients.
+const THRESHOLD_HYSTERESIS: u8 = 3;
+
+#[derive(Debug, Clone, Args)]
+pub struct BatteryConfig {
+    /// Seconds between battery readings of each connected device
+    #[clap(long, default_value_t = 60)]
+    pub battery_interval: u64,
+
+    /// Do not poll the battery while a device is streaming
+    #[clap(long)]
+    pub pause_battery_while_streaming: bool,
+
+    /// Charges in percent at which a low battery event is emitted
+    #[clap(long, value_delimiter = ',', default_value = "20,10,5")]
+    pub low_battery: Vec<u8>,
+
+    /// Minimum charge in percent required to start a recording without --force
+    #[clap(long, default_value_t = 15)]
+    pub min_record_charge: u8,
+}
+
+impl BatteryConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.battery_interval.max(1))
+    }
+}
+
+/// Keeps the battery history of a device and decides when to alert.
+pub struct BatteryMonitor {
+    thresholds: Vec<u8>,
+    history: VecDeque<(Instant, u8)>,
+    /// Thresholds the charge is currently below and that were reported.
+    alerted: Vec<u8>,
+}
+
+impl BatteryMonitor {
+    pub fn new(config: &BatteryConfig) -> Self {
+        Self {
+            thresholds: config.low_battery.clone(),
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            alerted: Vec::new(),
+        }
+    }
+
+    /// Records a reading and returns the lowest threshold it newly crossed.
+    pub fn record(&mut self, charge: u8) -> Option<u8> {
+        self.record_at(Instant::now(), charge)
+    }
+
+    fn record_at(&mut self, at: Instant, charge: u8) -> Option<u8> {
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back((at, charge));
+
+        self.alerted
+            .retain(|threshold| charge < threshold.saturating_add(THRESHOLD_HYSTERESIS));
+        let crossed = self
+            .thresholds
+            .iter()
+            .copied()
+            .filter(|threshold| charge < *threshold && !self.alerted.contains(threshold))
+            .min();
+        if crossed.is_some() {
+            // Everything above the crossed threshold is implied by it.
+            self.alerted.extend(
+                self.thresholds
+                    .iter()
+                    .filter(|threshold| charge < **threshold),
+            );
+            self.alerted.sort_unstable();
+            self.alerted.dedup();
+        }
+        crossed
+    }
+
+    /// Change of the charge in percent per hour, fitted over the readings in
+    /// the trend window.
+    pub fn trend(&self) -> Option<f32> {
+        let (newest, _) = *self.history.back()?;
+        let recent: Vec<(f64, f64)> = self
+            .history
+            .iter()
+            .filter(|(at, _)| newest.duration_since(*at) <= TREND_WINDOW)
+            .map(|(at, charge)| {
+                let hours_before = newest.duration_since(*at).as_secs_f64() / 3600.0;
+                (-hours_before, *charge as f64)
+            })
+            .collect();
+        let span = -recent.first()?.0;
+        if span * 3600.0 < MIN_TREND_SPAN.as_secs_f64() {
+            return None;
+        }
+
+        let n = recent.len() as f64;
+        let mean_t = recent.iter().map(|(t, _)| t).sum::<f64>() / n;
+        let mean_c = recent.iter().map(|(_, c)| c).sum::<f64>() / n;
+        let (cov, var) = recent.iter().fold((0.0, 0.0), |(cov, var), (t, c)| {
+            (
+                cov + (t - mean_t) * (c - mean_c),
+                var + (t - mean_t).powi(2),
+            )
+        });
+        Some((cov / var) as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor() -> BatteryMonitor {
+        BatteryMonitor::new(&BatteryConfig {
+            battery_interval: 60,
+            pause_battery_while_streaming: false,
+            low_battery: vec![20, 10, 5],
+            min_record_charge: 15,
+        })
+    }
+
+    fn minutes(start: Instant, minutes: u64) -> Instant {
+        start + Duration::from_secs(minutes * 60)
+    }
+
+    #[test]
+    fn single_reading_has_no_trend() {
+        let mut monitor = monitor();
+        assert_eq!(monitor.trend(), None);
+        monitor.record(80);
+        assert_eq!(monitor.trend(), None);
+    }
+
+    #[test]
+    fn trend_needs_a_long_enough_span() {
+        let mut monitor = monitor();
+        let start = Instant::now();
+        for minute in 0..4 {
+            monitor.record_at(minutes(start, minute), 80 - minute as u8);
+        }
+        assert_eq!(monitor.trend(), None);
+        monitor.record_at(minutes(start, 5), 75);
+        assert!(monitor.trend().is_some());
+    }
+
+    #[test]
+    fn trend_follows_the_last_hour() {
+        let mut monitor = monitor();
+        let start = Instant::now();
+        // An hour on the charger, then draining 12% an hour.
+        for minute in 0..=60 {
+            monitor.record_at(minutes(start, minute), 40 + (minute / 3) as u8);
+        }
+        for minute in 61..=120 {
+            monitor.record_at(minutes(start, minute), 60 - ((minute - 60) / 5) as u8);
+        }
+        let trend = monitor.trend().unwrap();
+        assert!((trend + 12.0).abs() < 0.5, "trend of {trend}%/h");
+    }
+
+    #[test]
+    fn alerts_once_per_threshold() {
+        let mut monitor = monitor();
+        assert_eq!(monitor.record(25), None);
+        assert_eq!(monitor.record(19), Some(20));
+        assert_eq!(monitor.record(18), None);
+        assert_eq!(monitor.record(9), Some(10));
+        // Skipping thresholds reports the lowest, the others are implied.
+        assert_eq!(monitor.record(4), Some(5));
+        assert_eq!(monitor.record(3), None);
+    }
+
+    #[test]
+    fn thresholds_rearm_above_the_hysteresis() {
+        let mut monitor = monitor();
+        assert_eq!(monitor.record(19), Some(20));
+        // Flickering around the threshold stays quiet.
+        assert_eq!(monitor.record(21), None);
+        assert_eq!(monitor.record(19), None);
+        // Charged well above it, the next drop alerts again.
+        assert_eq!(monitor.record(23), None);
+        assert_eq!(monitor.record(19), Some(20));
+    }
+}
@@ -0,0 +1,68 @@
+use crate::protocol::Calibration;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::{collections::BTreeMap, fs, io::ErrorKind, path::PathBuf};
+use tokio::sync::Mutex;
+
+#[derive(Debug, Clone, Args)]
+pub struct CalibrationConfig {
+    /// File the devices' calibrations are kept in, keyed by MAC address
+    #[clap(long, default_value = "mitch_calibrations.json")]
+    pub calibration_file: PathBuf,
+}
+
+/// The calibrations of every device ever calibrated, written back to disk
+/// whenever one changes.
+pub struct Calibrations {
+    path: PathBuf,
+    entries: Mutex<BTreeMap<String, Calibration>>,
+}
+
+impl Calibrations {
+    /// Loads the calibrations saved before. A file that cannot be read is an
+    /// error rather than a fresh start, the next change would overwrite it.
+    pub fn new(config: &CalibrationConfig) -> Result<Self> {
+        let path = config.calibration_file.clone();
+        let entries = match fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json).with_context(|| {
+                format!("failed to parse {}, fix or move it aside", path.display())
+            })?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read {}", path.display()));
+            }
+        };
+        Ok(Self {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Calibration of the device with address `mac`, empty if it was never
+    /// calibrated.
+    pub async fn get(&self, mac: &str) -> Calibration {
+        self.entries
+            .lock()
+            .await
+            .get(mac)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Changes the calibration of the device with address `mac` and saves all
+    /// of them.
+    pub async fn update(&self, mac: &str, f: impl FnOnce(&mut Calibration)) -> Result<Calibration> {
+        let mut entries = self.entries.lock().await;
+        let calibration = entries.entry(mac.to_string()).or_default();
+        f(calibration);
+        let calibration = calibration.clone();
+        let json = serde_json::to_vec_pretty(&*entries)?;
+        // Replaced in one go, a crash while writing leaves the old file.
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, json)
+            .and_then(|()| fs::rename(&tmp, &self.path))
+            .with_context(|| format!("failed to write {}", self.path.display()))?;
+        Ok(calibration)
+    }
+}
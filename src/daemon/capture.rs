@@ -0,0 +1,259 @@
+//! Captures of the raw traffic with a device, for reproducing what the
+//! firmware sent byte for byte.
+//!
+//! A capture starts with [`MAGIC`] followed by records laid out as `[kind,
+//! timestamp (f64), length (u16), data..]` with little endian numbers. The
+//! timestamp is the LSL clock when the host sent or received the data.
+
+use anyhow::{Context, Result, anyhow};
+use std::{
+    cell::{Cell, RefCell},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+pub const MAGIC: &[u8; 8] = b"MITCHCAP";
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureKind {
+    /// What the device was asked to stream, `[mode, rate (u16)]`.
+    Stream = 0,
+    /// A notification on the data characteristic.
+    Notification = 1,
+    /// A write to the command characteristic.
+    Write = 2,
+    /// The command characteristic read back after a write.
+    Read = 3,
+}
+
+impl TryFrom<u8> for CaptureKind {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(CaptureKind::Stream),
+            1 => Ok(CaptureKind::Notification),
+            2 => Ok(CaptureKind::Write),
+            3 => Ok(CaptureKind::Read),
+            other => Err(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureRecord {
+    pub kind: CaptureKind,
+    pub timestamp: f64,
+    pub data: Vec<u8>,
+}
+
+/// Appends records to a capture file. Recording takes `&self` so captures
+/// can be made from anywhere in the actor, after the first failure the
+/// writer gives up quietly rather than failing every notification.
+pub struct CaptureWriter {
+    path: PathBuf,
+    writer: RefCell<Option<BufWriter<File>>>,
+    last_flush: Cell<Instant>,
+}
+
+impl CaptureWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create_new(path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(MAGIC)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer: RefCell::new(Some(writer)),
+            last_flush: Cell::new(Instant::now()),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, kind: CaptureKind, timestamp: f64, data: &[u8]) -> Result<()> {
+        let mut writer = self.writer.borrow_mut();
+        let Some(w) = writer.as_mut() else {
+            return Ok(());
+        };
+        let mut res = write_record(w, kind, timestamp, data);
+        if res.is_ok() && self.last_flush.get().elapsed() >= FLUSH_INTERVAL {
+            res = w.flush().map_err(Into::into);
+            self.last_flush.set(Instant::now());
+        }
+        if res.is_err() {
+            *writer = None;
+        }
+        res.with_context(|| format!("failed to write {}", self.path.display()))
+    }
+
+    pub fn close(self) -> Result<()> {
+        if let Some(mut writer) = self.writer.into_inner() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn write_record(w: &mut impl Write, kind: CaptureKind, timestamp: f64, data: &[u8]) -> Result<()> {
+    let len = u16::try_from(data.len()).map_err(|_| anyhow!("record too long"))?;
+    w.write_all(&[kind as u8])?;
+    w.write_all(&timestamp.to_le_bytes())?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(data)?;
+    Ok(())
+}
+
+/// Whether `path` starts like a capture.
+pub fn is_capture(path: &Path) -> bool {
+    let mut magic = [0; MAGIC.len()];
+    File::open(path)
+        .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut magic))
+        .is_ok_and(|()| &magic == MAGIC)
+}
+
+/// Reads every record of the capture at `path`. A record cut short by a
+/// crash ends the capture.
+pub fn read_capture(path: &Path) -> Result<Vec<CaptureRecord>> {
+    let bytes = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut rest = bytes
+        .strip_prefix(MAGIC.as_slice())
+        .ok_or_else(|| anyhow!("{} is not a capture", path.display()))?;
+    let mut records = Vec::new();
+    while let [kind, t0, t1, t2, t3, t4, t5, t6, t7, l0, l1, tail @ ..] = rest {
+        let len = u16::from_le_bytes([*l0, *l1]) as usize;
+        let Some((data, tail)) = tail.split_at_checked(len) else {
+            break;
+        };
+        let kind = CaptureKind::try_from(*kind)
+            .map_err(|kind| anyhow!("unknown record kind {kind} in {}", path.display()))?;
+        records.push(CaptureRecord {
+            kind,
+            timestamp: f64::from_le_bytes([*t0, *t1, *t2, *t3, *t4, *t5, *t6, *t7]),
+            data: data.to_vec(),
+        });
+        rest = tail;
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        daemon::decode::Decoder,
+        mitch::{StreamConfig, StreamMode, frame::HEADER_LEN},
+    };
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mitch_{name}_{}.cap", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// A pressure notification carrying `samples`.
+    fn notification(counter: u16, samples: &[[u8; 16]]) -> Vec<u8> {
+        let payload: Vec<u8> = samples.concat();
+        let mut data = vec![0x01, payload.len() as u8];
+        data.extend_from_slice(&counter.to_le_bytes());
+        data.extend(payload);
+        assert_eq!(data.len(), HEADER_LEN + 16 * samples.len());
+        data
+    }
+
+    #[test]
+    fn captures_decode_like_the_live_stream() {
+        let path = temp_path("decode");
+        let writer = CaptureWriter::create(&path).unwrap();
+        let stream = StreamConfig::new(StreamMode::Pressure, 50).unwrap();
+        let mut config = vec![stream.mode().id()];
+        config.extend_from_slice(&stream.rate().to_le_bytes());
+        writer.record(CaptureKind::Stream, 10.0, &config).unwrap();
+        writer
+            .record(CaptureKind::Write, 10.0, &[0x02, 0x01, 0x02])
+            .unwrap();
+        writer
+            .record(
+                CaptureKind::Notification,
+                10.1,
+                &notification(7, &[[1; 16], [2; 16]]),
+            )
+            .unwrap();
+        writer
+            .record(
+                CaptureKind::Notification,
+                10.14,
+                &notification(8, &[[3; 16], [4; 16]]),
+            )
+            .unwrap();
+        writer.close().unwrap();
+
+        assert!(is_capture(&path));
+        let records = read_capture(&path).unwrap();
+        let kinds: Vec<CaptureKind> = records.iter().map(|r| r.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                CaptureKind::Stream,
+                CaptureKind::Write,
+                CaptureKind::Notification,
+                CaptureKind::Notification
+            ]
+        );
+        assert_eq!(records[0].data, config);
+        assert_eq!(records[2].timestamp, 10.1);
+
+        let mut decoder = Decoder::new(stream);
+        let mut decoded = Vec::new();
+        for record in records
+            .iter()
+            .filter(|r| r.kind == CaptureKind::Notification)
+        {
+            let (counter, samples) = decoder.decode(&record.data, record.timestamp).unwrap();
+            decoded.extend(samples.into_iter().map(|s| (counter, s)));
+        }
+        let values: Vec<(u16, i16)> = decoded.iter().map(|(c, s)| (*c, s.values[0])).collect();
+        assert_eq!(values, [(7, 1), (7, 2), (8, 3), (8, 4)]);
+        assert!(
+            decoded
+                .windows(2)
+                .all(|w| w[0].1.timestamp < w[1].1.timestamp)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_truncated_record_ends_the_capture() {
+        let path = temp_path("truncated");
+        let writer = CaptureWriter::create(&path).unwrap();
+        writer.record(CaptureKind::Read, 1.0, &[0xaa; 5]).unwrap();
+        writer
+            .record(CaptureKind::Notification, 2.0, &[0xbb; 20])
+            .unwrap();
+        writer.close().unwrap();
+        // Cut the last record short, as a crash mid-write would.
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 7]).unwrap();
+
+        let records = read_capture(&path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].kind, CaptureKind::Read);
+        assert_eq!(records[0].data, [0xaa; 5]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_files_that_are_no_capture() {
+        let path = temp_path("bogus");
+        fs::write(&path, b"timestamp,counter\n").unwrap();
+        assert!(!is_capture(&path));
+        assert!(read_capture(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,57 +1,60 @@
-use super::DeviceMap;
+use super::{DaemonState, DeviceHandle, SAMPLE_CAPACITY};
 use crate::{
     daemon::{DeviceCommand, device_actor::DeviceActor},
-    protocol::{ClientCommand, DaemonResponse, DeviceStatus},
+    mitch::{StreamConfig, StreamMode, dfu::FirmwareImage, layout::Side},
+    protocol::{
+        ClientCommand, DaemonResponse, DeviceHealth, DeviceStatus, SampleBatch, read_frame,
+        write_frame,
+    },
 };
 use ::futures::future::join_all;
 use anyhow::{Result, anyhow};
-use bluez_async::{AdapterInfo, BluetoothSession, DeviceInfo, MacAddress};
+use bluez_async::{DeviceInfo, MacAddress};
 use std::{process::Stdio, str::FromStr, time::Duration};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::{
-    sync::oneshot::{self},
+    sync::{
+        broadcast::{self, error::RecvError},
+        oneshot::{self},
+        watch,
+    },
     time,
 };
 use tracing::{info, warn};
 
+/// How long a single actor may take to answer a status request before its last
+/// known values are reported instead.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long to run discovery when looking for a device bluez does not know yet.
+const CONNECT_DISCOVERY: Duration = Duration::from_secs(5);
+
 pub struct Client {
-    session: BluetoothSession,
-    adapter: AdapterInfo,
-    device_map: DeviceMap,
+    state: DaemonState,
 }
 
 impl Client {
-    pub fn new(session: BluetoothSession, adapter: AdapterInfo, device_map: DeviceMap) -> Self {
-        Self {
-            session,
-            adapter,
-            device_map,
-        }
+    pub fn new(state: DaemonState) -> Self {
+        Self { state }
     }
 
     pub async fn handle<S>(&self, mut stream: S) -> Result<DaemonResponse>
     where
         S: AsyncRead + AsyncWrite + Unpin,
     {
-        let mut len_buf = [0u8; 8];
-        stream.read_exact(&mut len_buf).await?;
-        let len = u64::from_le_bytes(len_buf) as usize;
-        let mut command_json = vec![0; len];
-        stream.read_exact(&mut command_json).await?;
-        let command: ClientCommand = serde_json::from_slice(&command_json)?;
+        let command: ClientCommand = read_frame(&mut stream)
+            .await?
+            .ok_or(anyhow!("Client closed the connection without a command"))?;
         info!("Received new command: {command:?}");
 
         let response = match command {
             ClientCommand::Scan { timeout_ms } => {
-                self.session
-                    .start_discovery_on_adapter(&self.adapter.id)
-                    .await?;
-                time::sleep(Duration::from_millis(timeout_ms)).await;
-                self.session.stop_discovery().await?;
+                self.discover(Duration::from_millis(timeout_ms)).await?;
                 let mut devices = Vec::new();
                 for per in self
+                    .state
                     .session
-                    .get_devices_on_adapter(&self.adapter.id)
+                    .get_devices_on_adapter(&self.state.adapter.id)
                     .await?
                 {
                     let n = per.name.unwrap_or_default();
@@ -61,85 +64,413 @@ impl Client {
                 }
                 DaemonResponse::Devices(devices)
             }
-            ClientCommand::Connect { name } => self.connect(name.as_str()).await?,
+            ClientCommand::Connect {
+                name,
+                max_restarts,
+                side,
+            } => self.connect(name.as_str(), max_restarts, side).await,
             ClientCommand::Disconnect { name } => {
                 info!("Disconnecting from {}...", name);
-                let mut map = self.device_map.lock().await;
+                // Only hold the lock for the removal, the actor may be busy.
+                let handle = self.state.device_map.lock().await.remove(&name);
 
                 // Find the actor's channel and send Shutdown
-                if let Some(tx) = map.remove(&name) {
+                if let Some(handle) = handle {
                     // We don't care if the send fails (task might already be dead)
-                    let _ = tx.send(DeviceCommand::Shutdown).await;
+                    let _ = handle.tx.send(DeviceCommand::Shutdown).await;
+                    DaemonResponse::Ok
+                } else if self
+                    .state
+                    .failed_devices
+                    .lock()
+                    .await
+                    .remove(&name)
+                    .is_some()
+                {
                     DaemonResponse::Ok
                 } else {
                     DaemonResponse::Error("Device not connected".to_string())
                 }
             }
-            ClientCommand::Record { name } => {
+            ClientCommand::Record { name, options } => {
                 info!("Telling {} to record...", name);
-                let map = self.device_map.lock().await;
+                let handle = self.state.device_map.lock().await.get(&name).cloned();
 
-                if let Some(tx) = map.get(&name) {
-                    tx.send(DeviceCommand::StartRecording {
-                        lsl_stream_name: name,
-                    })
-                    .await?;
-                    DaemonResponse::Ok
+                if let Some(handle) = handle {
+                    let (reply, reply_rx) = oneshot::channel();
+                    handle
+                        .tx
+                        .send(DeviceCommand::StartRecording { options, reply })
+                        .await?;
+                    match reply_rx.await {
+                        Ok(Ok(())) => DaemonResponse::Ok,
+                        Ok(Err(e)) => DaemonResponse::Error(e),
+                        Err(_) => DaemonResponse::Error("Device actor stopped".to_string()),
+                    }
                 } else {
                     DaemonResponse::Error("Device not connected".to_string())
                 }
             }
-            ClientCommand::Status => {
-                let map = self.device_map.lock().await;
-                let mut status_fut = Vec::with_capacity(map.len());
-                for (_, c) in map.iter() {
-                    let (tx, rx) = oneshot::channel::<DeviceStatus>();
-                    c.send(DeviceCommand::Status { tx }).await?;
-                    status_fut.push(rx);
+            ClientCommand::StopRecording { name } => {
+                info!("Telling {} to stop recording...", name);
+                let handle = self.state.device_map.lock().await.get(&name).cloned();
+
+                if let Some(handle) = handle {
+                    let (reply, reply_rx) = oneshot::channel();
+                    handle
+                        .tx
+                        .send(DeviceCommand::StopRecording { reply })
+                        .await?;
+                    match reply_rx.await {
+                        Ok(Ok(())) => DaemonResponse::Ok,
+                        Ok(Err(e)) => DaemonResponse::Error(e),
+                        Err(_) => DaemonResponse::Error("Device actor stopped".to_string()),
+                    }
+                } else {
+                    DaemonResponse::Error("Device not connected".to_string())
                 }
-                let res = join_all(status_fut.into_iter())
+            }
+            ClientCommand::ListSessions => match self.state.sessions.list() {
+                Ok(sessions) => DaemonResponse::Sessions(sessions),
+                Err(e) => DaemonResponse::Error(format!("{e:#}")),
+            },
+            ClientCommand::ShowSession { name } => match self.state.sessions.load(&name).await {
+                Ok(session) => DaemonResponse::Session(session),
+                Err(e) => DaemonResponse::Error(format!("{e:#}")),
+            },
+            ClientCommand::Status => {
+                let handles: Vec<_> = self
+                    .state
+                    .device_map
+                    .lock()
                     .await
-                    .into_iter()
-                    .filter_map(|s| s.ok())
+                    .values()
+                    .cloned()
                     .collect();
+                let mut res: Vec<_> = join_all(handles.into_iter().map(Self::query_status)).await;
+                res.extend(self.state.failed_devices.lock().await.values().cloned());
+                res.sort_by(|a, b| a.name.cmp(&b.name));
                 DaemonResponse::Status(res)
             }
+            ClientCommand::Events => return self.follow_events(&mut stream).await,
+            ClientCommand::Marker { text } => match self.state.publish_marker(text) {
+                Ok(()) => DaemonResponse::Ok,
+                Err(e) => DaemonResponse::Error(format!("{e:#}")),
+            },
+            ClientCommand::DeviceLog { name, action } => {
+                return self
+                    .with_progress(&mut stream, &name, |reply| DeviceCommand::Log {
+                        action,
+                        reply,
+                    })
+                    .await;
+            }
+            ClientCommand::FirmwareUpdate { name, image } => {
+                let image = match FirmwareImage::load(&image) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        let response = DaemonResponse::Error(format!("{e:#}"));
+                        write_frame(&mut stream, &response).await?;
+                        return Ok(response);
+                    }
+                };
+                return self
+                    .with_progress(&mut stream, &name, |reply| DeviceCommand::FirmwareUpdate {
+                        image,
+                        reply,
+                    })
+                    .await;
+            }
+            ClientCommand::Tare { name, duration } => {
+                return self
+                    .with_progress(&mut stream, &name, |reply| DeviceCommand::Tare {
+                        duration,
+                        reply,
+                    })
+                    .await;
+            }
+            ClientCommand::SetGains { name, gains } => {
+                return self
+                    .with_progress(&mut stream, &name, |reply| DeviceCommand::SetGains {
+                        gains,
+                        reply,
+                    })
+                    .await;
+            }
+            ClientCommand::Subscribe { name, mode, rate } => {
+                return self.follow_samples(&mut stream, &name, mode, rate).await;
+            }
         };
 
-        let response_json = serde_json::to_vec(&response)?;
-        let len = response_json.len() as u64;
-        stream.write_all(&len.to_le_bytes()).await?;
-        stream.write_all(&response_json).await?;
+        write_frame(&mut stream, &response).await?;
         Ok(response)
     }
 
-    async fn connect(&self, name: &str) -> Result<DaemonResponse> {
-        info!("Connecting to {}...", name);
-        // 1. Find the peripheral (this is a simplified search)
-        self.session
-            .start_discovery_on_adapter(&self.adapter.id)
-            .await?;
-        time::sleep(Duration::from_secs(5)).await;
-        self.session.stop_discovery().await?;
-        let mut device = None;
-        for per in self
+    /// Forwards daemon events to the client until it disconnects.
+    async fn follow_events<S>(&self, stream: &mut S) -> Result<DaemonResponse>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let mut events = self.state.events.subscribe();
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if write_frame(stream, &DaemonResponse::Event(event))
+                        .await
+                        .is_err()
+                    {
+                        info!("Event follower disconnected");
+                        return Ok(DaemonResponse::Ok);
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Event follower lagged behind, {} events dropped", missed);
+                }
+                Err(RecvError::Closed) => return Ok(DaemonResponse::Ok),
+            }
+        }
+    }
+
+    /// Hands a long running command to the device, forwarding its progress
+    /// to the client until the device answers.
+    async fn with_progress<S>(
+        &self,
+        stream: &mut S,
+        name: &str,
+        command: impl FnOnce(oneshot::Sender<DaemonResponse>) -> DeviceCommand,
+    ) -> Result<DaemonResponse>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let handle = self.state.device_map.lock().await.get(name).cloned();
+        let Some(handle) = handle else {
+            let response = DaemonResponse::Error("Device not connected".to_string());
+            write_frame(stream, &response).await?;
+            return Ok(response);
+        };
+
+        let mut events = self.state.events.subscribe();
+        let (reply, mut reply_rx) = oneshot::channel();
+        handle.tx.send(command(reply)).await?;
+        let response = loop {
+            tokio::select! {
+                response = &mut reply_rx => {
+                    break response.unwrap_or_else(|_| {
+                        DaemonResponse::Error("Device actor stopped".to_string())
+                    });
+                }
+                Ok(event) = events.recv() => {
+                    if event.is_progress_of(name) {
+                        // The device finishes even if the client left.
+                        write_frame(stream, &DaemonResponse::Event(event)).await.ok();
+                    }
+                }
+            }
+        };
+        write_frame(stream, &response).await?;
+        Ok(response)
+    }
+
+    /// Forwards a device's samples to the client until it disconnects,
+    /// making sure the device streams in the first place.
+    async fn follow_samples<S>(
+        &self,
+        stream: &mut S,
+        name: &str,
+        mode: StreamMode,
+        rate: u16,
+    ) -> Result<DaemonResponse>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let mut samples = match self.start_samples(name, mode, rate).await {
+            Ok(samples) => samples,
+            Err(e) => {
+                let response = DaemonResponse::Error(e);
+                write_frame(stream, &response).await?;
+                return Ok(response);
+            }
+        };
+        loop {
+            match samples.recv().await {
+                Ok(batch) => {
+                    if write_frame(stream, &DaemonResponse::Samples(batch))
+                        .await
+                        .is_err()
+                    {
+                        info!("Sample subscriber of {} disconnected", name);
+                        return Ok(DaemonResponse::Ok);
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(
+                        "Sample subscriber of {} lagged, {} frames dropped",
+                        name, missed
+                    );
+                }
+                Err(RecvError::Closed) => return Ok(DaemonResponse::Ok),
+            }
+        }
+    }
+
+    async fn start_samples(
+        &self,
+        name: &str,
+        mode: StreamMode,
+        rate: u16,
+    ) -> Result<broadcast::Receiver<SampleBatch>, String> {
+        let stream = StreamConfig::new(mode, rate)?;
+        let handle = self
+            .state
+            .device_map
+            .lock()
+            .await
+            .get(name)
+            .cloned()
+            .ok_or("Device not connected".to_string())?;
+        // Subscribe before the stream starts so the first frames are not lost.
+        let samples = handle.samples.subscribe();
+        let (reply, reply_rx) = oneshot::channel();
+        handle
+            .tx
+            .send(DeviceCommand::EnsureStreaming { stream, reply })
+            .await
+            .map_err(|_| "Device actor stopped".to_string())?;
+        reply_rx
+            .await
+            .map_err(|_| "Device actor stopped".to_string())??;
+        Ok(samples)
+    }
+
+    /// Asks a single actor for its status, falling back to the values it
+    /// last published when it is reconnecting, gone, or does not answer
+    /// within [`STATUS_TIMEOUT`].
+    async fn query_status(handle: DeviceHandle) -> DeviceStatus {
+        let cached = handle.status.borrow().clone();
+        if cached.health == DeviceHealth::Reconnecting {
+            return cached;
+        }
+        let query = async {
+            let (status_tx, status_rx) = oneshot::channel::<DeviceStatus>();
+            handle
+                .tx
+                .send(DeviceCommand::Status { tx: status_tx })
+                .await
+                .ok()?;
+            status_rx.await.ok()
+        };
+        match time::timeout(STATUS_TIMEOUT, query).await {
+            Ok(Some(status)) => status,
+            Ok(None) => DeviceStatus {
+                health: DeviceHealth::Error("actor stopped".to_string()),
+                ..handle.status.borrow().clone()
+            },
+            Err(_) => {
+                warn!("Status request to {} timed out", cached.name);
+                DeviceStatus {
+                    health: DeviceHealth::Timeout,
+                    ..handle.status.borrow().clone()
+                }
+            }
+        }
+    }
+
+    /// Runs discovery on the adapter for `duration`. Discovery is shared
+    /// between clients, so it is only stopped once the last user is done.
+    async fn discover(&self, duration: Duration) -> Result<()> {
+        {
+            let mut users = self.state.discovery_users.lock().await;
+            if *users == 0 {
+                self.state
+                    .session
+                    .start_discovery_on_adapter(&self.state.adapter.id)
+                    .await?;
+            }
+            *users += 1;
+        }
+        time::sleep(duration).await;
+        let mut users = self.state.discovery_users.lock().await;
+        *users -= 1;
+        if *users == 0 {
+            self.state.session.stop_discovery().await?;
+        }
+        Ok(())
+    }
+
+    async fn find_device(&self, name: &str) -> Result<Option<DeviceInfo>> {
+        Ok(self
+            .state
             .session
-            .get_devices_on_adapter(&self.adapter.id)
+            .get_devices_on_adapter(&self.state.adapter.id)
             .await?
-        {
-            if let Some(ref n) = per.name
-                && n == name
-            {
-                device = Some(per);
+            .into_iter()
+            .find(|per| per.name.as_deref() == Some(name)))
+    }
+
+    async fn connect(&self, name: &str, max_restarts: u32, side: Option<Side>) -> DaemonResponse {
+        // Join an attempt that is already running for this device, if any.
+        let (outcome_tx, mut outcome_rx) = {
+            let mut pending = self.state.pending_connects.lock().await;
+            // Checked while holding `pending`: an attempt stores its handle
+            // before it leaves `pending`, so a finished attempt is never
+            // missed and followed by a second actor for the same device.
+            if self.state.device_map.lock().await.contains_key(name) {
+                info!("{} is already connected", name);
+                return DaemonResponse::Ok;
+            }
+            match pending.get(name) {
+                Some(rx) => (None, rx.clone()),
+                None => {
+                    let (tx, rx) = watch::channel(None);
+                    pending.insert(name.to_owned(), rx.clone());
+                    (Some(tx), rx)
+                }
             }
+        };
+
+        let outcome = match outcome_tx {
+            Some(outcome_tx) => {
+                let outcome = self
+                    .establish(name, max_restarts, side)
+                    .await
+                    .map_err(|e| e.to_string());
+                self.state.pending_connects.lock().await.remove(name);
+                outcome_tx.send_replace(Some(outcome.clone()));
+                outcome
+            }
+            None => {
+                info!("Connection to {} already in progress, waiting", name);
+                match outcome_rx.wait_for(Option::is_some).await {
+                    Ok(outcome) => outcome.clone().expect("waited for outcome"),
+                    Err(_) => Err(format!("Connection attempt to {name} was abandoned")),
+                }
+            }
+        };
+
+        match outcome {
+            Ok(()) => DaemonResponse::Ok,
+            Err(e) => DaemonResponse::Error(e),
         }
+    }
+
+    async fn establish(&self, name: &str, max_restarts: u32, side: Option<Side>) -> Result<()> {
+        info!("Connecting to {}...", name);
+        // 1. Find the peripheral, only running discovery if bluez does not
+        // already know it
+        let device = match self.find_device(name).await? {
+            Some(device) => Some(device),
+            None => {
+                self.discover(CONNECT_DISCOVERY).await?;
+                self.find_device(name).await?
+            }
+        };
 
         let Some(device) = device else {
-            return Ok(DaemonResponse::Error(format!("{name} not found")));
+            return Err(anyhow!("{name} not found"));
         };
 
         // 2. Connect
-        self.session.connect(&device.id).await?;
+        self.state.session.connect(&device.id).await?;
         info!("Daemon: Connected.");
 
         if let Err(e) = Self::update_connection(&device).await {
@@ -147,17 +478,40 @@ impl Client {
             warn!("Continuing with default config");
         }
 
-        // 3. Create the actor's command channel
+        // 3. Create the actor's command and status channels
         let (tx, rx) = tokio::sync::mpsc::channel(32); // 32 is a typical buffer size
-        let map_clone = self.device_map.clone();
+        let (samples, _) = broadcast::channel(SAMPLE_CAPACITY);
+        let (taps, _) = broadcast::channel(SAMPLE_CAPACITY);
+        let (status_tx, status_rx) = watch::channel(DeviceStatus::new(
+            name,
+            side.or_else(|| Side::from_name(name)),
+            device.mac_address.to_string(),
+        ));
 
-        DeviceActor::new(name, device, self.session.clone(), rx, map_clone).spawn();
+        // 4. Store the handle in the map before the actor can remove it again
+        self.state.failed_devices.lock().await.remove(name);
+        let handle = DeviceHandle {
+            tx,
+            status: status_rx,
+            samples,
+            taps,
+        };
+        let mut map = self.state.device_map.lock().await;
+        map.insert(name.to_owned(), handle.clone());
 
-        // 5. Store the sender in the map
-        let mut map = self.device_map.lock().await;
-        map.insert(name.to_owned(), tx);
+        // 5. Spawn the actor
+        DeviceActor::new(
+            name,
+            device,
+            rx,
+            status_tx,
+            &handle,
+            self.state.clone(),
+            max_restarts,
+        )
+        .spawn();
 
-        Ok(DaemonResponse::Ok)
+        Ok(())
     }
 
     pub(crate) async fn update_connection(device: &DeviceInfo) -> Result<()> {
@@ -0,0 +1,260 @@
+use crate::{mitch::frame::is_restart, protocol::ClockEstimate};
+use std::collections::VecDeque;
+
+/// Device time in seconds the model is fitted over, long enough to average
+/// out connection interval batching, short enough to follow drift.
+const WINDOW: f64 = 60.0;
+
+/// Frames needed before the fit is trusted over plain arrival times.
+const MIN_FRAMES: usize = 20;
+
+/// Seconds between samples held back to keep timestamps increasing.
+const MIN_STEP: f64 = 1e-6;
+
+/// Crystal drift is in the tens of ppm, anything beyond this is a bad fit.
+const MAX_DRIFT: f64 = 0.01;
+
+/// Maps the device's frame counter to the LSL clock.
+///
+/// Every frame's arrival time is the time it was sampled plus a varying BLE
+/// delay. Fitting arrival times against the device's own notion of time,
+/// the unwrapped frame counter times the frame period, yields timestamps
+/// that follow the device's steady clock instead of the radio's batching,
+/// and keeps following it as the two clocks drift apart.
+pub struct ClockModel {
+    rate: u16,
+    /// Nominal seconds per frame, known once the first frame arrived.
+    frame_period: Option<f64>,
+    last_counter: Option<u16>,
+    frame: u64,
+    /// Device time and arrival time of recent frames.
+    points: VecDeque<(f64, f64)>,
+    fit: Option<Fit>,
+    /// Timestamp of the last sample handed out, kept across restarts.
+    last_timestamp: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Fit {
+    /// LSL time at device time zero.
+    offset: f64,
+    /// LSL seconds per device second.
+    slope: f64,
+    /// Standard deviation of arrivals around the fit.
+    jitter: f64,
+}
+
+impl ClockModel {
+    pub fn new(rate: u16) -> Self {
+        Self {
+            rate,
+            frame_period: None,
+            last_counter: None,
+            frame: 0,
+            points: VecDeque::new(),
+            fit: None,
+            last_timestamp: None,
+        }
+    }
+
+    /// Records the arrival of a frame holding `count` samples and returns
+    /// the LSL timestamps of its samples, which always follow the samples of
+    /// earlier frames.
+    pub fn timestamps(&mut self, counter: u16, count: usize, arrival: f64) -> Vec<f64> {
+        let frame_period = *self
+            .frame_period
+            .get_or_insert(count as f64 / self.rate as f64);
+        if let Some(last) = self.last_counter {
+            let gap = counter.wrapping_sub(last);
+            if is_restart(gap) {
+                // The device started counting anew, so does the model.
+                let last_timestamp = self.last_timestamp;
+                *self = Self::new(self.rate);
+                self.frame_period = Some(frame_period);
+                self.last_timestamp = last_timestamp;
+            } else {
+                self.frame += gap as u64;
+            }
+        }
+        self.last_counter = Some(counter);
+
+        let device_time = self.frame as f64 * frame_period;
+        self.points.push_back((device_time, arrival));
+        while self
+            .points
+            .front()
+            .is_some_and(|(t, _)| device_time - t > WINDOW)
+        {
+            self.points.pop_front();
+        }
+        self.refit();
+
+        let (last, slope) = match self.fit {
+            Some(fit) => (fit.offset + fit.slope * device_time, fit.slope),
+            None => (arrival, 1.0),
+        };
+        let period = slope / self.rate as f64;
+        let mut timestamps: Vec<f64> = (0..count)
+            .map(|i| last - (count - 1 - i) as f64 * period)
+            .collect();
+        // A first fit or a restart can move the estimate back, samples that
+        // would go back in time are held just after the previous one instead
+        // until the estimate catches up.
+        if let Some(mut previous) = self.last_timestamp {
+            for timestamp in &mut timestamps {
+                *timestamp = timestamp.max(previous + MIN_STEP);
+                previous = *timestamp;
+            }
+        }
+        if let Some(last) = timestamps.last() {
+            self.last_timestamp = Some(*last);
+        }
+        timestamps
+    }
+
+    pub fn estimate(&self) -> Option<ClockEstimate> {
+        let fit = self.fit?;
+        Some(ClockEstimate {
+            offset: fit.offset,
+            // A fast device clock covers more device time per LSL second.
+            drift_ppm: (1.0 - fit.slope) * 1e6,
+            jitter_ms: fit.jitter * 1e3,
+        })
+    }
+
+    fn refit(&mut self) {
+        if self.points.len() < MIN_FRAMES {
+            return;
+        }
+        let n = self.points.len() as f64;
+        let mean_t = self.points.iter().map(|(t, _)| t).sum::<f64>() / n;
+        let mean_a = self.points.iter().map(|(_, a)| a).sum::<f64>() / n;
+        let (cov, var) = self.points.iter().fold((0.0, 0.0), |(cov, var), (t, a)| {
+            (
+                cov + (t - mean_t) * (a - mean_a),
+                var + (t - mean_t).powi(2),
+            )
+        });
+        let mut slope = cov / var;
+        if !slope.is_finite() || (slope - 1.0).abs() > MAX_DRIFT {
+            // Keep the nominal rate rather than bend timestamps on bad data.
+            slope = 1.0;
+        }
+        let offset = mean_a - slope * mean_t;
+        let residuals = self
+            .points
+            .iter()
+            .map(|(t, a)| (a - offset - slope * t).powi(2))
+            .sum::<f64>();
+        self.fit = Some(Fit {
+            offset,
+            slope,
+            jitter: (residuals / n).sqrt(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u16 = 100;
+    const SAMPLES: usize = 5;
+    const FRAME_PERIOD: f64 = SAMPLES as f64 / RATE as f64;
+
+    /// Deterministic delays uniform in [0, `max`).
+    fn delays(max: f64) -> impl FnMut() -> f64 {
+        let mut state = 0x2545_f491_u32;
+        move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as f64 / u32::MAX as f64 * max
+        }
+    }
+
+    /// Feeds frames of a device whose clock runs `drift_ppm` fast, sampled
+    /// from LSL time `start` and arriving after a fixed and a jittered delay.
+    fn feed(
+        model: &mut ClockModel,
+        frames: std::ops::Range<u32>,
+        first_counter: u16,
+        start: f64,
+        drift_ppm: f64,
+        delay: &mut impl FnMut() -> f64,
+    ) -> Vec<(f64, Vec<f64>)> {
+        let slope = 1.0 - drift_ppm * 1e-6;
+        frames
+            .map(|frame| {
+                let sampled = start + slope * frame as f64 * FRAME_PERIOD;
+                let counter = first_counter.wrapping_add(frame as u16);
+                let arrival = sampled + 0.02 + delay();
+                (sampled, model.timestamps(counter, SAMPLES, arrival))
+            })
+            .collect()
+    }
+
+    fn assert_increasing(timestamps: &[f64]) {
+        for pair in timestamps.windows(2) {
+            assert!(pair[1] > pair[0], "{} then {}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn recovers_drift_and_jitter() {
+        let mut model = ClockModel::new(RATE);
+        let frames = feed(&mut model, 0..2400, 0, 100.0, 50.0, &mut delays(0.004));
+        let estimate = model.estimate().unwrap();
+        assert!((estimate.drift_ppm - 50.0).abs() < 10.0, "{estimate:?}");
+        // Uniform jitter over 4 ms has a deviation of 1.15 ms.
+        assert!((estimate.jitter_ms - 1.15).abs() < 0.3, "{estimate:?}");
+
+        // Late frames are stamped at their sampling time plus the mean
+        // delay, without the jitter.
+        for (sampled, timestamps) in &frames[2000..] {
+            let error = timestamps.last().unwrap() - (sampled + 0.022);
+            assert!(error.abs() < 0.001, "off by {error}");
+        }
+        let all: Vec<f64> = frames.iter().flat_map(|(_, t)| t.clone()).collect();
+        assert_increasing(&all);
+    }
+
+    #[test]
+    fn counts_on_across_counter_wrap() {
+        let mut model = ClockModel::new(RATE);
+        let frames = feed(&mut model, 0..100, u16::MAX - 49, 0.0, 0.0, &mut || 0.0);
+        assert_eq!(model.frame, 99);
+        assert_eq!(model.points.len(), 100);
+        for pair in frames.windows(2) {
+            let step = pair[1].1[0] - pair[0].1[0];
+            assert!((step - FRAME_PERIOD).abs() < 1e-9, "step of {step}");
+        }
+    }
+
+    #[test]
+    fn restart_starts_over_without_going_back() {
+        let mut model = ClockModel::new(RATE);
+        let mut delay = delays(0.004);
+        let before = feed(&mut model, 0..100, 500, 0.0, 0.0, &mut delay);
+        assert!(model.estimate().is_some());
+        let previous = *before.last().unwrap().1.last().unwrap();
+
+        // The restarted device's first frame arrives earlier than the fit
+        // placed the previous one.
+        let restarted = model.timestamps(0, SAMPLES, previous - 0.01);
+        assert!(model.estimate().is_none());
+        assert_eq!(model.frame, 0);
+        assert!(restarted[0] > previous);
+        assert_increasing(&restarted);
+
+        let after = feed(&mut model, 1..100, 0, previous, 0.0, &mut delay);
+        let all: Vec<f64> = before
+            .iter()
+            .map(|(_, t)| t.clone())
+            .chain([restarted])
+            .chain(after.iter().map(|(_, t)| t.clone()))
+            .flatten()
+            .collect();
+        assert_increasing(&all);
+    }
+}
@@ -0,0 +1,49 @@
+use super::clock::ClockModel;
+use crate::{
+    mitch::{
+        StreamConfig,
+        frame::{Frame, FrameError},
+    },
+    protocol::{ClockEstimate, Sample},
+};
+
+/// Turns data notifications into timestamped samples. This is the part of
+/// an actor's pipeline that depends on nothing but the notifications and
+/// when they arrived, so captures can be fed through it without a device.
+pub struct Decoder {
+    stream: StreamConfig,
+    clock: ClockModel,
+}
+
+impl Decoder {
+    pub fn new(stream: StreamConfig) -> Self {
+        Self {
+            stream,
+            clock: ClockModel::new(stream.rate()),
+        }
+    }
+
+    pub fn stream(&self) -> StreamConfig {
+        self.stream
+    }
+
+    pub fn clock(&self) -> Option<ClockEstimate> {
+        self.clock.estimate()
+    }
+
+    /// Decodes a notification that arrived at LSL time `arrival`, returning
+    /// its frame counter and raw samples.
+    pub fn decode(&mut self, data: &[u8], arrival: f64) -> Result<(u16, Vec<Sample>), FrameError> {
+        let frame = Frame::parse(self.stream.mode(), data)?;
+        let timestamps = self
+            .clock
+            .timestamps(frame.counter, frame.samples.len(), arrival);
+        let samples = frame
+            .samples
+            .into_iter()
+            .zip(timestamps)
+            .map(|(values, timestamp)| Sample { timestamp, values })
+            .collect();
+        Ok((frame.counter, samples))
+    }
+}
@@ -0,0 +1,154 @@
+use crate::{
+    mitch::{
+        StreamMode,
+        layout::{InsoleSize, PRESSURE_SENSORS, Region, SENSOR_AREA_MM2, Side},
+    },
+    protocol::Calibration,
+};
+
+/// Label, unit and type of every derived channel, in sample order.
+pub const DERIVED_CHANNELS: [(&str, &str, &str); 7] = [
+    ("total_force", "N", "Force"),
+    ("cop_x", "mm", "Position"),
+    ("cop_y", "mm", "Position"),
+    ("heel_force", "N", "Force"),
+    ("midfoot_force", "N", "Force"),
+    ("forefoot_force", "N", "Force"),
+    ("toes_force", "N", "Force"),
+];
+
+const REGIONS: [Region; 4] = [
+    Region::Heel,
+    Region::Midfoot,
+    Region::Forefoot,
+    Region::Toes,
+];
+
+/// Below this total force in newtons the foot is in the air and the center
+/// of pressure is undefined.
+const MIN_COP_FORCE: f32 = 5.0;
+
+/// Computes total vertical force, center of pressure and regional forces
+/// from raw pressure samples of one insole.
+pub struct Derivation {
+    /// Sensor positions for the insole's size and side, in frame order.
+    positions: [(f32, f32); PRESSURE_SENSORS.len()],
+    /// Converts the counts to kPa.
+    calibration: Calibration,
+}
+
+impl Derivation {
+    pub fn new(size: InsoleSize, side: Side, calibration: Calibration) -> Self {
+        Self {
+            positions: PRESSURE_SENSORS.map(|sensor| sensor.position(size, side)),
+            calibration,
+        }
+    }
+
+    /// Derives a sample laid out like [`DERIVED_CHANNELS`], the center of
+    /// pressure is NaN while the foot is unloaded.
+    pub fn compute(&self, values: &[i16]) -> Vec<f32> {
+        let mut total = 0.0;
+        let mut moment = (0.0, 0.0);
+        let mut regions = [0.0; REGIONS.len()];
+        let pressures = self.calibration.to_physical(StreamMode::Pressure, values);
+        for ((sensor, (x, y)), kpa) in PRESSURE_SENSORS.iter().zip(&self.positions).zip(pressures) {
+            // Negative pressure is noise around an unloaded sensor.
+            let kpa = kpa.max(0.0);
+            let force = kpa * SENSOR_AREA_MM2 * 1e-3;
+            total += force;
+            moment.0 += force * x;
+            moment.1 += force * y;
+            if let Some(i) = REGIONS.iter().position(|r| *r == sensor.region) {
+                regions[i] += force;
+            }
+        }
+        let (cop_x, cop_y) = if total >= MIN_COP_FORCE {
+            (moment.0 / total, moment.1 / total)
+        } else {
+            (f32::NAN, f32::NAN)
+        };
+        let mut sample = vec![total, cop_x, cop_y];
+        sample.extend(regions);
+        sample
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-3, "{actual} != {expected}");
+    }
+
+    fn load(counts: &[(&str, i16)]) -> Vec<i16> {
+        PRESSURE_SENSORS
+            .iter()
+            .map(|sensor| {
+                counts
+                    .iter()
+                    .find(|(label, _)| *label == sensor.label)
+                    .map_or(0, |(_, c)| *c)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn single_sensor_puts_cop_at_its_position() {
+        let derivation = Derivation::new(InsoleSize::Large, Side::Right, Calibration::default());
+        // 40 counts are 100 kPa, over 154 mm² 15.4 N.
+        let sample = derivation.compute(&load(&[("mth5", 40)]));
+        assert_close(sample[0], 15.4);
+        // mth5 sits at (33, 170) mm on a medium insole, scaled by 1.08.
+        assert_close(sample[1], 35.64);
+        assert_close(sample[2], 183.6);
+        for (force, expected) in sample[3..].iter().zip([0.0, 0.0, 15.4, 0.0]) {
+            assert_close(*force, expected);
+        }
+    }
+
+    #[test]
+    fn uniform_load_puts_cop_at_the_centroid() {
+        let uniform = vec![10; PRESSURE_SENSORS.len()];
+        // Every sensor carries 25 kPa, 3.85 N, for 61.6 N in total.
+        let right = Derivation::new(InsoleSize::Medium, Side::Right, Calibration::default())
+            .compute(&uniform);
+        assert_close(right[0], 61.6);
+        assert_close(right[1], 38.0 / 16.0);
+        assert_close(right[2], 2172.0 / 16.0);
+        // Five sensors in the heel and forefoot, three in the midfoot and
+        // toes.
+        for (force, sensors) in right[3..].iter().zip([5.0, 3.0, 5.0, 3.0]) {
+            assert_close(*force, sensors * 3.85);
+        }
+
+        // The left insole is the mirror image.
+        let left = Derivation::new(InsoleSize::Medium, Side::Left, Calibration::default())
+            .compute(&uniform);
+        assert_close(left[1], -38.0 / 16.0);
+        assert_close(left[2], right[2]);
+    }
+
+    #[test]
+    fn symmetric_load_puts_cop_on_the_axis() {
+        let derivation = Derivation::new(InsoleSize::Medium, Side::Left, Calibration::default());
+        let sample = derivation.compute(&load(&[
+            ("heel_anterior_medial", 30),
+            ("heel_anterior_lateral", 30),
+        ]));
+        assert_close(sample[1], 0.0);
+        assert_close(sample[2], 60.0);
+        assert_close(sample[3], 2.0 * 75.0 * 0.154);
+    }
+
+    #[test]
+    fn unloaded_foot_has_no_cop() {
+        let derivation = Derivation::new(InsoleSize::Medium, Side::Right, Calibration::default());
+        // 3.85 N is below the minimum, the negative counts are noise.
+        let sample = derivation.compute(&load(&[("hallux", 10), ("toe_2_3", -50)]));
+        assert_close(sample[0], 3.85);
+        assert!(sample[1].is_nan() && sample[2].is_nan());
+        assert_close(sample[6], 3.85);
+    }
+}
@@ -1,15 +1,51 @@
-use super::{DeviceCommand, DeviceMap};
-use crate::{daemon::client::Client, mitch::Commands, protocol::DeviceStatus};
-use anyhow::{Result, anyhow};
+use super::{
+    DaemonState, DeviceCommand, DeviceHandle, Marker,
+    battery::BatteryMonitor,
+    capture::{CaptureKind, CaptureWriter},
+    decode::Decoder,
+    derived::Derivation,
+    gait::GaitDetector,
+    osc::OscSink,
+    outlet::{self, Outlet, StreamMeta},
+    session,
+    sink::Sink,
+};
+use crate::{
+    daemon::client::Client,
+    mitch::{
+        self, Commands, MitchState, StreamConfig, StreamMode, Units,
+        dfu::{self, DfuLink, FirmwareImage},
+        frame,
+        layout::PRESSURE_SENSORS,
+        memory::{self, StoredLog},
+    },
+    protocol::{
+        Calibration, DaemonEvent, DaemonResponse, DeviceHealth, DeviceIdentity, DeviceStatus,
+        GainCurve, LogAction, OutputFormat, RecordOptions, Sample, SampleBatch, SessionDevice,
+    },
+};
+use anyhow::{Context, Result, anyhow};
 use bluez_async::{
-    BluetoothSession, CharacteristicEvent, DeviceEvent, DeviceInfo, WriteOptions, WriteType,
+    BluetoothEvent, BluetoothSession, CharacteristicEvent, CharacteristicId, DeviceEvent,
+    DeviceInfo, WriteOptions, WriteType, uuid_from_u16,
+};
+use futures::{Stream, StreamExt as _};
+use lsl::ExPushable as _;
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
 };
-use core::panic;
-use futures::StreamExt as _;
-use lsl::{Pushable as _, StreamInfo, StreamOutlet};
-use std::time::Duration;
-use tokio::sync::mpsc::Receiver;
-use tracing::{info, warn};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::Receiver,
+        oneshot, watch,
+    },
+    time::{self, MissedTickBehavior},
+};
+use tracing::{error, info, warn};
 use uuid::{Uuid, uuid};
 
 pub const COMMAND_CHAR: Uuid = uuid!("d5913036-2d8a-41ee-85b9-4e361aa5c8a7");
@@ -17,12 +53,118 @@ pub const DATA_CHAR: Uuid = uuid!("09bf2c52-d1d9-c0b7-4145-475964544307");
 
 pub const SERVICE: Uuid = uuid!("c8c0a708-e361-4b5e-a365-98fa6b0a836f");
 
+/// The standard Device Information Service and the characteristics we read.
+const DEVICE_INFO_SERVICE: Uuid = uuid_from_u16(0x180A);
+const MODEL_NUMBER_CHAR: Uuid = uuid_from_u16(0x2A24);
+const SERIAL_NUMBER_CHAR: Uuid = uuid_from_u16(0x2A25);
+const FIRMWARE_REVISION_CHAR: Uuid = uuid_from_u16(0x2A26);
+const HARDWARE_REVISION_CHAR: Uuid = uuid_from_u16(0x2A27);
+const MANUFACTURER_NAME_CHAR: Uuid = uuid_from_u16(0x2A29);
+
+/// How long a readout may go without data before it is given up.
+const READOUT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sampling rate to tare at when the device is not streaming yet.
+const TARE_RATE: u16 = 50;
+
+/// Base delay before the supervisor restarts a failed actor, multiplied by
+/// the number of restarts so far.
+const RESTART_BACKOFF: Duration = Duration::from_secs(2);
+
 pub struct DeviceActor {
     name: String,
     device: DeviceInfo,
-    session: BluetoothSession,
     rx: Receiver<DeviceCommand>,
-    device_map: DeviceMap,
+    status: watch::Sender<DeviceStatus>,
+    samples: broadcast::Sender<SampleBatch>,
+    /// Passive listeners, not counted when deciding whether to stream.
+    taps: broadcast::Sender<SampleBatch>,
+    state: DaemonState,
+    max_restarts: u32,
+    /// Kept across reconnects and restarts so consumers never lose the stream.
+    lsl_outlet: Option<Outlet>,
+    /// What the device currently streams, for the outlet or for subscribers.
+    streaming: Option<StreamConfig>,
+    /// Decodes the notifications while streaming.
+    decoder: Option<Decoder>,
+    recording: Option<Recording>,
+    /// Applied to pressure samples before they go anywhere.
+    calibration: Calibration,
+    taring: Option<Taring>,
+    markers: broadcast::Receiver<Marker>,
+    battery: BatteryMonitor,
+    /// Set when told to shut down in the middle of a readout.
+    shutdown: bool,
+}
+
+/// Writes commands to the mitch's command characteristic and reads back the
+/// response.
+struct BluezLink<'a> {
+    session: &'a BluetoothSession,
+    cmd: &'a CharacteristicId,
+}
+
+impl DfuLink for BluezLink<'_> {
+    async fn request(&mut self, command: Vec<u8>) -> Result<Vec<u8>> {
+        self.session
+            .write_characteristic_value_with_options(
+                self.cmd,
+                command,
+                WriteOptions {
+                    write_type: Some(WriteType::WithResponse),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        Ok(self.session.read_characteristic_value(self.cmd).await?)
+    }
+}
+
+/// Sums of the raw pressure samples that arrived while taring.
+struct Taring {
+    sums: Vec<i64>,
+    count: u64,
+    until: time::Instant,
+    reply: oneshot::Sender<DaemonResponse>,
+}
+
+/// What the actor keeps about the recording in progress.
+struct Recording {
+    /// File the recording is also written to.
+    sink: Option<Sink>,
+    /// File the raw traffic with the device is written to.
+    capture: Option<CaptureWriter>,
+    osc: Option<OscSink>,
+    /// Whether the samples go to the LSL outlet.
+    lsl: bool,
+    session: Option<String>,
+    stop_at: Option<time::Instant>,
+    last_counter: Option<u16>,
+    gait: Option<GaitDetector>,
+    /// Kept up to date while recording, goes into the session log.
+    log: SessionDevice,
+}
+
+/// Closes a recording's output file rather than dropping it, a Parquet file
+/// is unreadable without the footer written on closing.
+fn close_sink(sink: &mut Option<Sink>, actor: &str) {
+    if let Some(sink) = sink.take() {
+        let path = sink.path().to_path_buf();
+        if let Err(e) = sink.close() {
+            warn!(
+                "Actor {}: failed to finish {}: {:#}",
+                actor,
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// The characteristics of the mitch service the actor talks to.
+struct MitchChars {
+    cmd: CharacteristicId,
+    data: CharacteristicId,
 }
 
 impl DeviceActor {
@@ -30,27 +172,110 @@ impl DeviceActor {
     pub fn new(
         name: &str,
         device: DeviceInfo,
-        session: BluetoothSession,
         rx: Receiver<DeviceCommand>,
-        device_map: DeviceMap,
+        status: watch::Sender<DeviceStatus>,
+        handle: &DeviceHandle,
+        state: DaemonState,
+        max_restarts: u32,
     ) -> Self {
+        let battery = BatteryMonitor::new(&state.config.battery);
+        let markers = state.markers.subscribe();
         Self {
             name: name.to_string(),
             device,
-            session,
             rx,
-            device_map,
+            status,
+            samples: handle.samples.clone(),
+            taps: handle.taps.clone(),
+            state,
+            max_restarts,
+            lsl_outlet: None,
+            streaming: None,
+            decoder: None,
+            recording: None,
+            calibration: Calibration::default(),
+            taring: None,
+            markers,
+            battery,
+            shutdown: false,
         }
     }
 
     pub fn spawn(self) {
-        tokio::task::spawn_local(self.task());
+        tokio::task::spawn_local(self.supervise());
+    }
+
+    /// Runs the actor, restarting it up to `max_restarts` times when it
+    /// fails. Once it stops for good the device is disconnected and removed
+    /// from the device map, failures are kept so clients can still see them.
+    async fn supervise(mut self) {
+        let mut restarts = 0;
+        let failure = loop {
+            let Err(e) = self.task().await else {
+                break None;
+            };
+            error!("Actor for {}: {:#}", self.name, e);
+            self.status
+                .send_modify(|s| s.health = DeviceHealth::Error(format!("{e:#}")));
+            if restarts >= self.max_restarts {
+                break Some(e);
+            }
+            restarts += 1;
+            let backoff = RESTART_BACKOFF * restarts;
+            warn!(
+                "Actor for {}: restarting in {}s ({}/{})",
+                self.name,
+                backoff.as_secs(),
+                restarts,
+                self.max_restarts
+            );
+            tokio::time::sleep(backoff).await;
+            if let Some(recording) = &mut self.recording {
+                recording.log.reconnects += 1;
+            }
+            if let Err(e) = self.state.session.connect(&self.device.id).await {
+                warn!(
+                    "Actor for {}: reconnect before restart failed: {}",
+                    self.name, e
+                );
+            }
+        };
+
+        info!("Actor for {}: Cleaning up resources...", self.name);
+        let reason = match &failure {
+            Some(e) => format!("failed: {e:#}"),
+            None => "device disconnected".to_string(),
+        };
+        self.finish_recording(reason).await;
+        self.state.session.disconnect(&self.device.id).await.ok();
+
+        let mut map = self.state.device_map.lock().await;
+        // A new actor may have been connected under the same name meanwhile.
+        if map
+            .get(&self.name)
+            .is_some_and(|handle| handle.status.same_channel(&self.status.subscribe()))
+        {
+            map.remove(&self.name);
+            if failure.is_some() {
+                self.state
+                    .failed_devices
+                    .lock()
+                    .await
+                    .insert(self.name.clone(), self.status.borrow().clone());
+            }
+        }
+        info!("Actor for {}: Shutdown complete.", self.name);
     }
 
-    async fn task(mut self) -> Result<()> {
+    /// Runs until the actor is told to shut down or fails.
+    async fn task(&mut self) -> Result<()> {
         info!("Actor for {}: Spawned.", self.name);
 
-        let mut notifications_stream = match self.session.device_event_stream(&self.device.id).await
+        let mut notifications_stream = match self
+            .state
+            .session
+            .device_event_stream(&self.device.id)
+            .await
         {
             Ok(stream) => stream.fuse(),
             Err(e) => {
@@ -62,147 +287,1235 @@ impl DeviceActor {
             }
         };
 
-        let mut lsl_outlet: Option<StreamOutlet> = None;
-        let service = self
-            .session
-            .get_service_by_uuid(&self.device.id, SERVICE)
-            .await?;
-        let cmd_char = self
-            .session
-            .get_characteristic_by_uuid(&service.id, COMMAND_CHAR)
-            .await?;
-        let data_char = self
-            .session
-            .get_characteristic_by_uuid(&service.id, DATA_CHAR)
-            .await?;
-        let data_id = data_char.id.clone();
+        let chars = self.resolve_chars().await?;
+        if self.status.borrow().identity.firmware_version.is_none() {
+            let identity = self.read_identity(&chars).await;
+            info!("Actor {}: {}", self.name, identity);
+            self.status.send_modify(|s| s.identity = identity);
+        }
+        let mac = self.status.borrow().identity.mac.clone();
+        self.calibration = self.state.calibrations.get(&mac).await;
+        // The device keeps logging while out of range, learn whether it does.
+        match self.request(&chars.cmd, Commands::GetState).await {
+            Ok(payload) => {
+                let state = payload.first().and_then(|b| MitchState::try_from(*b).ok());
+                info!("Actor {}: device state {:?}", self.name, state);
+                self.status
+                    .send_modify(|s| s.logging = state == Some(MitchState::SysLog));
+            }
+            Err(e) => warn!("Actor {}: failed to read device state: {}", self.name, e),
+        }
+        if let Some(stream) = self.streaming {
+            // Restarted while streaming, pick up where we left off.
+            self.start_stream(&chars, stream).await?;
+        }
+        self.status.send_modify(|s| s.health = DeviceHealth::Ok);
 
-        'main: loop {
+        let mut battery_poll = time::interval(self.state.config.battery.interval());
+        battery_poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
             tokio::select! {
+                _ = battery_poll.tick() => {
+                    if self.streaming.is_some() && self.state.config.battery.pause_battery_while_streaming {
+                        continue;
+                    }
+                    match self.read_battery(&chars.cmd).await {
+                        Ok(charge) => self.update_battery(charge),
+                        Err(e) => warn!("Actor {}: failed to poll battery: {}", self.name, e),
+                    }
+                },
+
                 maybe_command = self.rx.recv() => {
                     match maybe_command {
-                        Some(DeviceCommand::StartRecording { lsl_stream_name }) => {
-                            info!("Actor {}: Received StartRecording ({})", self.name, lsl_stream_name);
-
-                            let info =
-                                StreamInfo::new(self.name.as_str(), "Pressure", 16, 50.0, lsl::ChannelFormat::Int16, self.name.as_str())
-                                .unwrap();
-                            let outlet = StreamOutlet::new(&info, 1, 360).unwrap();
-                            lsl_outlet = Some(outlet);
-                            info!("Actor {}: LSL Outlet created.", self.name);
-
-                            self.session
-                                .write_characteristic_value_with_options(
-                                    &cmd_char.id,
-                                    Commands::StartPressureStream.as_ref(),
-                                    WriteOptions {
-                                        write_type: Some(WriteType::WithResponse),
-                                        ..Default::default()
-                                    },
-                                )
-                                .await?;
-                            self.session.read_characteristic_value(&cmd_char.id).await?;
-                            self.session.start_notify(&data_char.id).await?;
+                        Some(DeviceCommand::StartRecording { options, reply }) => {
+                            info!("Actor {}: Received StartRecording ({:?})", self.name, options);
+                            let res = self.start_recording(&chars, options).await;
+                            if let Err(e) = &res {
+                                warn!("Actor {}: failed to start recording: {:#}", self.name, e);
+                                self.stop_stream_if_unused(&chars).await;
+                            }
+                            reply.send(res.map_err(|e| format!("{e:#}"))).ok();
+                        }
+                        Some(DeviceCommand::StopRecording { reply }) => {
+                            info!("Actor {}: Received StopRecording", self.name);
+                            let res = if self.recording.is_some() {
+                                self.finish_recording("stopped".to_string()).await;
+                                self.stop_stream_if_unused(&chars).await;
+                                Ok(())
+                            } else {
+                                Err("not recording".to_string())
+                            };
+                            reply.send(res).ok();
+                        }
+                        Some(DeviceCommand::Log { action, reply }) => {
+                            info!("Actor {}: Received Log ({:?})", self.name, action);
+                            let response = self
+                                .device_log(&chars, &mut notifications_stream, action)
+                                .await
+                                .unwrap_or_else(|e| {
+                                    warn!("Actor {}: log action failed: {:#}", self.name, e);
+                                    DaemonResponse::Error(format!("{e:#}"))
+                                });
+                            reply.send(response).ok();
+                            if self.shutdown {
+                                info!("Actor {}: Shutting down after the readout.", self.name);
+                                return Ok(());
+                            }
+                        }
+                        Some(DeviceCommand::FirmwareUpdate { image, reply }) => {
+                            info!("Actor {}: Received FirmwareUpdate", self.name);
+                            match self.update_firmware(&chars, &image).await {
+                                Ok(()) => {
+                                    info!("Actor {}: rebooting into the new firmware, connect again once it is back", self.name);
+                                    reply.send(DaemonResponse::Ok).ok();
+                                    return Ok(());
+                                }
+                                Err(e) => {
+                                    warn!("Actor {}: firmware update failed: {:#}", self.name, e);
+                                    reply.send(DaemonResponse::Error(format!("{e:#}"))).ok();
+                                }
+                            }
+                        }
+                        Some(DeviceCommand::Tare { duration, reply }) => {
+                            info!("Actor {}: Received Tare ({:?})", self.name, duration);
+                            match self.start_tare(&chars).await {
+                                Ok(()) => {
+                                    self.taring = Some(Taring {
+                                        sums: vec![0; PRESSURE_SENSORS.len()],
+                                        count: 0,
+                                        until: time::Instant::now() + duration,
+                                        reply,
+                                    });
+                                }
+                                Err(e) => {
+                                    warn!("Actor {}: failed to tare: {:#}", self.name, e);
+                                    reply.send(DaemonResponse::Error(format!("{e:#}"))).ok();
+                                }
+                            }
+                        }
+                        Some(DeviceCommand::SetGains { gains, reply }) => {
+                            info!("Actor {}: Received SetGains", self.name);
+                            let response = match self.set_gains(gains).await {
+                                Ok(calibration) => DaemonResponse::Calibration(calibration),
+                                Err(e) => {
+                                    warn!("Actor {}: failed to save gains: {:#}", self.name, e);
+                                    DaemonResponse::Error(format!("{e:#}"))
+                                }
+                            };
+                            reply.send(response).ok();
+                        }
+                        Some(DeviceCommand::EnsureStreaming { stream, reply }) => {
+                            let res = match self.streaming {
+                                Some(_) => Ok(()),
+                                None => self.start_stream(&chars, stream).await,
+                            };
+                            if let Err(e) = &res {
+                                warn!("Actor {}: failed to start streaming: {:#}", self.name, e);
+                            }
+                            reply.send(res.map_err(|e| format!("{e:#}"))).ok();
                         }
                         Some(DeviceCommand::Shutdown) => {
                             info!("Actor {}: Received Shutdown command.", self.name);
-                            break; // Break the loop to enter cleanup
+                            return Ok(());
                         }
                         Some(DeviceCommand::Status { tx }) => {
-                            self.session
-                                .write_characteristic_value_with_options(
-                                    &cmd_char.id,
-                                    Commands::GetPower.as_ref(),
-                                    WriteOptions {
-                                        write_type: Some(WriteType::WithResponse),
-                                        ..Default::default()
-                                    },
-                                )
-                                .await?;
-                            let res = self.session.read_characteristic_value(&cmd_char.id).await?;
-                            let charge = if res[3] == 0 {
-                                Some(res[4])
-                            } else {
-                                None
-                            };
-                            tx.send(DeviceStatus{ name: self.name.clone(), battery_charge: charge }).ok();
+                            let clock = self.decoder.as_ref().and_then(Decoder::clock);
+                            self.status.send_modify(|s| s.clock = clock);
+                            match self.read_battery(&chars.cmd).await {
+                                Ok(charge) => {
+                                    self.status.send_modify(|s| s.health = DeviceHealth::Ok);
+                                    self.update_battery(charge);
+                                    tx.send(self.status.borrow().clone()).ok();
+                                }
+                                Err(e) => {
+                                    warn!("Actor {}: failed to read battery: {}", self.name, e);
+                                    tx.send(DeviceStatus {
+                                        health: DeviceHealth::Error(e.to_string()),
+                                        ..self.status.borrow().clone()
+                                    })
+                                    .ok();
+                                }
+                            }
                         }
                         None => {
                             info!("Actor {}: Command channel closed. Shutting down.", self.name);
-                            break; // Break the loop
+                            return Ok(());
+                        }
+                    }
+                },
+
+                marker = self.markers.recv() => match marker {
+                    Ok(marker) => {
+                        if let Some(sink) = self.recording.as_mut().and_then(|r| r.sink.as_mut())
+                            && let Err(e) = sink.write_marker(&marker)
+                        {
+                            warn!("Actor {}: failed to write marker to {}: {:#}", self.name, sink.path().display(), e);
                         }
                     }
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Actor {}: {} markers dropped", self.name, missed);
+                    }
+                    // The daemon state holds the sender as long as we run.
+                    Err(RecvError::Closed) => {}
+                },
+
+                _ = deadline(self.recording.as_ref().and_then(|r| r.stop_at)) => {
+                    info!("Actor {}: recording reached its duration", self.name);
+                    self.finish_recording("duration reached".to_string()).await;
+                    self.stop_stream_if_unused(&chars).await;
+                },
+
+                _ = deadline(self.taring.as_ref().map(|t| t.until)) => {
+                    self.finish_tare().await;
+                    self.stop_stream_if_unused(&chars).await;
                 },
 
                 maybe_data = notifications_stream.next() => {
                     match maybe_data {
-                        Some(bluez_async::BluetoothEvent::Characteristic { id, event }) => {
-                            if data_id == id  &&
-                                let Some(outlet) = lsl_outlet.as_ref() {
-                                    let CharacteristicEvent::Value { value: data } = event else {
-                                        panic!()
-                                    };
-                                    outlet
-                                        .push_sample(
-                                            &data[4..].iter().map(|b| *b as i16).collect::<Vec<i16>>(),
-                                        )
-                                        .unwrap();
-                            }
+                        Some(bluez_async::BluetoothEvent::Characteristic { id, event }) if id == chars.data => {
+                            let CharacteristicEvent::Value { value: data } = event else {
+                                warn!("Actor {}: unexpected data characteristic event {:?}", self.name, event);
+                                continue;
+                            };
+                            self.handle_data(&data);
+                            self.stop_stream_if_unused(&chars).await;
                         }
-                        Some(bluez_async::BluetoothEvent::Device { id: _, event: DeviceEvent::Connected { connected } }) => {
-                            if !connected {
-                                info!("Actor {}: lost connection attempting reconnect", self.name);
-                                let exp_backoff = [2, 4, 8, 16, u64::MAX];
-                                let max_attempts = exp_backoff.len() - 1;
-                                for (i, backoff) in exp_backoff.iter().enumerate() {
-                                    if self.session.connect(&self.device.id).await.is_err() {
-                                        if i == max_attempts {
-                                            warn!("Failed to reconnect to {} cleaning up", self.name);
-                                            break 'main;
-                                        }
-                                        warn!("Failed to reconnect to {}, attempting again in {}s", self.name, backoff);
-                                        tokio::time::sleep(Duration::from_secs(*backoff)).await;
-                                        continue
-                                    }
-                                    if lsl_outlet.is_some() {
-                                        self.session
-                                            .write_characteristic_value_with_options(
-                                                &cmd_char.id,
-                                                Commands::StartPressureStream.as_ref(),
-                                                WriteOptions {
-                                                    write_type: Some(WriteType::WithResponse),
-                                                    ..Default::default()
-                                                },
-                                            )
-                                            .await?;
-                                        self.session.read_characteristic_value(&cmd_char.id).await?;
-                                        self.session.start_notify(&data_char.id).await?;
+                        Some(bluez_async::BluetoothEvent::Device { id: _, event: DeviceEvent::Connected { connected: false } }) => {
+                            info!("Actor {}: lost connection attempting reconnect", self.name);
+                            self.status.send_modify(|s| s.health = DeviceHealth::Reconnecting);
+                            if let Some(recording) = &mut self.recording {
+                                recording.log.reconnects += 1;
+                            }
+                            let exp_backoff = [2, 4, 8, 16, u64::MAX];
+                            let max_attempts = exp_backoff.len() - 1;
+                            for (i, backoff) in exp_backoff.iter().enumerate() {
+                                if self.state.session.connect(&self.device.id).await.is_err() {
+                                    if i == max_attempts {
+                                        return Err(anyhow!("failed to reconnect to {}", self.name));
                                     }
-                                    break;
+                                    warn!("Failed to reconnect to {}, attempting again in {}s", self.name, backoff);
+                                    tokio::time::sleep(Duration::from_secs(*backoff)).await;
+                                    continue
                                 }
-                                info!("Actor {}: sucessfully reconnected", self.name);
-                                if let Err(e) = Client::update_connection(&self.device).await {
-                                    warn!("Failed to upgrade connection with error: {}", e);
-                                    warn!("Continuing with default config");
+                                if let Some(stream) = self.streaming {
+                                    self.start_stream(&chars, stream).await?;
                                 }
+                                break;
+                            }
+                            info!("Actor {}: sucessfully reconnected", self.name);
+                            self.status.send_modify(|s| s.health = DeviceHealth::Ok);
+                            if let Err(e) = Client::update_connection(&self.device).await {
+                                warn!("Failed to upgrade connection with error: {}", e);
+                                warn!("Continuing with default config");
                             }
                         }
                         None => {
-                            warn!("Actor {}: something strange happened cleaning up", self.name);
-                            break; // Break the loop
+                            return Err(anyhow!("device event stream ended unexpectedly"));
                         }
                         _ => {}
                     }
                 },
             }
         }
+    }
 
-        info!("Actor for {}: Cleaning up resources...", self.name);
-        self.session.disconnect(&self.device.id).await.ok();
+    async fn resolve_chars(&self) -> Result<MitchChars> {
+        let service = self
+            .state
+            .session
+            .get_service_by_uuid(&self.device.id, SERVICE)
+            .await?;
+        let cmd = self
+            .state
+            .session
+            .get_characteristic_by_uuid(&service.id, COMMAND_CHAR)
+            .await?;
+        let data = self
+            .state
+            .session
+            .get_characteristic_by_uuid(&service.id, DATA_CHAR)
+            .await?;
+        Ok(MitchChars {
+            cmd: cmd.id,
+            data: data.id,
+        })
+    }
 
-        let mut map = self.device_map.lock().await;
-        map.remove(&self.name);
-        info!("Actor for {}: Shutdown complete.", self.name);
+    async fn start_recording(&mut self, chars: &MitchChars, options: RecordOptions) -> Result<()> {
+        if self.taring.is_some() {
+            return Err(anyhow!(
+                "device is being tared, record once it is done so the recording keeps one offset"
+            ));
+        }
+        let min_charge = self.state.config.battery.min_record_charge;
+        match self.read_battery(&chars.cmd).await {
+            Ok(charge) => self.update_battery(charge),
+            Err(e) => warn!("Actor {}: failed to read battery: {}", self.name, e),
+        }
+        if let Some(charge) = self.status.borrow().battery_charge
+            && charge < min_charge
+            && !options.force
+        {
+            return Err(anyhow!(
+                "battery at {charge}%, below the minimum of {min_charge}% for recordings, use --force to record anyway"
+            ));
+        }
+
+        if let (Some(duration), Some(charge), Some(trend)) = (
+            options.duration,
+            self.status.borrow().battery_charge,
+            self.status.borrow().battery_trend,
+        ) && trend < 0.0
+            && (charge as f32 / -trend) < duration.as_secs_f32() / 3600.0
+            && !options.force
+        {
+            return Err(anyhow!(
+                "battery at {charge}% drains {:.1}%/h and will not last {}, use --force to record anyway",
+                -trend,
+                humantime::format_duration(duration)
+            ));
+        }
+
+        let stream = StreamConfig::new(options.mode, options.rate).map_err(|e| anyhow!(e))?;
+        let output = match (&options.output, &options.session) {
+            (Some(output), _) => Some(output.clone()),
+            (None, Some(session)) => Some(self.state.sessions.dir(session)?.join(format!(
+                "{}.{}",
+                self.name,
+                options
+                    .output_format
+                    .unwrap_or(OutputFormat::Csv)
+                    .extension()
+            ))),
+            (None, None) => None,
+        };
+        if options.derived && options.mode != StreamMode::Pressure {
+            return Err(anyhow!("the derived stream needs the pressure mode"));
+        }
+        if options.derived && self.status.borrow().side.is_none() {
+            return Err(anyhow!(
+                "the derived stream needs the insole's side, connect with --side"
+            ));
+        }
+        let gait = options
+            .gait
+            .gait
+            .then(|| {
+                GaitDetector::new(
+                    &options.gait,
+                    options.mode,
+                    self.status.borrow().side,
+                    &self.name,
+                    self.calibration.clone(),
+                )
+            })
+            .transpose()?;
+        if self.recording.is_some() {
+            self.finish_recording("replaced by a new recording".to_string())
+                .await;
+        }
+
+        let log = SessionDevice {
+            name: self.name.clone(),
+            side: self.status.borrow().side,
+            identity: self.status.borrow().identity.clone(),
+            mode: options.mode,
+            rate: options.rate,
+            units: options.units,
+            duration: options.duration,
+            file: output.clone(),
+            started_at: session::now(),
+            stopped_at: None,
+            lsl_start: lsl::local_clock(),
+            lsl_stop: None,
+            stop_reason: None,
+            reconnects: 0,
+            frames: 0,
+            lost_frames: 0,
+            clock: None,
+            calibration: self.pressure_calibration(options.mode),
+        };
+        if let Some(session) = &options.session {
+            self.state
+                .sessions
+                .join(session, &options.meta, log.clone())
+                .await?;
+        }
+        self.recording = Some(Recording {
+            sink: None,
+            capture: None,
+            osc: None,
+            lsl: !options.no_lsl,
+            session: options.session.clone(),
+            stop_at: None,
+            last_counter: None,
+            gait,
+            log,
+        });
+        // From here on the session knows about us, failures must end the
+        // recording so they show up in its log.
+        if let Err(e) = self.begin_recording(chars, options, stream, output).await {
+            self.finish_recording(format!("failed to start: {e:#}"))
+                .await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn begin_recording(
+        &mut self,
+        chars: &MitchChars,
+        options: RecordOptions,
+        stream: StreamConfig,
+        output: Option<PathBuf>,
+    ) -> Result<()> {
+        let side = self.status.borrow().side;
+        let calibration = self.pressure_calibration(options.mode);
+        let osc = OscSink::new(
+            &options.osc,
+            &self.name,
+            side,
+            options.mode,
+            options.units,
+            calibration.clone().unwrap_or_default(),
+            options.derived.then_some(side).flatten().map(|side| {
+                Derivation::new(
+                    options.insole_size,
+                    side,
+                    calibration.clone().unwrap_or_default(),
+                )
+            }),
+        )
+        .await?;
+        if let Some(osc) = &osc {
+            info!("Actor {}: sending OSC to {}", self.name, osc.target());
+        }
+        let identity = self.status.borrow().identity.clone();
+        let meta = StreamMeta {
+            name: options.stream_name.unwrap_or_else(|| self.name.clone()),
+            stream_type: options
+                .stream_type
+                .unwrap_or_else(|| options.mode.stream_type().to_string()),
+            source_id: outlet::source_id(&identity.mac, options.mode),
+            stream,
+            units: options.units,
+            side,
+            size: options.insole_size,
+            derived: options.derived,
+            calibration,
+            identity,
+        };
+        match &self.lsl_outlet {
+            _ if options.no_lsl => {}
+            Some(existing) if existing.meta.same_stream(&meta) => {
+                info!("Actor {}: Reusing LSL Outlet.", self.name);
+            }
+            _ => {
+                self.lsl_outlet = Some(Outlet::new(meta.clone())?);
+                info!("Actor {}: LSL Outlet created.", self.name);
+            }
+        }
+
+        if let Some(path) = &options.capture {
+            let capture = CaptureWriter::create(path)?;
+            let mut config = vec![stream.mode().id()];
+            config.extend_from_slice(&stream.rate().to_le_bytes());
+            capture.record(CaptureKind::Stream, lsl::local_clock(), &config)?;
+            info!("Actor {}: capturing to {}", self.name, path.display());
+            // Set before streaming starts so its command is captured too.
+            if let Some(recording) = &mut self.recording {
+                recording.capture = Some(capture);
+            }
+        }
+
+        self.start_stream(chars, stream).await?;
+        // Only once the device streams, so a failed start leaves no empty
+        // file behind. No samples are handled before it exists.
+        let sink = output
+            .as_deref()
+            .map(|path| {
+                Sink::create(
+                    options
+                        .output_format
+                        .unwrap_or_else(|| OutputFormat::of_path(path)),
+                    path,
+                    &self.name,
+                    &meta,
+                    options.session.as_deref(),
+                    &options.meta,
+                )
+            })
+            .transpose()?;
+        if let Some(sink) = &sink {
+            info!("Actor {}: writing to {}", self.name, sink.path().display());
+        }
+        if let Some(recording) = &mut self.recording {
+            recording.sink = sink;
+            recording.osc = osc;
+            recording.stop_at = options.duration.map(|d| time::Instant::now() + d);
+        }
+        self.status.send_modify(|s| s.recording = true);
         Ok(())
     }
+
+    /// Ends the recording in progress, if any, and logs why it ended.
+    async fn finish_recording(&mut self, reason: String) {
+        let Some(mut recording) = self.recording.take() else {
+            return;
+        };
+        self.status.send_modify(|s| s.recording = false);
+        close_sink(&mut recording.sink, &self.name);
+        if let Some(capture) = recording.capture.take() {
+            let path = capture.path().to_path_buf();
+            if let Err(e) = capture.close() {
+                warn!(
+                    "Actor {}: failed to finish {}: {:#}",
+                    self.name,
+                    path.display(),
+                    e
+                );
+            }
+        }
+        info!(
+            "Actor {}: recording ended ({}), {} frames, {} lost",
+            self.name, reason, recording.log.frames, recording.log.lost_frames
+        );
+        let log = &mut recording.log;
+        log.stopped_at = Some(session::now());
+        log.lsl_stop = Some(lsl::local_clock());
+        log.stop_reason = Some(reason);
+        log.clock = self.decoder.as_ref().and_then(Decoder::clock);
+        if let Some(session) = recording.session {
+            self.state.sessions.leave(&session, recording.log).await;
+        }
+    }
+
+    /// The calibration applied to samples of `mode`, if any.
+    fn pressure_calibration(&self, mode: StreamMode) -> Option<Calibration> {
+        (mode == StreamMode::Pressure && !self.calibration.is_empty())
+            .then(|| self.calibration.clone())
+    }
+
+    /// Makes sure the device streams pressure for a tare.
+    async fn start_tare(&mut self, chars: &MitchChars) -> Result<()> {
+        if self.recording.is_some() {
+            return Err(anyhow!(
+                "device is recording, stop it first so the recording keeps one offset"
+            ));
+        }
+        if self.taring.is_some() {
+            return Err(anyhow!("device is already being tared"));
+        }
+        match self.streaming {
+            Some(stream) if stream.mode() != StreamMode::Pressure => Err(anyhow!(
+                "device streams {}, taring needs pressure",
+                stream.mode()
+            )),
+            Some(_) => Ok(()),
+            None => {
+                let stream =
+                    StreamConfig::new(StreamMode::Pressure, TARE_RATE).map_err(|e| anyhow!(e))?;
+                self.start_stream(chars, stream).await
+            }
+        }
+    }
+
+    /// Averages what arrived while taring into the device's new offsets.
+    async fn finish_tare(&mut self) {
+        let Some(taring) = self.taring.take() else {
+            return;
+        };
+        let response = match self.save_tare(&taring).await {
+            Ok(calibration) => DaemonResponse::Calibration(calibration),
+            Err(e) => {
+                warn!("Actor {}: failed to tare: {:#}", self.name, e);
+                DaemonResponse::Error(format!("{e:#}"))
+            }
+        };
+        taring.reply.send(response).ok();
+    }
+
+    async fn save_tare(&mut self, taring: &Taring) -> Result<Calibration> {
+        if taring.count == 0 {
+            return Err(anyhow!("no samples arrived while taring"));
+        }
+        let offsets: Vec<i16> = taring
+            .sums
+            .iter()
+            .map(|sum| (*sum as f64 / taring.count as f64).round() as i16)
+            .collect();
+        let mac = self.status.borrow().identity.mac.clone();
+        let calibration = self
+            .state
+            .calibrations
+            .update(&mac, |c| {
+                c.offsets = Some(offsets);
+                c.tared_at = Some(session::now());
+            })
+            .await?;
+        info!(
+            "Actor {}: tared over {} samples, {}",
+            self.name, taring.count, calibration
+        );
+        self.calibration = calibration.clone();
+        self.recalibrate_outlet();
+        Ok(calibration)
+    }
+
+    async fn set_gains(&mut self, gains: Vec<Option<GainCurve>>) -> Result<Calibration> {
+        if self.recording.is_some() {
+            return Err(anyhow!(
+                "device is recording, stop it first so the recording keeps one calibration"
+            ));
+        }
+        if gains.len() != PRESSURE_SENSORS.len() {
+            return Err(anyhow!(
+                "expected gains for {} sensors, got {}",
+                PRESSURE_SENSORS.len(),
+                gains.len()
+            ));
+        }
+        let mac = self.status.borrow().identity.mac.clone();
+        let calibration = self
+            .state
+            .calibrations
+            .update(&mac, |c| {
+                c.gains = Some(gains);
+                c.calibrated_at = Some(session::now());
+            })
+            .await?;
+        info!("Actor {}: {}", self.name, calibration);
+        self.calibration = calibration.clone();
+        self.recalibrate_outlet();
+        Ok(calibration)
+    }
+
+    /// Recreates a live outlet whose description and conversion still hold
+    /// the calibration the samples no longer get. Its source id stays, so
+    /// recorders pick the new outlet up.
+    fn recalibrate_outlet(&mut self) {
+        let Some(outlet) = &self.lsl_outlet else {
+            return;
+        };
+        let calibration = self.pressure_calibration(outlet.meta.stream.mode());
+        if outlet.meta.calibration == calibration {
+            return;
+        }
+        let meta = StreamMeta {
+            calibration,
+            ..outlet.meta.clone()
+        };
+        // The old outlet goes first, two outlets would share a source id.
+        self.lsl_outlet = None;
+        match Outlet::new(meta) {
+            Ok(outlet) => {
+                info!(
+                    "Actor {}: LSL Outlet recreated with the new calibration.",
+                    self.name
+                );
+                self.lsl_outlet = Some(outlet);
+            }
+            Err(e) => warn!(
+                "Actor {}: failed to recreate LSL outlet: {:#}",
+                self.name, e
+            ),
+        }
+    }
+
+    /// Runs the recording's gait detector over `samples`, publishing what it
+    /// finds on the gait outlet and into the file sink.
+    fn detect_gait(&mut self, samples: &[Sample]) {
+        let Some(recording) = &mut self.recording else {
+            return;
+        };
+        let Some(detector) = &mut recording.gait else {
+            return;
+        };
+        for sample in samples {
+            let Some(event) = detector.update(sample.timestamp, &sample.values) else {
+                continue;
+            };
+            let marker = Marker {
+                timestamp: event.timestamp,
+                text: event.to_string(),
+            };
+            if let Err(e) = self.state.gait_outlet.push(&marker) {
+                warn!("Actor {}: {:#}", self.name, e);
+            }
+            if let Some(sink) = &mut recording.sink
+                && let Err(e) = sink.write_marker(&marker)
+            {
+                warn!(
+                    "Actor {}: failed to write to {}, closing it: {:#}",
+                    self.name,
+                    sink.path().display(),
+                    e
+                );
+                close_sink(&mut recording.sink, &self.name);
+            }
+        }
+    }
+
+    async fn start_stream(&mut self, chars: &MitchChars, stream: StreamConfig) -> Result<()> {
+        if self.status.borrow().logging {
+            return Err(anyhow!(
+                "device is logging to its memory, stop logging first"
+            ));
+        }
+        self.command(&chars.cmd, Commands::StartStream(stream))
+            .await?;
+        self.state.session.start_notify(&chars.data).await?;
+        self.streaming = Some(stream);
+        self.decoder = Some(Decoder::new(stream));
+        // The new decoder counts frames anew, so does the recording.
+        if let Some(recording) = &mut self.recording {
+            recording.last_counter = None;
+        }
+        Ok(())
+    }
+
+    async fn stop_stream(&mut self, chars: &MitchChars) -> Result<()> {
+        self.streaming = None;
+        self.decoder = None;
+        if let Some(recording) = &mut self.recording {
+            recording.last_counter = None;
+        }
+        self.status.send_modify(|s| s.clock = None);
+        self.state.session.stop_notify(&chars.data).await?;
+        self.command(&chars.cmd, Commands::StopStream).await?;
+        Ok(())
+    }
+
+    /// Stops the device streaming once neither a recording nor a subscriber
+    /// needs its samples.
+    async fn stop_stream_if_unused(&mut self, chars: &MitchChars) {
+        if self.streaming.is_none()
+            || self.recording.is_some()
+            || self.taring.is_some()
+            || self.samples.receiver_count() > 0
+        {
+            return;
+        }
+        info!(
+            "Actor {}: nobody consumes samples, stopping stream",
+            self.name
+        );
+        if let Err(e) = self.stop_stream(chars).await {
+            warn!("Actor {}: failed to stop streaming: {}", self.name, e);
+        }
+    }
+
+    /// Decodes a data notification and publishes its samples.
+    fn handle_data(&mut self, data: &[u8]) {
+        let arrival = lsl::local_clock();
+        self.capture(CaptureKind::Notification, data);
+        let Some(decoder) = &mut self.decoder else {
+            return;
+        };
+        let stream = decoder.stream();
+        let (counter, mut samples) = match decoder.decode(data, arrival) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                warn!("Actor {}: dropping frame: {}", self.name, e);
+                return;
+            }
+        };
+
+        if stream.mode() == StreamMode::Pressure {
+            if let Some(taring) = &mut self.taring {
+                for sample in &samples {
+                    for (sum, value) in taring.sums.iter_mut().zip(&sample.values) {
+                        *sum += *value as i64;
+                    }
+                    taring.count += 1;
+                }
+            }
+            for sample in &mut samples {
+                self.calibration.apply(&mut sample.values);
+            }
+        }
+
+        if let Some(recording) = &mut self.recording {
+            if let Some(last) = recording.last_counter {
+                recording.log.lost_frames += frame::lost_frames(last, counter);
+            }
+            recording.last_counter = Some(counter);
+            recording.log.frames += 1;
+        }
+
+        if let Some(Outlet {
+            meta,
+            outlet,
+            derived,
+        }) = &self.lsl_outlet
+            // Once created, the outlet carries what the device streams as
+            // long as it is the stream it describes, except during a
+            // recording that stays off LSL.
+            && meta.stream == stream
+            && self.recording.as_ref().is_none_or(|r| r.lsl)
+        {
+            for Sample {
+                timestamp,
+                values: sample,
+            } in &samples
+            {
+                let timestamp = *timestamp;
+                let res = match meta.units {
+                    Units::Raw => outlet.push_sample_ex(sample, timestamp, false),
+                    Units::Physical => outlet.push_sample_ex(
+                        &meta.calibration.as_ref().map_or_else(
+                            || frame::to_physical(meta.stream.mode(), sample),
+                            |c| c.to_physical(meta.stream.mode(), sample),
+                        ),
+                        timestamp,
+                        false,
+                    ),
+                };
+                if let Err(e) = res {
+                    warn!("Actor {}: failed to push sample: {:?}", self.name, e);
+                }
+                if let Some(derived) = derived
+                    && let Err(e) = derived.outlet.push_sample_ex(
+                        &derived.derivation.compute(sample),
+                        timestamp,
+                        false,
+                    )
+                {
+                    warn!(
+                        "Actor {}: failed to push derived sample: {:?}",
+                        self.name, e
+                    );
+                }
+            }
+        }
+
+        if let Some(recording) = &mut self.recording
+            && let Some(sink) = &mut recording.sink
+            && let Err(e) = sink.write_samples(counter, &samples)
+        {
+            warn!(
+                "Actor {}: failed to write to {}, closing it: {:#}",
+                self.name,
+                sink.path().display(),
+                e
+            );
+            close_sink(&mut recording.sink, &self.name);
+        }
+        if let Some(recording) = &mut self.recording
+            && let Some(osc) = &mut recording.osc
+        {
+            osc.send_samples(&samples);
+        }
+        self.detect_gait(&samples);
+
+        if self.samples.receiver_count() > 0 || self.taps.receiver_count() > 0 {
+            let batch = SampleBatch {
+                counter,
+                mode: stream.mode(),
+                rate: stream.rate(),
+                samples,
+            };
+            if self.taps.receiver_count() > 0 {
+                self.taps.send(batch.clone()).ok();
+            }
+            // Nobody listening anymore is handled by the caller.
+            self.samples.send(batch).ok();
+        }
+    }
+
+    /// Reads what the device reports about itself. Everything is optional,
+    /// older firmware does not implement all of it.
+    async fn read_identity(&self, chars: &MitchChars) -> DeviceIdentity {
+        let mut identity = self.status.borrow().identity.clone();
+
+        if let Ok(service) = self
+            .state
+            .session
+            .get_service_by_uuid(&self.device.id, DEVICE_INFO_SERVICE)
+            .await
+        {
+            for (uuid, field) in [
+                (MODEL_NUMBER_CHAR, &mut identity.model),
+                (SERIAL_NUMBER_CHAR, &mut identity.serial),
+                (FIRMWARE_REVISION_CHAR, &mut identity.firmware_version),
+                (HARDWARE_REVISION_CHAR, &mut identity.hardware_revision),
+                (MANUFACTURER_NAME_CHAR, &mut identity.manufacturer),
+            ] {
+                let Ok(char) = self
+                    .state
+                    .session
+                    .get_characteristic_by_uuid(&service.id, uuid)
+                    .await
+                else {
+                    continue;
+                };
+                if let Ok(value) = self.state.session.read_characteristic_value(&char.id).await {
+                    let value = String::from_utf8_lossy(&value)
+                        .trim_end_matches('\0')
+                        .trim()
+                        .to_string();
+                    if !value.is_empty() {
+                        *field = Some(value);
+                    }
+                }
+            }
+        } else {
+            info!("Actor {}: no device information service", self.name);
+        }
+
+        // The mitch's own answers are more specific than the generic service.
+        match self.command(&chars.cmd, Commands::GetFirmwareVersion).await {
+            Ok(res) => {
+                if let Some(payload) = mitch::response_payload(&res) {
+                    let (bootloader, application) = mitch::parse_firmware_version(payload);
+                    identity.bootloader_version = bootloader.or(identity.bootloader_version);
+                    identity.firmware_version = application.or(identity.firmware_version);
+                }
+            }
+            Err(e) => warn!(
+                "Actor {}: failed to read firmware version: {}",
+                self.name, e
+            ),
+        }
+        match self.command(&chars.cmd, Commands::GetDeviceId).await {
+            Ok(res) => {
+                if let Some(id) = mitch::response_payload(&res).and_then(mitch::parse_device_id) {
+                    identity.serial = Some(id);
+                }
+            }
+            Err(e) => warn!("Actor {}: failed to read device id: {}", self.name, e),
+        }
+
+        identity
+    }
+
+    /// Writes a command to the command characteristic and reads back the
+    /// device's response.
+    async fn command(&self, cmd_char: &CharacteristicId, command: Commands) -> Result<Vec<u8>> {
+        let bytes = command.to_bytes();
+        self.capture(CaptureKind::Write, &bytes);
+        let res = BluezLink {
+            session: &self.state.session,
+            cmd: cmd_char,
+        }
+        .request(bytes)
+        .await;
+        if let Ok(res) = &res {
+            self.capture(CaptureKind::Read, res);
+        }
+        res
+    }
+
+    /// Adds `data` to the recording's capture, if it makes one.
+    fn capture(&self, kind: CaptureKind, data: &[u8]) {
+        let Some(capture) = self.recording.as_ref().and_then(|r| r.capture.as_ref()) else {
+            return;
+        };
+        if let Err(e) = capture.record(kind, lsl::local_clock(), data) {
+            warn!("Actor {}: {:#}, capture stopped", self.name, e);
+        }
+    }
+
+    /// Flashes `image` and reboots the device into it.
+    async fn update_firmware(&mut self, chars: &MitchChars, image: &FirmwareImage) -> Result<()> {
+        if self.streaming.is_some() || self.status.borrow().logging {
+            return Err(anyhow!("device is busy, stop streaming and logging first"));
+        }
+        info!(
+            "Actor {}: updating firmware ({} bytes, crc {:08x})",
+            self.name,
+            image.data.len(),
+            image.crc
+        );
+        let mut link = BluezLink {
+            session: &self.state.session,
+            cmd: &chars.cmd,
+        };
+        let mut reported_percent = None;
+        dfu::update(&mut link, image, |sent, total| {
+            let percent = sent as u64 * 100 / total as u64;
+            if reported_percent != Some(percent) {
+                reported_percent = Some(percent);
+                self.state
+                    .events
+                    .send(DaemonEvent::FirmwareProgress {
+                        name: self.name.clone(),
+                        sent,
+                        total,
+                    })
+                    .ok();
+            }
+        })
+        .await
+    }
+
+    /// Records a battery reading, publishes it and warns about low charge.
+    fn update_battery(&mut self, charge: Option<u8>) {
+        let Some(charge) = charge else {
+            return;
+        };
+        let crossed = self.battery.record(charge);
+        let trend = self.battery.trend();
+        self.status.send_modify(|s| {
+            s.battery_charge = Some(charge);
+            s.battery_trend = trend;
+        });
+        if let Some(threshold) = crossed {
+            warn!(
+                "Actor {}: battery at {}%, below {}%",
+                self.name, charge, threshold
+            );
+            // Nobody listening is fine, the warning is logged either way.
+            self.state
+                .events
+                .send(DaemonEvent::LowBattery {
+                    name: self.name.clone(),
+                    charge,
+                    threshold,
+                })
+                .ok();
+        }
+    }
+
+    /// Like [`Self::command`], but fails if the device rejects the command
+    /// and returns only the response's payload.
+    async fn request(&self, cmd_char: &CharacteristicId, command: Commands) -> Result<Vec<u8>> {
+        let res = self.command(cmd_char, command).await?;
+        mitch::response_payload(&res)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| anyhow!("device rejected the command: {res:02x?}"))
+    }
+
+    async fn device_log<S>(
+        &mut self,
+        chars: &MitchChars,
+        events: &mut S,
+        action: LogAction,
+    ) -> Result<DaemonResponse>
+    where
+        S: Stream<Item = BluetoothEvent> + Unpin,
+    {
+        let logging = self.status.borrow().logging;
+        match action {
+            LogAction::Start { mode, rate } => {
+                if self.streaming.is_some() {
+                    return Err(anyhow!("device is streaming, stop it first"));
+                }
+                let stream = StreamConfig::new(mode, rate).map_err(|e| anyhow!(e))?;
+                self.request(&chars.cmd, Commands::StartLog(stream)).await?;
+                self.status.send_modify(|s| s.logging = true);
+            }
+            LogAction::Stop => {
+                self.request(&chars.cmd, Commands::StopLog).await?;
+                self.status.send_modify(|s| s.logging = false);
+            }
+            LogAction::List => return Ok(DaemonResponse::Logs(self.stored_logs(chars).await?)),
+            LogAction::Readout { index, output } => {
+                if logging || self.streaming.is_some() {
+                    return Err(anyhow!("device is busy, stop logging and streaming first"));
+                }
+                self.readout(chars, events, index, &output).await?;
+            }
+            LogAction::Erase => {
+                if logging {
+                    return Err(anyhow!("device is logging, stop logging first"));
+                }
+                self.request(&chars.cmd, Commands::EraseMemory).await?;
+                info!("Actor {}: memory erased", self.name);
+            }
+        }
+        Ok(DaemonResponse::Ok)
+    }
+
+    async fn stored_logs(&self, chars: &MitchChars) -> Result<Vec<StoredLog>> {
+        let payload = self.request(&chars.cmd, Commands::GetMemoryInfo).await?;
+        let count = memory::parse_log_count(&payload)
+            .ok_or_else(|| anyhow!("malformed memory info {payload:02x?}"))?;
+        let mut logs = Vec::with_capacity(count as usize);
+        for index in 0..count {
+            let payload = self
+                .request(&chars.cmd, Commands::GetLogInfo(index))
+                .await?;
+            logs.push(
+                memory::parse_log_info(index, &payload)
+                    .ok_or_else(|| anyhow!("malformed info of log {index}: {payload:02x?}"))?,
+            );
+        }
+        Ok(logs)
+    }
+
+    /// Reads log `index` into `output`. The data goes to a temporary file
+    /// that only replaces `output` once size and checksum match what the
+    /// device reported.
+    ///
+    /// The readout runs inside the actor's loop and consumes the device's
+    /// notifications itself, so the actor serves nothing else until the log
+    /// is read. Commands arriving meanwhile are refused with an error, apart
+    /// from status requests and shutdown.
+    async fn readout<S>(
+        &mut self,
+        chars: &MitchChars,
+        events: &mut S,
+        index: u16,
+        output: &Path,
+    ) -> Result<()>
+    where
+        S: Stream<Item = BluetoothEvent> + Unpin,
+    {
+        let payload = self
+            .request(&chars.cmd, Commands::GetLogInfo(index))
+            .await?;
+        let log = memory::parse_log_info(index, &payload)
+            .ok_or_else(|| anyhow!("malformed info of log {index}: {payload:02x?}"))?;
+        if output.exists() {
+            return Err(anyhow!("{} already exists", output.display()));
+        }
+        let mut partial = output.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        let mut file = File::create_new(&partial)
+            .with_context(|| format!("failed to create {}", partial.display()))?;
+
+        info!("Actor {}: reading out {}", self.name, log);
+        self.state.session.start_notify(&chars.data).await?;
+        let res = match self.request(&chars.cmd, Commands::ReadoutLog(index)).await {
+            Ok(_) => self.receive_log(chars, events, &log, &mut file).await,
+            Err(e) => Err(e),
+        };
+        // Back to idle whatever happened, the device stays in readout otherwise.
+        self.state.session.stop_notify(&chars.data).await.ok();
+        if let Err(e) = self.request(&chars.cmd, Commands::StopLog).await {
+            warn!("Actor {}: failed to leave readout: {}", self.name, e);
+        }
+
+        match res {
+            Ok(()) => {
+                file.sync_all()?;
+                fs::rename(&partial, output)?;
+                info!(
+                    "Actor {}: log {} saved to {}",
+                    self.name,
+                    index,
+                    output.display()
+                );
+                Ok(())
+            }
+            Err(e) => {
+                fs::remove_file(&partial).ok();
+                Err(e)
+            }
+        }
+    }
+
+    /// Answers a command that arrived while reading out a log, failing the
+    /// readout if the actor is to shut down.
+    fn refuse_during_readout(&mut self, command: Option<DeviceCommand>) -> Result<()> {
+        match command {
+            Some(DeviceCommand::Status { tx }) => {
+                tx.send(self.status.borrow().clone()).ok();
+            }
+            Some(DeviceCommand::Shutdown) | None => {
+                self.shutdown = true;
+                return Err(anyhow!("readout interrupted by shutdown"));
+            }
+            Some(command) => {
+                warn!("Actor {}: refusing a command during readout", self.name);
+                command.refuse("device is reading out a log, try again once it is done");
+            }
+        }
+        Ok(())
+    }
+
+    async fn receive_log<S>(
+        &mut self,
+        chars: &MitchChars,
+        events: &mut S,
+        log: &StoredLog,
+        file: &mut File,
+    ) -> Result<()>
+    where
+        S: Stream<Item = BluetoothEvent> + Unpin,
+    {
+        let mut hasher = crc32fast::Hasher::new();
+        let mut received = 0u32;
+        let mut expected_chunk = 0u16;
+        let mut reported_percent = None;
+        while received < log.size {
+            let event = tokio::select! {
+                event = time::timeout(READOUT_TIMEOUT, events.next()) => event.map_err(|_| {
+                    anyhow!("readout stalled at {received} of {} bytes", log.size)
+                })?,
+                command = self.rx.recv() => {
+                    self.refuse_during_readout(command)?;
+                    continue;
+                }
+            };
+            match event {
+                Some(BluetoothEvent::Characteristic {
+                    id,
+                    event: CharacteristicEvent::Value { value },
+                }) if id == chars.data => {
+                    let (counter, chunk) = memory::parse_chunk(&value)?;
+                    if counter != expected_chunk {
+                        return Err(anyhow!(
+                            "chunk {expected_chunk} missing, got {counter} instead"
+                        ));
+                    }
+                    expected_chunk = expected_chunk.wrapping_add(1);
+                    hasher.update(chunk);
+                    file.write_all(chunk)?;
+                    received += chunk.len() as u32;
+
+                    let percent = (received as u64 * 100 / log.size as u64) as u32;
+                    if reported_percent != Some(percent) {
+                        reported_percent = Some(percent);
+                        self.state
+                            .events
+                            .send(DaemonEvent::ReadoutProgress {
+                                name: self.name.clone(),
+                                index: log.index,
+                                received,
+                                total: log.size,
+                            })
+                            .ok();
+                    }
+                }
+                Some(BluetoothEvent::Device {
+                    event: DeviceEvent::Connected { connected: false },
+                    ..
+                }) => return Err(anyhow!("connection lost during readout")),
+                Some(_) => {}
+                None => return Err(anyhow!("device event stream ended unexpectedly")),
+            }
+        }
+
+        if received != log.size {
+            return Err(anyhow!(
+                "received {received} bytes but the log has {}",
+                log.size
+            ));
+        }
+        let crc = hasher.finalize();
+        if crc != log.crc {
+            return Err(anyhow!(
+                "checksum mismatch, computed {crc:08x} but the device reported {:08x}",
+                log.crc
+            ));
+        }
+        Ok(())
+    }
+
+    async fn read_battery(&self, cmd_char: &CharacteristicId) -> Result<Option<u8>> {
+        let res = self.command(cmd_char, Commands::GetPower).await?;
+        Ok(mitch::response_payload(&res).and_then(|payload| payload.first().copied()))
+    }
+}
+
+/// Completes at `at`, or never if there is no deadline.
+async fn deadline(at: Option<time::Instant>) {
+    match at {
+        Some(at) => time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
 }
@@ -0,0 +1,253 @@
+use crate::{
+    mitch::{
+        StreamMode,
+        layout::{PRESSURE_SENSORS, Side},
+    },
+    protocol::{Calibration, GaitOptions},
+};
+use anyhow::{Result, anyhow};
+use std::fmt;
+
+/// Shortest stance or swing phase in seconds, anything shorter is the
+/// pressure bouncing around a threshold rather than a step.
+const MIN_PHASE: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GaitEventKind {
+    HeelStrike,
+    ToeOff,
+}
+
+/// A detected gait event, named like `HS_L` when published.
+#[derive(Debug, Clone)]
+pub struct GaitEvent {
+    pub kind: GaitEventKind,
+    pub timestamp: f64,
+    /// `L`, `R` or the device name when the side is unknown.
+    pub foot: String,
+}
+
+impl fmt::Display for GaitEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            GaitEventKind::HeelStrike => "HS",
+            GaitEventKind::ToeOff => "TO",
+        };
+        write!(f, "{}_{}", kind, self.foot)
+    }
+}
+
+/// Finds heel strikes and toe offs in a pressure stream by thresholding the
+/// summed pressure with hysteresis: the foot lands when the sum rises above
+/// the contact threshold and lifts off when it falls below the release one.
+pub struct GaitDetector {
+    foot: String,
+    /// Sensors taking part in the sum.
+    channels: Vec<usize>,
+    /// Converts the counts to kPa.
+    calibration: Calibration,
+    contact: f32,
+    release: f32,
+    on_ground: Option<bool>,
+    last_event: f64,
+    /// When the load crossed a threshold too soon after the last event. The
+    /// event is reported with this time once the phase proves long enough.
+    crossed_at: Option<f64>,
+}
+
+impl GaitDetector {
+    pub fn new(
+        options: &GaitOptions,
+        mode: StreamMode,
+        side: Option<Side>,
+        name: &str,
+        calibration: Calibration,
+    ) -> Result<Self> {
+        if mode != StreamMode::Pressure {
+            return Err(anyhow!("gait events need the pressure mode"));
+        }
+        if options.gait_release >= options.gait_contact {
+            return Err(anyhow!(
+                "the gait release threshold must be below the contact threshold"
+            ));
+        }
+        let foot = match side {
+            Some(Side::Left) => "L".to_string(),
+            Some(Side::Right) => "R".to_string(),
+            None => name.to_string(),
+        };
+        let channels = PRESSURE_SENSORS
+            .iter()
+            .enumerate()
+            .filter(|(_, sensor)| options.gait_region.is_none_or(|r| sensor.region == r))
+            .map(|(i, _)| i)
+            .collect();
+        Ok(Self {
+            foot,
+            channels,
+            calibration,
+            contact: options.gait_contact,
+            release: options.gait_release,
+            on_ground: None,
+            last_event: f64::NEG_INFINITY,
+            crossed_at: None,
+        })
+    }
+
+    /// Feeds one raw pressure sample, returning the event it completes.
+    pub fn update(&mut self, timestamp: f64, values: &[i16]) -> Option<GaitEvent> {
+        let pressures = self.calibration.to_physical(StreamMode::Pressure, values);
+        let load: f32 = self.channels.iter().filter_map(|&i| pressures.get(i)).sum();
+        let Some(on_ground) = self.on_ground else {
+            // Whatever the foot does when we start is not an event.
+            self.on_ground = Some(load >= self.contact);
+            return None;
+        };
+        let kind = if !on_ground && load > self.contact {
+            GaitEventKind::HeelStrike
+        } else if on_ground && load < self.release {
+            GaitEventKind::ToeOff
+        } else {
+            // Back across before the phase was long enough, a bounce.
+            self.crossed_at = None;
+            return None;
+        };
+        let crossed_at = *self.crossed_at.get_or_insert(timestamp);
+        if timestamp - self.last_event < MIN_PHASE {
+            return None;
+        }
+        self.crossed_at = None;
+        self.on_ground = Some(kind == GaitEventKind::HeelStrike);
+        self.last_event = crossed_at;
+        Some(GaitEvent {
+            kind,
+            timestamp: crossed_at,
+            foot: self.foot.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mitch::frame::PRESSURE_KPA_PER_COUNT;
+
+    const RATE: f64 = 100.0;
+
+    fn detector() -> GaitDetector {
+        let options = GaitOptions {
+            gait: true,
+            gait_contact: 200.0,
+            gait_release: 100.0,
+            gait_region: None,
+        };
+        GaitDetector::new(
+            &options,
+            StreamMode::Pressure,
+            Some(Side::Left),
+            "mitch_L",
+            Calibration::default(),
+        )
+        .unwrap()
+    }
+
+    /// Feeds summed loads in kPa at `RATE`, returning the events found.
+    fn run(detector: &mut GaitDetector, loads: &[f32]) -> Vec<(GaitEventKind, f64)> {
+        loads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, load)| {
+                let mut values = vec![0; PRESSURE_SENSORS.len()];
+                values[0] = (load / PRESSURE_KPA_PER_COUNT) as i16;
+                detector.update(i as f64 / RATE, &values)
+            })
+            .map(|event| (event.kind, event.timestamp))
+            .collect()
+    }
+
+    /// `n` samples of `load`.
+    fn hold(load: f32, n: usize) -> Vec<f32> {
+        vec![load; n]
+    }
+
+    #[test]
+    fn finds_heel_strike_and_toe_off() {
+        let trace = [hold(0.0, 50), hold(500.0, 60), hold(0.0, 50)].concat();
+        let events = run(&mut detector(), &trace);
+        assert_eq!(
+            events,
+            [
+                (GaitEventKind::HeelStrike, 0.5),
+                (GaitEventKind::ToeOff, 1.1)
+            ]
+        );
+    }
+
+    #[test]
+    fn names_events_after_the_foot() {
+        let mut detector = detector();
+        run(&mut detector, &hold(0.0, 20));
+        let mut values = vec![0; PRESSURE_SENSORS.len()];
+        values[0] = 400;
+        let event = detector.update(1.0, &values).unwrap();
+        assert_eq!(event.to_string(), "HS_L");
+    }
+
+    #[test]
+    fn starting_state_is_no_event() {
+        assert!(run(&mut detector(), &hold(500.0, 50)).is_empty());
+        assert!(run(&mut detector(), &hold(0.0, 50)).is_empty());
+    }
+
+    #[test]
+    fn hysteresis_ignores_loads_between_thresholds() {
+        // Dropping into the band between release and contact is no toe off,
+        // rising into it from the air no heel strike.
+        let trace = [
+            hold(0.0, 20),
+            hold(150.0, 30),
+            hold(500.0, 30),
+            hold(150.0, 30),
+            hold(500.0, 30),
+            hold(50.0, 30),
+        ]
+        .concat();
+        let events = run(&mut detector(), &trace);
+        assert_eq!(
+            events,
+            [
+                (GaitEventKind::HeelStrike, 0.5),
+                (GaitEventKind::ToeOff, 1.4)
+            ]
+        );
+    }
+
+    #[test]
+    fn debounces_short_phases() {
+        // A bounce below release right after landing is ignored.
+        let trace = [
+            hold(0.0, 20),
+            hold(500.0, 3),
+            hold(50.0, 3),
+            hold(500.0, 30),
+        ]
+        .concat();
+        let events = run(&mut detector(), &trace);
+        assert_eq!(events, [(GaitEventKind::HeelStrike, 0.2)]);
+    }
+
+    #[test]
+    fn reports_debounced_crossings_at_their_time() {
+        // The foot really lifts 50 ms after landing, the toe off is only
+        // reported once the debounce is over but keeps its time.
+        let trace = [hold(0.0, 20), hold(500.0, 5), hold(0.0, 30)].concat();
+        let events = run(&mut detector(), &trace);
+        assert_eq!(
+            events,
+            [
+                (GaitEventKind::HeelStrike, 0.2),
+                (GaitEventKind::ToeOff, 0.25)
+            ]
+        );
+    }
+}
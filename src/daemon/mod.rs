@@ -1,45 +1,242 @@
-use crate::protocol::{DeviceStatus, IPC_SOCKET_PATH};
+use crate::mitch::{StreamConfig, dfu::FirmwareImage};
+use crate::protocol::{
+    DaemonEvent, DaemonResponse, DeviceStatus, GainCurve, IPC_SOCKET_PATH, LogAction,
+    RecordOptions, SampleBatch,
+};
 use anyhow::Result;
+use battery::BatteryConfig;
 use bluez_async::{AdapterInfo, BluetoothSession};
+use calibration::{CalibrationConfig, Calibrations};
+use clap::Args;
 use client::Client;
+use mqtt::MqttConfig;
+use outlet::MarkerOutlet;
+use session::{SessionConfig, Sessions};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 #[cfg(unix)]
 use tokio::net::UnixListener;
-use tokio::sync::{Mutex, mpsc, oneshot::Sender};
-use tracing::{error, info};
+use tokio::sync::{Mutex, broadcast, mpsc, oneshot::Sender, watch};
+use tracing::{error, info, warn};
 
+mod battery;
+mod calibration;
+pub mod capture;
 mod client;
+mod clock;
+pub mod decode;
+mod derived;
 mod device_actor;
+mod gait;
+mod mqtt;
+mod osc;
+pub mod outlet;
+mod session;
+pub mod sink;
 
-type DeviceMap = Arc<Mutex<HashMap<String, mpsc::Sender<DeviceCommand>>>>;
+/// How many events a slow client may fall behind before it misses some.
+const EVENT_CAPACITY: usize = 64;
+
+/// How many markers an actor busy with its device may fall behind.
+const MARKER_CAPACITY: usize = 64;
+
+/// How many frames a slow sample subscriber may fall behind, about five
+/// seconds at the highest rate.
+const SAMPLE_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Args)]
+pub struct DaemonConfig {
+    #[clap(flatten)]
+    pub battery: BatteryConfig,
+    #[clap(flatten)]
+    pub session: SessionConfig,
+    #[clap(flatten)]
+    pub calibration: CalibrationConfig,
+    #[clap(flatten)]
+    pub mqtt: MqttConfig,
+}
+
+type DeviceMap = Arc<Mutex<HashMap<String, DeviceHandle>>>;
+
+/// Everything the daemon keeps about a connected device.
+#[derive(Clone)]
+struct DeviceHandle {
+    tx: mpsc::Sender<DeviceCommand>,
+    /// Last status published by the actor, readable while the actor is busy.
+    status: watch::Receiver<DeviceStatus>,
+    /// Decoded samples, fanned out to every subscribed client.
+    samples: broadcast::Sender<SampleBatch>,
+    /// The same samples for listeners that only follow what the device
+    /// streams for others, unlike `samples` they never keep it streaming.
+    taps: broadcast::Sender<SampleBatch>,
+}
+
+/// Last status of devices whose actor failed for good, kept until the device
+/// is connected again or explicitly disconnected.
+type FailedDevices = Arc<Mutex<HashMap<String, DeviceStatus>>>;
+
+/// Outcome of a connection attempt, shared with every client waiting on it.
+type ConnectOutcome = Option<Result<(), String>>;
+
+/// Connection attempts that are currently in flight, keyed by device name.
+/// Clients asking for a device that is already being connected subscribe to
+/// the existing attempt instead of starting a second one.
+type PendingConnects = Arc<Mutex<HashMap<String, watch::Receiver<ConnectOutcome>>>>;
+
+/// Number of clients currently relying on adapter discovery. Discovery is
+/// started by the first and stopped by the last one.
+type DiscoveryUsers = Arc<Mutex<usize>>;
 
 enum DeviceCommand {
-    StartRecording { lsl_stream_name: String },
-    Status { tx: Sender<DeviceStatus> },
+    StartRecording {
+        options: RecordOptions,
+        reply: Sender<Result<(), String>>,
+    },
+    /// Stop recording, the stream keeps running for subscribers.
+    StopRecording {
+        reply: Sender<Result<(), String>>,
+    },
+    Status {
+        tx: Sender<DeviceStatus>,
+    },
+    /// Answers with `Ok`, `Logs` or `Error`.
+    Log {
+        action: LogAction,
+        reply: Sender<DaemonResponse>,
+    },
+    /// Answers with `Ok` once the device reboots into the new firmware, the
+    /// actor stops then.
+    FirmwareUpdate {
+        image: FirmwareImage,
+        reply: Sender<DaemonResponse>,
+    },
+    /// Answers with the new `Calibration` once the device was tared.
+    Tare {
+        duration: Duration,
+        reply: Sender<DaemonResponse>,
+    },
+    /// Answers with the new `Calibration` once the gains are saved.
+    SetGains {
+        gains: Vec<Option<GainCurve>>,
+        reply: Sender<DaemonResponse>,
+    },
+    /// Start streaming with `stream` unless the device already streams.
+    EnsureStreaming {
+        stream: StreamConfig,
+        reply: Sender<Result<(), String>>,
+    },
     Shutdown,
 }
 
-pub struct Daemon {
+impl DeviceCommand {
+    /// Answers with `error` without running the command.
+    fn refuse(self, error: &str) {
+        match self {
+            DeviceCommand::StartRecording { reply, .. }
+            | DeviceCommand::StopRecording { reply }
+            | DeviceCommand::EnsureStreaming { reply, .. } => {
+                reply.send(Err(error.to_string())).ok();
+            }
+            DeviceCommand::Log { reply, .. }
+            | DeviceCommand::FirmwareUpdate { reply, .. }
+            | DeviceCommand::Tare { reply, .. }
+            | DeviceCommand::SetGains { reply, .. } => {
+                reply.send(DaemonResponse::Error(error.to_string())).ok();
+            }
+            // Dropping the sender tells the client there is no answer.
+            DeviceCommand::Status { .. } | DeviceCommand::Shutdown => {}
+        }
+    }
+}
+
+/// An annotation of the recordings, timestamped with the LSL clock like the
+/// samples.
+#[derive(Debug, Clone)]
+pub struct Marker {
+    pub timestamp: f64,
+    pub text: String,
+}
+
+/// State shared by the daemon, its clients and the device actors.
+#[derive(Clone)]
+struct DaemonState {
     session: BluetoothSession,
     adapter: AdapterInfo,
+    config: Arc<DaemonConfig>,
     device_map: DeviceMap,
+    failed_devices: FailedDevices,
+    pending_connects: PendingConnects,
+    discovery_users: DiscoveryUsers,
+    events: broadcast::Sender<DaemonEvent>,
+    marker_outlet: Arc<MarkerOutlet>,
+    /// Gait events detected by any of the actors.
+    gait_outlet: Arc<MarkerOutlet>,
+    /// Markers for the actors to write into their file sinks.
+    markers: broadcast::Sender<Marker>,
+    sessions: Arc<Sessions>,
+    calibrations: Arc<Calibrations>,
+}
+
+impl DaemonState {
+    /// Timestamps `text` now and publishes it on the marker outlet and to
+    /// every actor.
+    fn publish_marker(&self, text: String) -> Result<()> {
+        let marker = Marker {
+            timestamp: lsl::local_clock(),
+            text,
+        };
+        info!("Marker at {:.6}: {}", marker.timestamp, marker.text);
+        self.marker_outlet.push(&marker)?;
+        // No actor listening just means nothing is being written to files.
+        if self.markers.send(marker).is_err() {
+            warn!("No device connected, the marker only went to LSL");
+        }
+        Ok(())
+    }
+}
+
+pub struct Daemon {
+    state: DaemonState,
 }
 
 impl Daemon {
-    pub async fn new() -> Result<Self> {
+    pub async fn new(config: DaemonConfig) -> Result<Self> {
         let session = BluetoothSession::new().await?.1;
         let adapter = session.get_adapters().await?[0].clone();
         let device_map = DeviceMap::new(Mutex::new(HashMap::new()));
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let (markers, _) = broadcast::channel(MARKER_CAPACITY);
+        let sessions = Arc::new(Sessions::new(&config.session)?);
+        let calibrations = Arc::new(Calibrations::new(&config.calibration)?);
         Ok(Self {
-            session,
-            adapter,
-            device_map,
+            state: DaemonState {
+                session,
+                adapter,
+                config: Arc::new(config),
+                device_map,
+                failed_devices: FailedDevices::default(),
+                pending_connects: PendingConnects::default(),
+                discovery_users: DiscoveryUsers::default(),
+                events,
+                marker_outlet: Arc::new(MarkerOutlet::new("mitch_markers")?),
+                gait_outlet: Arc::new(MarkerOutlet::new("mitch_gait")?),
+                markers,
+                sessions,
+                calibrations,
+            },
         })
     }
     pub async fn run(&self) -> Result<()> {
         info!("Daemon listening on {}", IPC_SOCKET_PATH);
 
+        let state = self.state.clone();
+        tokio::task::spawn_local(async move {
+            if let Err(e) = mqtt::run(state).await {
+                error!("MQTT publisher failed: {}", e);
+            }
+        });
+
         #[cfg(unix)]
         {
             let _ = tokio::fs::remove_file(IPC_SOCKET_PATH).await;
@@ -48,17 +245,11 @@ impl Daemon {
             loop {
                 match listener.accept().await {
                     Ok((mut stream, _addr)) => {
-                        let session_clone = self.session.clone();
-                        let device_map_clone = self.device_map.clone();
-                        let adapter_clone = self.adapter.clone();
+                        let state_clone = self.state.clone();
 
                         // Spawn a task to handle this client
                         tokio::task::spawn_local(async move {
-                            if let Err(e) =
-                                Client::new(session_clone, adapter_clone, device_map_clone)
-                                    .handle(&mut stream)
-                                    .await
-                            {
+                            if let Err(e) = Client::new(state_clone).handle(&mut stream).await {
                                 error!("Client error: {}", e);
                             }
                         });
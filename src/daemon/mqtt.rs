@@ -0,0 +1,587 @@
+//! Publishes the state of the daemon's devices to an MQTT broker, for
+//! monitoring setups that run for days without anyone watching a terminal.
+//!
+//! Each device's status is published retained to its status topic whenever
+//! it changes, and again after every reconnect so the broker never holds a
+//! stale picture for long. With a data rate set, samples of streaming
+//! devices are averaged into windows and published to the data topic.
+
+use super::DaemonState;
+use crate::{
+    mitch::StreamMode,
+    protocol::{DeviceStatus, SampleBatch},
+};
+use anyhow::{Result, anyhow};
+use clap::Args;
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use serde::Serialize;
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info, warn};
+
+/// How many publishes may queue up while the broker is unreachable.
+const QUEUE_CAPACITY: usize = 256;
+
+/// How often the devices are checked for a changed status.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reconnect attempts back off up to this long.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Args)]
+pub struct MqttConfig {
+    /// Publish device status to the MQTT broker at HOST:PORT
+    #[clap(long, value_name = "HOST:PORT")]
+    pub mqtt: Option<String>,
+
+    /// Client id the daemon connects to the broker with
+    #[clap(long, default_value = "mitch_cli")]
+    pub mqtt_client_id: String,
+
+    /// User name to authenticate with, the password is read from
+    /// MITCH_MQTT_PASSWORD
+    #[clap(long)]
+    pub mqtt_username: Option<String>,
+
+    /// Quality of service of every publish, 0, 1 or 2
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=2))]
+    pub mqtt_qos: u8,
+
+    /// Topic of a device's status, {name} is replaced by the device name
+    #[clap(long, default_value = "mitch/{name}/status")]
+    pub mqtt_status_topic: String,
+
+    /// Topic of a device's data, {name} is replaced by the device name
+    #[clap(long, default_value = "mitch/{name}/data")]
+    pub mqtt_data_topic: String,
+
+    /// Topic the daemon announces itself on, `offline` once it is gone
+    #[clap(long, default_value = "mitch/daemon")]
+    pub mqtt_daemon_topic: String,
+
+    /// Publish the data of streaming devices averaged down to this many
+    /// messages per second, no data is published without it
+    #[clap(long, value_name = "HZ")]
+    pub mqtt_data_rate: Option<f64>,
+
+    /// Seconds after which an unchanged status is published again
+    #[clap(long, default_value_t = 60)]
+    pub mqtt_status_interval: u64,
+}
+
+#[derive(Serialize)]
+struct StatusMessage<'a> {
+    /// Whether the daemon still has an actor for the device.
+    connected: bool,
+    #[serde(flatten)]
+    status: &'a DeviceStatus,
+}
+
+#[derive(Serialize)]
+struct DataMessage {
+    /// LSL time of the window's last sample.
+    timestamp: f64,
+    mode: StreamMode,
+    /// How many samples were averaged.
+    samples: usize,
+    values: Vec<f32>,
+}
+
+/// Where the publisher's messages go, the broker connection in the daemon.
+trait Broker: Clone + 'static {
+    /// Queues a publish without waiting for the broker.
+    fn publish(&self, topic: &str, retain: bool, payload: Vec<u8>) -> Result<()>;
+}
+
+#[derive(Clone)]
+struct MqttBroker {
+    client: AsyncClient,
+    qos: QoS,
+}
+
+impl Broker for MqttBroker {
+    fn publish(&self, topic: &str, retain: bool, payload: Vec<u8>) -> Result<()> {
+        self.client.try_publish(topic, self.qos, retain, payload)?;
+        Ok(())
+    }
+}
+
+/// Connects to the configured broker and publishes until the daemon exits.
+/// Returns right away when no broker is configured.
+pub async fn run(state: DaemonState) -> Result<()> {
+    let config = &state.config.mqtt;
+    let Some(broker) = &config.mqtt else {
+        return Ok(());
+    };
+    if config
+        .mqtt_data_rate
+        .is_some_and(|rate| !rate.is_finite() || rate <= 0.0)
+    {
+        return Err(anyhow!("the MQTT data rate must be positive"));
+    }
+    let qos = rumqttc::qos(config.mqtt_qos)?;
+    let (host, port) = broker
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        .ok_or_else(|| anyhow!("expected HOST:PORT for the MQTT broker, got {broker}"))?;
+
+    let mut options = MqttOptions::new(&config.mqtt_client_id, host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        &config.mqtt_daemon_topic,
+        "offline",
+        qos,
+        true,
+    ));
+    if let Some(username) = &config.mqtt_username {
+        let password = std::env::var("MITCH_MQTT_PASSWORD").unwrap_or_default();
+        options.set_credentials(username, password);
+    }
+    let (client, eventloop) = AsyncClient::new(options, QUEUE_CAPACITY);
+    info!("Publishing to MQTT broker {}", broker);
+
+    // Bumped on every connection so the publisher knows to send everything
+    // again.
+    let connections = Rc::new(Cell::new(0u64));
+    tokio::task::spawn_local(drive(eventloop, broker.clone(), connections.clone()));
+    let mut publisher = Publisher::new(MqttBroker { client, qos }, config.clone());
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    let mut seen_connections = 0;
+    loop {
+        interval.tick().await;
+        if connections.get() != seen_connections {
+            seen_connections = connections.get();
+            publisher.reconnected();
+        }
+
+        let mut statuses: Vec<(bool, DeviceStatus)> = Vec::new();
+        for (name, handle) in state.device_map.lock().await.iter() {
+            statuses.push((true, handle.status.borrow().clone()));
+            // The tap only carries samples while the device streams for
+            // someone else, it never keeps the device streaming.
+            if let Some(rate) = config.mqtt_data_rate
+                && publisher.needs_forwarder(name, &handle.taps)
+            {
+                tokio::task::spawn_local(forward_samples(
+                    handle.taps.subscribe(),
+                    publisher.broker.clone(),
+                    topic(&config.mqtt_data_topic, name),
+                    rate,
+                ));
+            }
+        }
+        let failed = state.failed_devices.lock().await;
+        statuses.extend(failed.values().map(|status| (false, status.clone())));
+        drop(failed);
+        publisher.update(&statuses);
+    }
+}
+
+/// Polls the event loop, which is what actually talks to the broker and
+/// reconnects after the connection was lost.
+async fn drive(mut eventloop: EventLoop, broker: String, connections: Rc<Cell<u64>>) {
+    let mut backoff = Duration::from_secs(1);
+    let mut reported = false;
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker {}", broker);
+                connections.set(connections.get() + 1);
+                backoff = Duration::from_secs(1);
+                reported = false;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                // A broker that is down would otherwise be reported on
+                // every attempt.
+                if !reported {
+                    warn!("MQTT broker {} unreachable, retrying: {}", broker, e);
+                    reported = true;
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+fn topic(template: &str, name: &str) -> String {
+    template.replace("{name}", name)
+}
+
+/// Decides which statuses to publish.
+struct Publisher<B> {
+    broker: B,
+    config: MqttConfig,
+    /// What was last published per device and when.
+    published: HashMap<String, (String, Instant)>,
+    /// The tap whose samples are being forwarded per device, without
+    /// keeping it open once the actor is gone.
+    streams: HashMap<String, broadcast::WeakSender<SampleBatch>>,
+}
+
+impl<B: Broker> Publisher<B> {
+    fn new(broker: B, config: MqttConfig) -> Self {
+        Self {
+            broker,
+            config,
+            published: HashMap::new(),
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Whether the samples of `name` need a new forwarder, because the
+    /// device is new or its actor was replaced by a reconnect since.
+    fn needs_forwarder(&mut self, name: &str, taps: &broadcast::Sender<SampleBatch>) -> bool {
+        match self.streams.get(name).and_then(|weak| weak.upgrade()) {
+            Some(forwarded) if forwarded.same_channel(taps) => false,
+            _ => {
+                self.streams.insert(name.to_string(), taps.downgrade());
+                true
+            }
+        }
+    }
+
+    /// Announces the daemon and has every status published again.
+    fn reconnected(&mut self) {
+        // Forget what was published but not which devices, those gone
+        // meanwhile are still reported as disconnected.
+        self.published
+            .values_mut()
+            .for_each(|(last, _)| last.clear());
+        self.publish(&self.config.mqtt_daemon_topic, "online".into());
+    }
+
+    /// Publishes the statuses that changed or are due again, `connected`
+    /// tells whether the daemon still has an actor for the device.
+    fn update(&mut self, statuses: &[(bool, DeviceStatus)]) {
+        let refresh = Duration::from_secs(self.config.mqtt_status_interval.max(1));
+        let current: HashSet<&str> = statuses.iter().map(|(_, s)| s.name.as_str()).collect();
+        self.streams
+            .retain(|name, _| current.contains(name.as_str()));
+        // Devices that are gone entirely were disconnected on purpose.
+        let gone: Vec<String> = self
+            .published
+            .keys()
+            .filter(|name| !current.contains(name.as_str()))
+            .cloned()
+            .collect();
+        for name in gone {
+            self.published.remove(&name);
+            self.publish(
+                &topic(&self.config.mqtt_status_topic, &name),
+                r#"{"connected":false}"#.into(),
+            );
+        }
+
+        for (connected, status) in statuses {
+            let message = StatusMessage {
+                connected: *connected,
+                status,
+            };
+            let Ok(payload) = serde_json::to_string(&message) else {
+                continue;
+            };
+            let due = match self.published.get(&status.name) {
+                Some((last, at)) => *last != payload || at.elapsed() >= refresh,
+                None => true,
+            };
+            if due {
+                self.publish(
+                    &topic(&self.config.mqtt_status_topic, &status.name),
+                    payload.clone(),
+                );
+                self.published
+                    .insert(status.name.clone(), (payload, Instant::now()));
+            }
+        }
+    }
+
+    /// Publishes retained, dropping the message when the queue is full as
+    /// the next change or reconnect publishes the status again anyway.
+    fn publish(&self, topic: &str, payload: String) {
+        if let Err(e) = self.broker.publish(topic, true, payload.into_bytes()) {
+            warn!("Dropped MQTT publish to {}: {}", topic, e);
+        }
+    }
+}
+
+/// Averages samples over windows of a fixed length.
+struct DataWindow {
+    length: f64,
+    sums: Vec<f64>,
+    count: usize,
+    start: Option<f64>,
+    mode: Option<StreamMode>,
+}
+
+impl DataWindow {
+    fn new(rate: f64) -> Self {
+        Self {
+            length: 1.0 / rate,
+            sums: Vec::new(),
+            count: 0,
+            start: None,
+            mode: None,
+        }
+    }
+
+    /// Adds a batch, returning the windows it completed.
+    fn push(&mut self, batch: SampleBatch) -> Vec<DataMessage> {
+        // A window never mixes modes, the channels would mean different
+        // things.
+        if self.mode != Some(batch.mode) {
+            self.mode = Some(batch.mode);
+            self.sums.clear();
+            self.count = 0;
+            self.start = None;
+        }
+        let mut messages = Vec::new();
+        for sample in batch.samples {
+            let first = *self.start.get_or_insert(sample.timestamp);
+            if self.sums.len() != sample.values.len() {
+                self.sums = vec![0.0; sample.values.len()];
+                self.count = 0;
+            }
+            for (sum, value) in self.sums.iter_mut().zip(&sample.values) {
+                *sum += *value as f64;
+            }
+            self.count += 1;
+            if sample.timestamp - first < self.length {
+                continue;
+            }
+            messages.push(DataMessage {
+                timestamp: sample.timestamp,
+                mode: batch.mode,
+                samples: self.count,
+                values: self
+                    .sums
+                    .iter()
+                    .map(|sum| (sum / self.count as f64) as f32)
+                    .collect(),
+            });
+            self.sums.iter_mut().for_each(|sum| *sum = 0.0);
+            self.count = 0;
+            self.start = None;
+        }
+        messages
+    }
+}
+
+/// Averages the samples of one device over windows of `1 / rate` seconds
+/// and publishes each window, until the device's actor is gone.
+async fn forward_samples(
+    mut samples: broadcast::Receiver<SampleBatch>,
+    broker: impl Broker,
+    topic: String,
+    rate: f64,
+) {
+    let mut window = DataWindow::new(rate);
+    loop {
+        let batch = match samples.recv().await {
+            Ok(batch) => batch,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        for message in window.push(batch) {
+            let Ok(payload) = serde_json::to_vec(&message) else {
+                continue;
+            };
+            // Data is only useful live, drop it rather than queue it up
+            // while the broker is away.
+            broker.publish(&topic, false, payload).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Sample;
+    use clap::Parser;
+    use serde_json::Value;
+    use std::cell::RefCell;
+
+    /// Stands in for the broker, keeping what was published.
+    #[derive(Clone, Default)]
+    struct Recorder(Rc<RefCell<Vec<(String, bool, Value)>>>);
+
+    impl Broker for Recorder {
+        fn publish(&self, topic: &str, retain: bool, payload: Vec<u8>) -> Result<()> {
+            let payload = serde_json::from_slice(&payload)
+                .unwrap_or_else(|_| Value::String(String::from_utf8(payload).unwrap()));
+            self.0
+                .borrow_mut()
+                .push((topic.to_string(), retain, payload));
+            Ok(())
+        }
+    }
+
+    impl Recorder {
+        fn take(&self) -> Vec<(String, bool, Value)> {
+            self.0.take()
+        }
+    }
+
+    #[derive(Parser)]
+    struct Cli {
+        #[clap(flatten)]
+        mqtt: MqttConfig,
+    }
+
+    fn config(args: &[&str]) -> MqttConfig {
+        let args = ["mitch_cli", "--mqtt", "localhost:1883"].iter().chain(args);
+        Cli::parse_from(args).mqtt
+    }
+
+    fn status(name: &str) -> DeviceStatus {
+        DeviceStatus::new(name, None, "00:11:22:33:44:55".to_string())
+    }
+
+    fn batch(mode: StreamMode, samples: &[(f64, &[i16])]) -> SampleBatch {
+        SampleBatch {
+            counter: 0,
+            mode,
+            rate: 10,
+            samples: samples
+                .iter()
+                .map(|(timestamp, values)| Sample {
+                    timestamp: *timestamp,
+                    values: values.to_vec(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn publishes_status_retained_once_until_it_changes() {
+        let broker = Recorder::default();
+        let mut publisher = Publisher::new(
+            broker.clone(),
+            config(&["--mqtt-status-topic", "lab/{name}"]),
+        );
+        let mut left = status("mitch_L");
+        publisher.update(&[(true, left.clone())]);
+        let published = broker.take();
+        assert_eq!(published.len(), 1);
+        let (topic, retain, payload) = &published[0];
+        assert_eq!(topic, "lab/mitch_L");
+        assert!(retain);
+        assert_eq!(payload["connected"], true);
+        assert_eq!(payload["name"], "mitch_L");
+        assert_eq!(payload["identity"]["mac"], "00:11:22:33:44:55");
+
+        publisher.update(&[(true, left.clone())]);
+        assert!(broker.take().is_empty());
+
+        left.battery_charge = Some(42);
+        publisher.update(&[(false, left)]);
+        let published = broker.take();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].2["battery_charge"], 42);
+        assert_eq!(published[0].2["connected"], false);
+    }
+
+    #[test]
+    fn reports_devices_gone_as_disconnected() {
+        let broker = Recorder::default();
+        let mut publisher = Publisher::new(broker.clone(), config(&[]));
+        publisher.update(&[(true, status("a")), (true, status("b"))]);
+        broker.take();
+        publisher.update(&[(true, status("a"))]);
+        let published = broker.take();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].0, "mitch/b/status");
+        assert_eq!(published[0].2, serde_json::json!({ "connected": false }));
+    }
+
+    #[test]
+    fn publishes_everything_again_after_reconnecting() {
+        let broker = Recorder::default();
+        let mut publisher = Publisher::new(broker.clone(), config(&[]));
+        publisher.update(&[(true, status("a"))]);
+        broker.take();
+        publisher.reconnected();
+        publisher.update(&[(true, status("a"))]);
+        let published = broker.take();
+        let topics: Vec<&str> = published.iter().map(|(t, _, _)| t.as_str()).collect();
+        assert_eq!(topics, ["mitch/daemon", "mitch/a/status"]);
+        assert_eq!(published[0].2, Value::String("online".to_string()));
+    }
+
+    #[test]
+    fn forwards_samples_again_after_failed_device_reconnects() {
+        let mut publisher = Publisher::new(Recorder::default(), config(&[]));
+        let (taps, _) = broadcast::channel(4);
+        assert!(publisher.needs_forwarder("a", &taps));
+        assert!(!publisher.needs_forwarder("a", &taps));
+
+        // The actor fails, the device stays listed among the failed ones
+        // and its tap closes with the actor.
+        publisher.update(&[(false, status("a"))]);
+        drop(taps);
+        assert!(publisher.streams.contains_key("a"));
+
+        // Reconnecting gives the device a new tap to subscribe to.
+        let (taps, _) = broadcast::channel(4);
+        assert!(publisher.needs_forwarder("a", &taps));
+        assert!(!publisher.needs_forwarder("a", &taps));
+
+        // An actor replaced while the old tap is still open counts too.
+        let (replaced, _) = broadcast::channel(4);
+        assert!(publisher.needs_forwarder("a", &replaced));
+    }
+
+    #[test]
+    fn averages_samples_into_windows() {
+        let mut window = DataWindow::new(2.0);
+        let messages = window.push(batch(
+            StreamMode::Pressure,
+            &[
+                (0.0, &[0, 10]),
+                (0.25, &[2, 20]),
+                (0.5, &[4, 30]),
+                (0.75, &[6, 40]),
+            ],
+        ));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].timestamp, 0.5);
+        assert_eq!(messages[0].samples, 3);
+        assert_eq!(messages[0].values, [2.0, 20.0]);
+
+        // A change of mode discards the window begun with the old one.
+        let messages = window.push(batch(
+            StreamMode::Accelerometry,
+            &[(1.0, &[1, 1, 1]), (1.5, &[3, 3, 3])],
+        ));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].mode, StreamMode::Accelerometry);
+        assert_eq!(messages[0].values, [2.0, 2.0, 2.0]);
+    }
+
+    #[tokio::test]
+    async fn forwards_data_unretained_until_the_actor_is_gone() {
+        let broker = Recorder::default();
+        let (tx, rx) = broadcast::channel(8);
+        tx.send(batch(StreamMode::Pressure, &[(0.0, &[1]), (1.0, &[3])]))
+            .unwrap();
+        drop(tx);
+        forward_samples(rx, broker.clone(), topic("mitch/{name}/data", "a"), 1.0).await;
+        let published = broker.take();
+        assert_eq!(published.len(), 1);
+        let (topic, retain, payload) = &published[0];
+        assert_eq!(topic, "mitch/a/data");
+        assert!(!retain);
+        assert_eq!(payload["values"], serde_json::json!([2.0]));
+        assert_eq!(payload["samples"], 2);
+        assert_eq!(
+            payload["mode"],
+            serde_json::to_value(StreamMode::Pressure).unwrap()
+        );
+    }
+}
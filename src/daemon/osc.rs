@@ -0,0 +1,264 @@
+//! Sends samples as OSC 1.0 messages over UDP, for feedback applications
+//! that speak OSC rather than LSL.
+//!
+//! Every sample becomes a message to `<address>/raw` with one argument per
+//! channel, int32 counts or float32 physical units, and with the derived
+//! stream enabled another to `<address>/derived` carrying its channels.
+
+use super::derived::Derivation;
+use crate::{
+    mitch::{StreamMode, Units, layout::Side},
+    protocol::{Calibration, OscOptions, Sample},
+};
+use anyhow::{Context, Result, anyhow};
+use std::net::{SocketAddr, UdpSocket};
+use tracing::warn;
+
+enum OscArg {
+    Int(i32),
+    Float(f32),
+}
+
+pub struct OscSink {
+    socket: UdpSocket,
+    target: SocketAddr,
+    address: String,
+    mode: StreamMode,
+    units: Units,
+    calibration: Calibration,
+    derivation: Option<Derivation>,
+    /// Whether a failed send was reported already, a missing receiver would
+    /// otherwise be reported for every sample.
+    warned: bool,
+}
+
+impl OscSink {
+    pub async fn new(
+        options: &OscOptions,
+        name: &str,
+        side: Option<Side>,
+        mode: StreamMode,
+        units: Units,
+        calibration: Calibration,
+        derivation: Option<Derivation>,
+    ) -> Result<Option<Self>> {
+        let Some(target) = &options.osc else {
+            return Ok(None);
+        };
+        // Resolved without blocking, the actor shares its thread.
+        let target = tokio::net::lookup_host(target)
+            .await
+            .with_context(|| format!("failed to resolve {target}"))?
+            .next()
+            .ok_or_else(|| anyhow!("{target} resolves to no address"))?;
+        let address = options.osc_address.replace("{name}", name).replace(
+            "{side}",
+            &side
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+        );
+        if !address.starts_with('/') {
+            return Err(anyhow!("OSC address `{address}` must start with /"));
+        }
+        let bind: SocketAddr = if target.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_nonblocking(true)?;
+        Ok(Some(Self {
+            socket,
+            target,
+            address,
+            mode,
+            units,
+            calibration,
+            derivation,
+            warned: false,
+        }))
+    }
+
+    pub fn target(&self) -> SocketAddr {
+        self.target
+    }
+
+    pub fn send_samples(&mut self, samples: &[Sample]) {
+        for sample in samples {
+            let args: Vec<OscArg> = match self.units {
+                Units::Raw => sample
+                    .values
+                    .iter()
+                    .map(|v| OscArg::Int(*v as i32))
+                    .collect(),
+                Units::Physical => self
+                    .calibration
+                    .to_physical(self.mode, &sample.values)
+                    .into_iter()
+                    .map(OscArg::Float)
+                    .collect(),
+            };
+            self.send(&format!("{}/raw", self.address), &args);
+            if let Some(derivation) = &self.derivation {
+                let args: Vec<OscArg> = derivation
+                    .compute(&sample.values)
+                    .into_iter()
+                    .map(OscArg::Float)
+                    .collect();
+                self.send(&format!("{}/derived", self.address), &args);
+            }
+        }
+    }
+
+    fn send(&mut self, address: &str, args: &[OscArg]) {
+        let packet = encode_message(address, args);
+        match self.socket.send_to(&packet, self.target) {
+            Ok(_) => self.warned = false,
+            Err(e) if !self.warned => {
+                warn!("Failed to send OSC to {}: {}", self.target, e);
+                self.warned = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+fn encode_message(address: &str, args: &[OscArg]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    push_string(&mut packet, address);
+    let tags: String = std::iter::once(',')
+        .chain(args.iter().map(|arg| match arg {
+            OscArg::Int(_) => 'i',
+            OscArg::Float(_) => 'f',
+        }))
+        .collect();
+    push_string(&mut packet, &tags);
+    for arg in args {
+        match arg {
+            OscArg::Int(v) => packet.extend_from_slice(&v.to_be_bytes()),
+            OscArg::Float(v) => packet.extend_from_slice(&v.to_be_bytes()),
+        }
+    }
+    packet
+}
+
+/// Appends an OSC string, null terminated and padded to four bytes.
+fn push_string(packet: &mut Vec<u8>, s: &str) {
+    packet.extend_from_slice(s.as_bytes());
+    let padding = 4 - s.len() % 4;
+    packet.extend(std::iter::repeat_n(0, padding));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mitch::layout::{InsoleSize, PRESSURE_SENSORS};
+    use std::time::Duration;
+
+    /// Reads an OSC string, returning it and what follows its padding.
+    fn read_string(packet: &[u8]) -> (String, &[u8]) {
+        let end = packet.iter().position(|b| *b == 0).unwrap();
+        let padded = (end / 4 + 1) * 4;
+        assert!(packet[end..padded].iter().all(|b| *b == 0));
+        (
+            String::from_utf8(packet[..end].to_vec()).unwrap(),
+            &packet[padded..],
+        )
+    }
+
+    /// Splits a message into its address, type tags and raw arguments.
+    fn decode(packet: &[u8]) -> (String, String, Vec<[u8; 4]>) {
+        assert_eq!(packet.len() % 4, 0);
+        let (address, rest) = read_string(packet);
+        let (tags, rest) = read_string(rest);
+        let args = rest.chunks(4).map(|c| c.try_into().unwrap()).collect();
+        (address, tags, args)
+    }
+
+    fn receive(socket: &UdpSocket) -> (String, String, Vec<[u8; 4]>) {
+        let mut buf = [0; 1024];
+        let len = socket.recv(&mut buf).unwrap();
+        decode(&buf[..len])
+    }
+
+    async fn sink(socket: &UdpSocket, units: Units, derived: bool) -> OscSink {
+        let options = OscOptions {
+            osc: Some(socket.local_addr().unwrap().to_string()),
+            osc_address: "/mitch/{name}/{side}".to_string(),
+        };
+        let derivation = derived
+            .then(|| Derivation::new(InsoleSize::Medium, Side::Left, Calibration::default()));
+        OscSink::new(
+            &options,
+            "insole",
+            Some(Side::Left),
+            StreamMode::Pressure,
+            units,
+            Calibration::default(),
+            derivation,
+        )
+        .await
+        .unwrap()
+        .unwrap()
+    }
+
+    fn receiver() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        socket
+    }
+
+    fn sample() -> Sample {
+        let mut values = vec![0; PRESSURE_SENSORS.len()];
+        values[0] = 40;
+        values[1] = -3;
+        Sample {
+            timestamp: 1.0,
+            values,
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_raw_counts_as_int32() {
+        let socket = receiver();
+        let mut sink = sink(&socket, Units::Raw, false).await;
+        sink.send_samples(&[sample()]);
+        let (address, tags, args) = receive(&socket);
+        assert_eq!(address, "/mitch/insole/left/raw");
+        assert_eq!(tags, format!(",{}", "i".repeat(PRESSURE_SENSORS.len())));
+        assert_eq!(i32::from_be_bytes(args[0]), 40);
+        assert_eq!(i32::from_be_bytes(args[1]), -3);
+        assert_eq!(i32::from_be_bytes(args[2]), 0);
+    }
+
+    #[tokio::test]
+    async fn sends_physical_and_derived_values_as_float32() {
+        let socket = receiver();
+        let mut sink = sink(&socket, Units::Physical, true).await;
+        sink.send_samples(&[sample()]);
+        let (address, tags, args) = receive(&socket);
+        assert_eq!(address, "/mitch/insole/left/raw");
+        assert_eq!(tags, format!(",{}", "f".repeat(PRESSURE_SENSORS.len())));
+        let expected = Calibration::default().to_physical(StreamMode::Pressure, &sample().values);
+        for (arg, value) in args.iter().zip(expected) {
+            assert_eq!(f32::from_be_bytes(*arg), value);
+        }
+
+        let (address, tags, args) = receive(&socket);
+        assert_eq!(address, "/mitch/insole/left/derived");
+        let derived = Derivation::new(InsoleSize::Medium, Side::Left, Calibration::default())
+            .compute(&sample().values);
+        assert_eq!(tags, format!(",{}", "f".repeat(derived.len())));
+        for (arg, value) in args.iter().zip(derived) {
+            assert_eq!(f32::from_be_bytes(*arg).to_bits(), value.to_bits());
+        }
+    }
+
+    #[test]
+    fn pads_strings_to_four_bytes() {
+        let packet = encode_message("/abc", &[]);
+        assert_eq!(packet, b"/abc\0\0\0\0,\0\0\0");
+    }
+}
@@ -0,0 +1,281 @@
+use super::{
+    Marker,
+    derived::{DERIVED_CHANNELS, Derivation},
+};
+use crate::{
+    mitch::{
+        ACCELEROMETER_AXES, StreamConfig, StreamMode, Units,
+        layout::{InsoleSize, PRESSURE_SENSORS, Side},
+    },
+    protocol::{Calibration, DeviceIdentity},
+};
+use anyhow::{Result, anyhow};
+use lsl::{ChannelFormat, ExPushable as _, StreamInfo, StreamOutlet, XMLElement};
+
+/// How the sensor locations and the center of pressure are measured.
+const LOCATION_FRAME: &str =
+    "x towards the wearer's right, y from the back of the heel towards the toes";
+
+/// Everything that goes into the description of a device's LSL stream.
+#[derive(Clone)]
+pub struct StreamMeta {
+    pub name: String,
+    pub stream_type: String,
+    pub source_id: String,
+    pub stream: StreamConfig,
+    pub units: Units,
+    pub side: Option<Side>,
+    pub size: InsoleSize,
+    /// Whether the derived stream is published next to the samples.
+    pub derived: bool,
+    /// Applied to the samples before they are pushed.
+    pub calibration: Option<Calibration>,
+    pub identity: DeviceIdentity,
+}
+
+impl StreamMeta {
+    pub fn format(&self) -> ChannelFormat {
+        match self.units {
+            Units::Raw => ChannelFormat::Int16,
+            Units::Physical => ChannelFormat::Float32,
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self.units {
+            Units::Raw => "counts",
+            Units::Physical => self.stream.mode().physical_unit(),
+        }
+    }
+
+    /// Builds the stream info, describing every channel and the device it
+    /// comes from so recordings can be interpreted on their own.
+    pub fn stream_info(&self) -> Result<StreamInfo> {
+        let mut info = StreamInfo::new(
+            &self.name,
+            &self.stream_type,
+            self.stream.mode().channel_count() as u32,
+            self.stream.rate() as f64,
+            self.format(),
+            &self.source_id,
+        )
+        .map_err(|e| anyhow!("failed to create LSL stream info: {e:?}"))?;
+
+        let mut desc = info.desc();
+        let mut channels = desc.append_child("channels");
+        match self.stream.mode() {
+            StreamMode::Pressure => {
+                let offsets = self.calibration.as_ref().and_then(|c| c.offsets.as_ref());
+                let gains = self
+                    .calibration
+                    .as_ref()
+                    .and_then(|c| c.gains.as_ref())
+                    .filter(|_| self.units == Units::Physical);
+                for (i, sensor) in PRESSURE_SENSORS.iter().enumerate() {
+                    let mut channel = channels.append_child("channel");
+                    channel.append_child_value("label", sensor.label);
+                    channel.append_child_value("unit", self.unit());
+                    channel.append_child_value("type", "Pressure");
+                    channel.append_child_value("region", &sensor.region.to_string());
+                    if let Some(offset) = offsets.and_then(|o| o.get(i)) {
+                        // Adding it back recovers the raw counts.
+                        channel.append_child_value("offset", &offset.to_string());
+                    }
+                    if let Some(gain) = gains.and_then(|g| g.get(i)).copied().flatten() {
+                        channel.append_child_value("gain_linear", &gain.linear.to_string());
+                        channel.append_child_value("gain_quadratic", &gain.quadratic.to_string());
+                    }
+                    if let Some(side) = self.side {
+                        let (x, y) = sensor.position(self.size, side);
+                        let mut location = channel.append_child("location");
+                        location.append_child_value("X", &x.to_string());
+                        location.append_child_value("Y", &y.to_string());
+                        location.append_child_value("Z", "0");
+                    }
+                }
+            }
+            StreamMode::Accelerometry => {
+                for axis in ACCELEROMETER_AXES {
+                    let mut channel = channels.append_child("channel");
+                    channel.append_child_value("label", axis);
+                    channel.append_child_value("unit", self.unit());
+                    channel.append_child_value("type", "Acceleration");
+                }
+            }
+        }
+
+        let mut setup = desc.append_child("setup");
+        setup.append_child_value("mode", &self.stream.mode().to_string());
+        setup.append_child_value("sampling_rate", &self.stream.rate().to_string());
+        setup.append_child_value(
+            "side",
+            &self
+                .side
+                .map(|side| side.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+        );
+        setup.append_child_value("insole_size", &self.size.to_string());
+        if let Some(tared_at) = self.calibration.as_ref().and_then(|c| c.tared_at.as_ref()) {
+            setup.append_child_value("tared_at", tared_at);
+        }
+        if let Some(calibrated_at) = self
+            .calibration
+            .as_ref()
+            .and_then(|c| c.calibrated_at.as_ref())
+            .filter(|_| self.units == Units::Physical)
+        {
+            setup.append_child_value("calibrated_at", calibrated_at);
+        }
+        setup.append_child_value("location_unit", "mm");
+        setup.append_child_value("location_frame", LOCATION_FRAME);
+
+        self.describe_acquisition(&mut desc);
+        Ok(info)
+    }
+
+    /// Describes the derived stream, named and identified after the samples
+    /// stream it is computed from.
+    pub fn derived_info(&self) -> Result<StreamInfo> {
+        let mut info = StreamInfo::new(
+            &format!("{}_derived", self.name),
+            "Force",
+            DERIVED_CHANNELS.len() as u32,
+            self.stream.rate() as f64,
+            ChannelFormat::Float32,
+            &format!("{}_derived", self.source_id),
+        )
+        .map_err(|e| anyhow!("failed to create LSL stream info: {e:?}"))?;
+
+        let mut desc = info.desc();
+        let mut channels = desc.append_child("channels");
+        for (label, unit, channel_type) in DERIVED_CHANNELS {
+            let mut channel = channels.append_child("channel");
+            channel.append_child_value("label", label);
+            channel.append_child_value("unit", unit);
+            channel.append_child_value("type", channel_type);
+        }
+
+        let mut setup = desc.append_child("setup");
+        setup.append_child_value("source_stream", &self.name);
+        setup.append_child_value("insole_size", &self.size.to_string());
+        if let Some(side) = self.side {
+            setup.append_child_value("side", &side.to_string());
+        }
+        setup.append_child_value("location_unit", "mm");
+        setup.append_child_value("location_frame", LOCATION_FRAME);
+
+        self.describe_acquisition(&mut desc);
+        Ok(info)
+    }
+
+    fn describe_acquisition(&self, desc: &mut XMLElement) {
+        let identity = &self.identity;
+        let mut acquisition = desc.append_child("acquisition");
+        acquisition.append_child_value("mac", &identity.mac);
+        for (key, value) in [
+            ("manufacturer", &identity.manufacturer),
+            ("model", &identity.model),
+            ("serial_number", &identity.serial),
+            ("firmware_version", &identity.firmware_version),
+            ("bootloader_version", &identity.bootloader_version),
+            ("hardware_revision", &identity.hardware_revision),
+        ] {
+            if let Some(value) = value {
+                acquisition.append_child_value(key, value);
+            }
+        }
+        acquisition.append_child_value("software", env!("CARGO_PKG_NAME"));
+        acquisition.append_child_value("daemon_version", env!("CARGO_PKG_VERSION"));
+    }
+
+    /// Whether an outlet created from `other` is indistinguishable from one
+    /// created from `self` for LSL consumers.
+    pub fn same_stream(&self, other: &StreamMeta) -> bool {
+        self.name == other.name
+            && self.stream_type == other.stream_type
+            && self.source_id == other.source_id
+            && self.stream == other.stream
+            && self.units == other.units
+            && self.size == other.size
+            && self.derived == other.derived
+            && self.calibration == other.calibration
+    }
+}
+
+/// Builds a source id that stays the same for a device across reconnects and
+/// daemon restarts, so recorders can resume a stream they lost.
+pub fn source_id(mac: &str, mode: StreamMode) -> String {
+    format!("mitch_{}_{}", mac.replace(':', "").to_lowercase(), mode)
+}
+
+/// A daemon-wide outlet carrying markers as strings, at irregular times.
+pub struct MarkerOutlet {
+    outlet: StreamOutlet,
+}
+
+impl MarkerOutlet {
+    /// Creates an outlet named `name`, which doubles as its source id.
+    pub fn new(name: &str) -> Result<Self> {
+        let mut info = StreamInfo::new(name, "Markers", 1, 0.0, ChannelFormat::String, name)
+            .map_err(|e| anyhow!("failed to create LSL stream info: {e:?}"))?;
+        let mut desc = info.desc();
+        let mut channel = desc.append_child("channels").append_child("channel");
+        channel.append_child_value("label", "marker");
+        channel.append_child_value("type", "Marker");
+        let mut acquisition = desc.append_child("acquisition");
+        acquisition.append_child_value("software", env!("CARGO_PKG_NAME"));
+        acquisition.append_child_value("daemon_version", env!("CARGO_PKG_VERSION"));
+
+        let outlet = StreamOutlet::new(&info, 1, 360)
+            .map_err(|e| anyhow!("failed to create LSL marker outlet: {e:?}"))?;
+        Ok(Self { outlet })
+    }
+
+    pub fn push(&self, marker: &Marker) -> Result<()> {
+        self.outlet
+            .push_sample_ex(&vec![marker.text.clone()], marker.timestamp, true)
+            .map_err(|e| anyhow!("failed to push marker: {e:?}"))
+    }
+}
+
+/// An LSL outlet together with the description it was created from.
+pub struct Outlet {
+    pub meta: StreamMeta,
+    pub outlet: StreamOutlet,
+    pub derived: Option<DerivedOutlet>,
+}
+
+/// The outlet of the stream derived from the samples.
+pub struct DerivedOutlet {
+    pub derivation: Derivation,
+    pub outlet: StreamOutlet,
+}
+
+impl Outlet {
+    pub fn new(meta: StreamMeta) -> Result<Self> {
+        let outlet = StreamOutlet::new(&meta.stream_info()?, 1, 360)
+            .map_err(|e| anyhow!("failed to create LSL outlet: {e:?}"))?;
+        let derived = match (meta.derived, meta.side) {
+            (false, _) => None,
+            (true, Some(side)) => Some(DerivedOutlet {
+                derivation: Derivation::new(
+                    meta.size,
+                    side,
+                    meta.calibration.clone().unwrap_or_default(),
+                ),
+                outlet: StreamOutlet::new(&meta.derived_info()?, 1, 360)
+                    .map_err(|e| anyhow!("failed to create LSL derived outlet: {e:?}"))?,
+            }),
+            (true, None) => {
+                return Err(anyhow!(
+                    "the derived stream needs the insole's side, connect with --side"
+                ));
+            }
+        };
+        Ok(Self {
+            meta,
+            outlet,
+            derived,
+        })
+    }
+}
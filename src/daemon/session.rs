@@ -0,0 +1,171 @@
+use crate::protocol::{SessionDevice, SessionLog};
+use anyhow::{Context, Result, anyhow};
+use clap::Args;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+const LOG_FILE: &str = "session.json";
+
+#[derive(Debug, Clone, Args)]
+pub struct SessionConfig {
+    /// Directory sessions are saved in, one subdirectory per session
+    #[clap(long, default_value = "mitch_sessions")]
+    pub data_dir: PathBuf,
+}
+
+/// Current wall clock time as used in session logs.
+pub fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+/// Keeps the logs of sessions with devices still recording and writes them
+/// to disk whenever they change.
+pub struct Sessions {
+    dir: PathBuf,
+    active: Mutex<HashMap<String, SessionLog>>,
+}
+
+impl Sessions {
+    /// Resolves the data directory once, so recordings do not move with the
+    /// working directory of whoever reads the logs.
+    pub fn new(config: &SessionConfig) -> Result<Self> {
+        let dir = std::path::absolute(&config.data_dir)
+            .with_context(|| format!("failed to resolve {}", config.data_dir.display()))?;
+        info!("Sessions are saved in {}", dir.display());
+        Ok(Self {
+            dir,
+            active: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Directory holding the data and log of session `name`.
+    pub fn dir(&self, name: &str) -> Result<PathBuf> {
+        // Names end up in paths, keep them from escaping the data directory.
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(anyhow!("invalid session name `{name}`"));
+        }
+        Ok(self.dir.join(name))
+    }
+
+    /// Adds a device to session `name`, starting the session unless another
+    /// device is already recording in it. Finished sessions are never
+    /// reopened so their logs stay as they were.
+    pub async fn join(
+        &self,
+        name: &str,
+        meta: &[(String, String)],
+        device: SessionDevice,
+    ) -> Result<()> {
+        let dir = self.dir(name)?;
+        let mut active = self.active.lock().await;
+        let log = match active.get_mut(name) {
+            Some(log) => {
+                if log
+                    .devices
+                    .iter()
+                    .any(|d| d.name == device.name && d.stopped_at.is_none())
+                {
+                    return Err(anyhow!("{} is already recording in {name}", device.name));
+                }
+                log.meta.extend(meta.iter().cloned());
+                log
+            }
+            None => {
+                if dir.join(LOG_FILE).exists() {
+                    return Err(anyhow!("session {name} already exists"));
+                }
+                fs::create_dir_all(&dir)
+                    .with_context(|| format!("failed to create {}", dir.display()))?;
+                info!("Session {} started", name);
+                active.entry(name.to_string()).or_insert(SessionLog {
+                    name: name.to_string(),
+                    started_at: now(),
+                    stopped_at: None,
+                    meta: meta.iter().cloned().collect::<BTreeMap<_, _>>(),
+                    devices: Vec::new(),
+                })
+            }
+        };
+        log.devices.push(device);
+        write_log(&dir, log)
+    }
+
+    /// Records that a device stopped, closing the session once no device
+    /// records in it anymore.
+    pub async fn leave(&self, name: &str, device: SessionDevice) {
+        let Ok(dir) = self.dir(name) else {
+            return;
+        };
+        let mut active = self.active.lock().await;
+        let Some(log) = active.get_mut(name) else {
+            warn!("Session {} is not active", name);
+            return;
+        };
+        if let Some(entry) = log
+            .devices
+            .iter_mut()
+            .rev()
+            .find(|d| d.name == device.name && d.stopped_at.is_none())
+        {
+            *entry = device;
+        }
+        let finished = log.devices.iter().all(|d| d.stopped_at.is_some());
+        if finished {
+            log.stopped_at = Some(now());
+            info!("Session {} finished", name);
+        }
+        if let Err(e) = write_log(&dir, log) {
+            warn!("Session {}: {:#}", name, e);
+        }
+        if finished {
+            active.remove(name);
+        }
+    }
+
+    /// Logs of all sessions in the data directory, oldest first.
+    pub fn list(&self) -> Result<Vec<SessionLog>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context(format!("failed to read {}", self.dir.display())),
+        };
+        let mut logs = Vec::new();
+        for entry in entries {
+            let path = entry?.path().join(LOG_FILE);
+            if !path.exists() {
+                continue;
+            }
+            match read_log(&path) {
+                Ok(log) => logs.push(log),
+                Err(e) => warn!("Skipping {}: {:#}", path.display(), e),
+            }
+        }
+        // RFC 3339 times in the same zone sort chronologically.
+        logs.sort_by(|a, b| a.started_at.cmp(&b.started_at));
+        Ok(logs)
+    }
+
+    pub async fn load(&self, name: &str) -> Result<SessionLog> {
+        if let Some(log) = self.active.lock().await.get(name) {
+            return Ok(log.clone());
+        }
+        read_log(&self.dir(name)?.join(LOG_FILE))
+    }
+}
+
+fn write_log(dir: &Path, log: &SessionLog) -> Result<()> {
+    let path = dir.join(LOG_FILE);
+    let json = serde_json::to_vec_pretty(log)?;
+    fs::write(&path, json).with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn read_log(path: &Path) -> Result<SessionLog> {
+    let json = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(serde_json::from_slice(&json)?)
+}
@@ -0,0 +1,514 @@
+use super::{Marker, outlet::StreamMeta};
+use crate::{
+    mitch::{StreamMode, Units},
+    protocol::{Calibration, OutputFormat, Sample},
+};
+use anyhow::{Context, Result};
+use arrow_array::{
+    ArrayRef, Float32Array, Float64Array, Int16Array, RecordBatch, StringArray, UInt16Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::{
+    arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties, format::KeyValue,
+};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    iter,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// How much a crash of the daemon may lose at most.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Rows collected before they are handed to the Parquet writer.
+const BATCH_ROWS: usize = 4096;
+
+/// Rows per Parquet row group, about five minutes at 200 Hz. Only the row
+/// group being written is held in memory.
+const ROW_GROUP_ROWS: usize = 65536;
+
+/// A recording's output file in either format.
+pub enum Sink {
+    Csv(CsvSink),
+    Parquet(Box<ParquetSink>),
+}
+
+impl Sink {
+    /// Creates `path` for the recording of `device` described by `meta`.
+    /// `session` and its `session_meta` only go into Parquet files, CSV
+    /// files rely on the session log next to them.
+    pub fn create(
+        format: OutputFormat,
+        path: &Path,
+        device: &str,
+        meta: &StreamMeta,
+        session: Option<&str>,
+        session_meta: &[(String, String)],
+    ) -> Result<Self> {
+        Ok(match format {
+            OutputFormat::Csv => Sink::Csv(CsvSink::create(
+                path,
+                meta.stream.mode(),
+                meta.units,
+                meta.calibration.clone().unwrap_or_default(),
+            )?),
+            OutputFormat::Parquet => Sink::Parquet(Box::new(ParquetSink::create(
+                path,
+                device,
+                meta,
+                session,
+                session_meta,
+            )?)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            Sink::Csv(sink) => sink.path(),
+            Sink::Parquet(sink) => sink.path(),
+        }
+    }
+
+    pub fn write_samples(&mut self, counter: u16, samples: &[Sample]) -> Result<()> {
+        match self {
+            Sink::Csv(sink) => sink.write_samples(counter, samples),
+            Sink::Parquet(sink) => sink.write_samples(counter, samples),
+        }
+    }
+
+    pub fn write_marker(&mut self, marker: &Marker) -> Result<()> {
+        match self {
+            Sink::Csv(sink) => sink.write_marker(marker),
+            Sink::Parquet(sink) => sink.write_marker(marker),
+        }
+    }
+
+    pub fn close(self) -> Result<()> {
+        match self {
+            Sink::Csv(sink) => sink.close(),
+            Sink::Parquet(sink) => (*sink).close(),
+        }
+    }
+}
+
+/// Writes a device's samples and the daemon's markers to a CSV file, one row
+/// per sample or marker. Marker rows leave the channels empty, sample rows
+/// the marker.
+pub struct CsvSink {
+    path: PathBuf,
+    mode: StreamMode,
+    units: Units,
+    /// Converts the counts when writing physical units.
+    calibration: Calibration,
+    writer: BufWriter<File>,
+    last_flush: Instant,
+}
+
+impl CsvSink {
+    pub fn create(
+        path: &Path,
+        mode: StreamMode,
+        units: Units,
+        calibration: Calibration,
+    ) -> Result<Self> {
+        let file = File::create_new(path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writeln!(
+            writer,
+            "timestamp,counter,{},marker",
+            mode.channel_labels().join(",")
+        )?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            mode,
+            units,
+            calibration,
+            writer,
+            last_flush: Instant::now(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write_samples(&mut self, counter: u16, samples: &[Sample]) -> Result<()> {
+        for sample in samples {
+            let values: Vec<String> = match self.units {
+                Units::Raw => sample.values.iter().map(|v| v.to_string()).collect(),
+                Units::Physical => self
+                    .calibration
+                    .to_physical(self.mode, &sample.values)
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect(),
+            };
+            writeln!(
+                self.writer,
+                "{:.6},{},{},",
+                sample.timestamp,
+                counter,
+                values.join(",")
+            )?;
+        }
+        self.flush_if_due()
+    }
+
+    pub fn write_marker(&mut self, marker: &Marker) -> Result<()> {
+        let empty = ",".repeat(self.mode.channel_count());
+        writeln!(
+            self.writer,
+            "{:.6},{},{}",
+            marker.timestamp,
+            empty,
+            csv_field(&marker.text)
+        )?;
+        // Markers are rare and precious, do not keep them in the buffer.
+        self.writer.flush()?;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    /// Flushes what is left, unlike dropping the sink this reports failures.
+    pub fn close(mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn flush_if_due(&mut self) -> Result<()> {
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.writer.flush()?;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+/// Quotes a field if it would otherwise break the row.
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// Writes a device's samples and the daemon's markers to a Parquet file with
+/// the same rows as [`CsvSink`], plus columns naming the device and session
+/// so files of several devices can simply be concatenated. Everything else
+/// known about the recording goes into the file's key-value metadata.
+///
+/// Unlike a CSV file, a Parquet file is only readable once it was closed.
+pub struct ParquetSink {
+    path: PathBuf,
+    mode: StreamMode,
+    units: Units,
+    calibration: Calibration,
+    schema: SchemaRef,
+    writer: ArrowWriter<File>,
+    device: String,
+    mac: String,
+    side: Option<String>,
+    session: Option<String>,
+    timestamps: Vec<f64>,
+    counters: Vec<Option<u16>>,
+    /// One column per channel, in the file's units.
+    channels: Vec<Vec<Option<f32>>>,
+    markers: Vec<Option<String>>,
+}
+
+impl ParquetSink {
+    pub fn create(
+        path: &Path,
+        device: &str,
+        meta: &StreamMeta,
+        session: Option<&str>,
+        session_meta: &[(String, String)],
+    ) -> Result<Self> {
+        let mode = meta.stream.mode();
+        let channel_type = match meta.units {
+            Units::Raw => DataType::Int16,
+            Units::Physical => DataType::Float32,
+        };
+        let mut fields = vec![
+            Field::new("timestamp", DataType::Float64, false),
+            Field::new("counter", DataType::UInt16, true),
+            Field::new("device", DataType::Utf8, false),
+            Field::new("mac", DataType::Utf8, false),
+            Field::new("side", DataType::Utf8, true),
+            Field::new("session", DataType::Utf8, true),
+        ];
+        fields.extend(
+            mode.channel_labels()
+                .iter()
+                .map(|label| Field::new(*label, channel_type.clone(), true)),
+        );
+        fields.push(Field::new("marker", DataType::Utf8, true));
+        let schema = Arc::new(Schema::new(fields));
+
+        let identity = &meta.identity;
+        let mut metadata = vec![
+            ("device", Some(device.to_string())),
+            ("mac", Some(identity.mac.clone())),
+            ("side", meta.side.map(|s| s.to_string())),
+            ("serial", identity.serial.clone()),
+            ("firmware_version", identity.firmware_version.clone()),
+            ("mode", Some(mode.to_string())),
+            ("rate", Some(meta.stream.rate().to_string())),
+            ("units", Some(meta.units.to_string())),
+            ("unit", Some(meta.unit().to_string())),
+            ("insole_size", Some(meta.size.to_string())),
+            ("session", session.map(str::to_string)),
+            (
+                "calibration",
+                meta.calibration
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()?,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some(KeyValue::new(key.to_string(), value?)))
+        .collect::<Vec<_>>();
+        metadata.extend(
+            session_meta
+                .iter()
+                .map(|(key, value)| KeyValue::new(format!("meta.{key}"), value.clone())),
+        );
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(ROW_GROUP_ROWS)
+            .set_key_value_metadata(Some(metadata))
+            .build();
+
+        let file = File::create_new(path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        let writer = ArrowWriter::try_new(file, schema.clone(), Some(properties))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            mode,
+            units: meta.units,
+            calibration: meta.calibration.clone().unwrap_or_default(),
+            schema,
+            writer,
+            device: device.to_string(),
+            mac: identity.mac.clone(),
+            side: meta.side.map(|s| s.to_string()),
+            session: session.map(str::to_string),
+            timestamps: Vec::with_capacity(BATCH_ROWS),
+            counters: Vec::with_capacity(BATCH_ROWS),
+            channels: vec![Vec::with_capacity(BATCH_ROWS); mode.channel_count()],
+            markers: Vec::with_capacity(BATCH_ROWS),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write_samples(&mut self, counter: u16, samples: &[Sample]) -> Result<()> {
+        for sample in samples {
+            let values = match self.units {
+                Units::Raw => sample.values.iter().map(|v| *v as f32).collect(),
+                Units::Physical => self.calibration.to_physical(self.mode, &sample.values),
+            };
+            self.write_values(sample.timestamp, Some(counter), &values)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a sample whose values are in the file's units already.
+    pub fn write_values(
+        &mut self,
+        timestamp: f64,
+        counter: Option<u16>,
+        values: &[f32],
+    ) -> Result<()> {
+        self.timestamps.push(timestamp);
+        self.counters.push(counter);
+        for (i, column) in self.channels.iter_mut().enumerate() {
+            column.push(values.get(i).copied());
+        }
+        self.markers.push(None);
+        self.write_if_full()
+    }
+
+    pub fn write_marker(&mut self, marker: &Marker) -> Result<()> {
+        self.timestamps.push(marker.timestamp);
+        self.counters.push(None);
+        for column in &mut self.channels {
+            column.push(None);
+        }
+        self.markers.push(Some(marker.text.clone()));
+        self.write_if_full()
+    }
+
+    /// Writes what is left and the file's footer, without which the file
+    /// cannot be read.
+    pub fn close(mut self) -> Result<()> {
+        self.write_batch()?;
+        self.writer.close()?;
+        Ok(())
+    }
+
+    fn write_if_full(&mut self) -> Result<()> {
+        if self.timestamps.len() >= BATCH_ROWS {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+
+    /// Hands the collected rows to the writer, which encodes them into the
+    /// current row group.
+    fn write_batch(&mut self) -> Result<()> {
+        let rows = self.timestamps.len();
+        if rows == 0 {
+            return Ok(());
+        }
+        let repeated = |value: &Option<String>| -> ArrayRef {
+            Arc::new(StringArray::from_iter(iter::repeat_n(
+                value.as_deref(),
+                rows,
+            )))
+        };
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(Float64Array::from(std::mem::take(&mut self.timestamps))),
+            Arc::new(UInt16Array::from(std::mem::take(&mut self.counters))),
+            Arc::new(StringArray::from_iter_values(iter::repeat_n(
+                &self.device,
+                rows,
+            ))),
+            Arc::new(StringArray::from_iter_values(iter::repeat_n(
+                &self.mac, rows,
+            ))),
+            repeated(&self.side),
+            repeated(&self.session),
+        ];
+        for column in &mut self.channels {
+            let column = std::mem::take(column);
+            columns.push(match self.units {
+                Units::Raw => Arc::new(Int16Array::from_iter(
+                    column.into_iter().map(|v| v.map(|v| v as i16)),
+                )),
+                Units::Physical => Arc::new(Float32Array::from(column)),
+            });
+        }
+        columns.push(Arc::new(StringArray::from(std::mem::take(
+            &mut self.markers,
+        ))));
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+        self.writer
+            .write(&batch)
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mitch::{StreamConfig, layout::InsoleSize, layout::Side},
+        protocol::DeviceIdentity,
+    };
+    use arrow_array::Array;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    fn meta() -> StreamMeta {
+        let mode = StreamMode::Accelerometry;
+        StreamMeta {
+            name: "mitch_L".to_string(),
+            stream_type: mode.stream_type().to_string(),
+            source_id: "source".to_string(),
+            stream: StreamConfig::new(mode, 50).unwrap(),
+            units: Units::Raw,
+            side: Some(Side::Left),
+            size: InsoleSize::default(),
+            derived: false,
+            calibration: None,
+            identity: DeviceIdentity {
+                mac: "00:11:22:33:44:55".to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn parquet_round_trip() {
+        let path = std::env::temp_dir().join(format!("mitch_sink_{}.parquet", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let meta = meta();
+        let meta_pairs = [("subject".to_string(), "s01".to_string())];
+        let mut sink =
+            ParquetSink::create(&path, "mitch_L", &meta, Some("trial"), &meta_pairs).unwrap();
+        // More than a batch, so rows are written in several pieces.
+        let samples = BATCH_ROWS + 100;
+        for i in 0..samples {
+            let sample = Sample {
+                timestamp: i as f64 * 0.02,
+                values: vec![i as i16, -(i as i16), 7],
+            };
+            sink.write_samples(i as u16, &[sample]).unwrap();
+            if i % 1000 == 0 {
+                sink.write_marker(&Marker {
+                    timestamp: i as f64 * 0.02,
+                    text: format!("mark {i}"),
+                })
+                .unwrap();
+            }
+        }
+        sink.close().unwrap();
+        let markers = samples.div_ceil(1000);
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap();
+        let kv: Vec<(String, Option<String>)> = builder
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .unwrap()
+            .iter()
+            .map(|kv| (kv.key.clone(), kv.value.clone()))
+            .collect();
+        let value = |key: &str| {
+            kv.iter()
+                .find(|(k, _)| k == key)
+                .and_then(|(_, v)| v.clone())
+        };
+        assert_eq!(value("device").as_deref(), Some("mitch_L"));
+        assert_eq!(value("mac").as_deref(), Some("00:11:22:33:44:55"));
+        assert_eq!(value("side").as_deref(), Some("left"));
+        assert_eq!(value("rate").as_deref(), Some("50"));
+        assert_eq!(value("session").as_deref(), Some("trial"));
+        assert_eq!(value("meta.subject").as_deref(), Some("s01"));
+        assert_eq!(value("calibration"), None);
+
+        let schema = builder.schema().clone();
+        let names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        let mut expected = vec!["timestamp", "counter", "device", "mac", "side", "session"];
+        expected.extend(StreamMode::Accelerometry.channel_labels());
+        expected.push("marker");
+        assert_eq!(names, expected);
+        assert_eq!(schema.field(6).data_type(), &DataType::Int16);
+
+        let mut rows = 0;
+        let mut marker_rows = 0;
+        for batch in builder.build().unwrap() {
+            let batch = batch.unwrap();
+            rows += batch.num_rows();
+            let marker = batch.column(batch.num_columns() - 1);
+            marker_rows += marker.len() - marker.null_count();
+            let device = batch
+                .column(2)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap();
+            assert!(device.iter().all(|d| d == Some("mitch_L")));
+        }
+        assert_eq!(rows, samples + markers);
+        assert_eq!(marker_rows, markers);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
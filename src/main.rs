@@ -1,12 +1,18 @@
 use clap::{Parser, Subcommand};
-use daemon::Daemon;
+use daemon::{Daemon, DaemonConfig};
+use std::path::PathBuf;
 use tokio::task::LocalSet;
 use tracing::{Level, info};
 use tracing_subscriber::FmtSubscriber;
+mod calibrate;
 mod client;
+mod convert;
 mod daemon;
 pub mod mitch;
+mod monitor;
 mod protocol;
+mod replay;
+mod xdf;
 
 #[derive(Debug, Parser)]
 #[clap(name = "mitch_cli", version = "0.1.0")]
@@ -17,7 +23,10 @@ struct Cli {
 
 #[derive(Debug, Subcommand)]
 enum Command {
-    DaemonStart,
+    DaemonStart {
+        #[clap(flatten)]
+        config: DaemonConfig,
+    },
     Scan {
         #[clap(short, long, default_value_t = 2000)]
         timeout: u64,
@@ -27,28 +36,187 @@ enum Command {
 
     Connect {
         name: String,
+        /// How often the daemon may restart the device's actor after a failure
+        #[clap(long, default_value_t = 0)]
+        restarts: u32,
+        /// Foot the insole is worn on, guessed from the name if not given
+        #[clap(long, value_enum)]
+        side: Option<mitch::layout::Side>,
     },
     Disconnect {
         name: String,
     },
     Record {
         name: String,
+        #[clap(flatten)]
+        options: protocol::RecordOptions,
+    },
+    /// Follow daemon events such as low battery warnings
+    Events,
+    /// Stop a device's recording
+    Stop {
+        name: String,
+    },
+    /// Inspect recorded sessions
+    Sessions {
+        #[clap(subcommand)]
+        command: SessionsCommand,
+    },
+    /// Record to a device's memory and read the logs back
+    Log {
+        #[clap(subcommand)]
+        command: LogCommand,
+    },
+    /// Manage device firmware
+    Firmware {
+        #[clap(subcommand)]
+        command: FirmwareCommand,
+    },
+    /// Zero a device's pressure sensors, keep the insole unloaded meanwhile
+    Tare {
+        name: String,
+        /// How long to average the unloaded sensors
+        #[clap(long, default_value_t = 3.0)]
+        seconds: f64,
+    },
+    /// Calibrate a device's pressure sensors against known loads, step by step
+    Calibrate {
+        name: String,
+        /// Reference loads in kg, placed one after the other
+        #[clap(long, value_delimiter = ',', required = true)]
+        loads: Vec<f32>,
+        /// Area of the plate the loads stand on, in cm²
+        #[clap(long)]
+        plate_area: f32,
+        /// How long to average each load
+        #[clap(long, default_value_t = 3.0)]
+        seconds: f64,
+        /// Only used when the device does not stream yet
+        #[clap(long, default_value_t = 50)]
+        rate: u16,
+    },
+    /// Write a device's samples to stdout, tared raw counts
+    Stream {
+        name: String,
+        #[clap(long, value_enum, default_value_t = client::SampleFormat::Csv)]
+        format: client::SampleFormat,
+        /// Only used when the device does not stream yet
+        #[clap(long, value_enum, default_value_t = mitch::StreamMode::Pressure)]
+        mode: mitch::StreamMode,
+        /// Only used when the device does not stream yet
+        #[clap(long, default_value_t = 50)]
+        rate: u16,
+    },
+    /// Publish a recorded CSV or XDF file or raw capture on LSL as if the
+    /// device were streaming
+    Replay {
+        file: PathBuf,
+        /// The device to replay from an XDF file holding several
+        #[clap(long)]
+        stream: Option<String>,
+        /// Playback speed relative to real time
+        #[clap(long, default_value_t = 1.0)]
+        speed: f64,
+        /// Start over at the end until interrupted
+        #[clap(long = "loop")]
+        looping: bool,
+    },
+    /// Convert a recorded CSV or XDF file or raw capture into a Parquet file
+    Convert {
+        input: PathBuf,
+        /// Defaults to the input with a `.parquet` extension
+        #[clap(long)]
+        output: Option<PathBuf>,
+        /// The device to convert from an XDF file holding several
+        #[clap(long)]
+        stream: Option<String>,
+    },
+    /// Annotate the recordings, e.g. with the start of a trial
+    Mark {
+        text: String,
+    },
+    /// Show a device's samples live in the terminal
+    Monitor {
+        name: String,
+        /// Only used when the device does not stream yet
+        #[clap(long, value_enum, default_value_t = mitch::StreamMode::Pressure)]
+        mode: mitch::StreamMode,
+        /// Only used when the device does not stream yet
+        #[clap(long, default_value_t = 50)]
+        rate: u16,
+        /// Foot to draw the insole for, guessed from the name if not given
+        #[clap(long, value_enum)]
+        side: Option<mitch::layout::Side>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum LogCommand {
+    /// Start recording to the device's memory, it keeps going out of range
+    Start {
+        name: String,
+        #[clap(long, value_enum, default_value_t = mitch::StreamMode::Pressure)]
+        mode: mitch::StreamMode,
+        #[clap(long, default_value_t = 50)]
+        rate: u16,
     },
+    /// Stop recording to the device's memory
+    Stop { name: String },
+    /// List the logs stored on the device
+    List { name: String },
+    /// Read a log into a file, verifying its checksum
+    Readout {
+        name: String,
+        index: u16,
+        /// File to write the log to, must not exist
+        #[clap(long)]
+        output: PathBuf,
+    },
+    /// Delete all logs stored on the device
+    Erase {
+        name: String,
+        /// Confirm that the logs may be deleted
+        #[clap(long)]
+        yes: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum FirmwareCommand {
+    /// Flash a firmware image through the device's bootloader
+    Update {
+        name: String,
+        image: PathBuf,
+        /// Only validate the image by flashing a simulated device, the daemon
+        /// and the device are not involved
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SessionsCommand {
+    /// List all sessions in the daemon's data directory
+    List,
+    /// Show a session's log
+    Show { name: String },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    // Logs stay off stdout, which `stream` pipes samples through.
     let subscriber = FmtSubscriber::builder()
         .with_max_level(Level::TRACE)
+        .with_writer(std::io::stderr)
         .finish();
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
     let args = Cli::parse();
 
     match args.command {
-        Command::DaemonStart => {
+        Command::DaemonStart { config } => {
             info!("Starting daemon...");
             let localset = LocalSet::new();
-            localset.run_until(Daemon::new().await?.run()).await?;
+            localset.run_until(Daemon::new(config).await?.run()).await?;
         }
         Command::Scan { timeout } => {
             client::run_client(protocol::ClientCommand::Scan {
@@ -56,17 +224,135 @@ async fn main() -> anyhow::Result<()> {
             })
             .await?;
         }
-        Command::Connect { name } => {
-            client::run_client(protocol::ClientCommand::Connect { name }).await?;
+        Command::Connect {
+            name,
+            restarts,
+            side,
+        } => {
+            client::run_client(protocol::ClientCommand::Connect {
+                name,
+                max_restarts: restarts,
+                side,
+            })
+            .await?;
         }
         Command::Disconnect { name } => {
             client::run_client(protocol::ClientCommand::Disconnect { name }).await?;
         }
-        Command::Record { name } => {
-            client::run_client(protocol::ClientCommand::Record { name }).await?
+        Command::Record { name, mut options } => {
+            // The daemon has its own working directory, resolve against ours.
+            options.output = options.output.map(std::path::absolute).transpose()?;
+            options.capture = options.capture.map(std::path::absolute).transpose()?;
+            client::run_client(protocol::ClientCommand::Record { name, options }).await?
+        }
+        Command::Stop { name } => {
+            client::run_client(protocol::ClientCommand::StopRecording { name }).await?
+        }
+        Command::Sessions { command } => {
+            let command = match command {
+                SessionsCommand::List => protocol::ClientCommand::ListSessions,
+                SessionsCommand::Show { name } => protocol::ClientCommand::ShowSession { name },
+            };
+            client::run_client(command).await?
+        }
+        Command::Log { command } => {
+            let (name, action) = match command {
+                LogCommand::Start { name, mode, rate } => {
+                    (name, protocol::LogAction::Start { mode, rate })
+                }
+                LogCommand::Stop { name } => (name, protocol::LogAction::Stop),
+                LogCommand::List { name } => (name, protocol::LogAction::List),
+                LogCommand::Readout {
+                    name,
+                    index,
+                    output,
+                } => (
+                    name,
+                    protocol::LogAction::Readout {
+                        index,
+                        output: std::path::absolute(output)?,
+                    },
+                ),
+                LogCommand::Erase { name, yes } => {
+                    if !yes {
+                        anyhow::bail!("erasing deletes every log stored on {name}, pass --yes");
+                    }
+                    (name, protocol::LogAction::Erase)
+                }
+            };
+            client::run_client(protocol::ClientCommand::DeviceLog { name, action }).await?
         }
+        Command::Firmware {
+            command:
+                FirmwareCommand::Update {
+                    name,
+                    image,
+                    dry_run,
+                },
+        } => {
+            if dry_run {
+                let image = mitch::dfu::FirmwareImage::load(&image)?;
+                let mut device = mitch::dfu::SimulatedMitch::default();
+                mitch::dfu::update(&mut device, &image, |_, _| {}).await?;
+                println!(
+                    "Dry run passed: {} bytes in {} chunks, crc {:08x}, simulated device {:?}",
+                    image.data.len(),
+                    image.chunk_count(),
+                    image.crc,
+                    device.state()
+                );
+            } else {
+                let image = std::path::absolute(image)?;
+                client::run_client(protocol::ClientCommand::FirmwareUpdate { name, image }).await?
+            }
+        }
+        Command::Tare { name, seconds } => {
+            let duration = seconds_to_duration(seconds)?;
+            client::run_client(protocol::ClientCommand::Tare { name, duration }).await?
+        }
+        Command::Calibrate {
+            name,
+            loads,
+            plate_area,
+            seconds,
+            rate,
+        } => calibrate::run(name, loads, plate_area, seconds_to_duration(seconds)?, rate).await?,
+        Command::Stream {
+            name,
+            format,
+            mode,
+            rate,
+        } => client::stream_samples(name, mode, rate, format).await?,
+        Command::Replay {
+            file,
+            stream,
+            speed,
+            looping,
+        } => replay::run(&file, stream.as_deref(), speed, looping).await?,
+        Command::Convert {
+            input,
+            output,
+            stream,
+        } => convert::run(&input, output, stream.as_deref())?,
+        Command::Mark { text } => {
+            client::run_client(protocol::ClientCommand::Marker { text }).await?
+        }
+        Command::Events => client::run_client(protocol::ClientCommand::Events).await?,
         Command::Status => client::run_client(protocol::ClientCommand::Status).await?,
+        Command::Monitor {
+            name,
+            mode,
+            rate,
+            side,
+        } => monitor::run(name, mode, rate, side).await?,
     }
 
     Ok(())
 }
+
+fn seconds_to_duration(seconds: f64) -> anyhow::Result<std::time::Duration> {
+    std::time::Duration::try_from_secs_f64(seconds)
+        .ok()
+        .filter(|d| !d.is_zero())
+        .ok_or_else(|| anyhow::anyhow!("--seconds must be positive"))
+}
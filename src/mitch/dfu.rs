@@ -0,0 +1,367 @@
+//! Firmware updates through the mitch's bootloader.
+//!
+//! The application is asked to reboot into its bootloader (`BootIdle`), the
+//! image's size and CRC-32 are announced, which moves the bootloader to
+//! `BootDownload`, and the image follows in numbered chunks that are each
+//! acknowledged. Once all chunks arrived the bootloader checks the CRC and is
+//! told to reboot into the new application.
+//!
+//! Every command is answered like the application's commands, `[ack, length,
+//! command, error, payload..]`, chunk acknowledgements carry the chunk's
+//! sequence number as payload. A chunk resent because its acknowledgement got
+//! lost is acknowledged again without being stored twice.
+
+use super::{MitchState, response_payload};
+use anyhow::{Context, Result, anyhow};
+use std::{fs, path::Path, time::Duration};
+use tokio::time;
+use tracing::warn;
+
+const ENTER_BOOTLOADER: u8 = 0x02;
+const BEGIN_DOWNLOAD: u8 = 0x30;
+const CHUNK: u8 = 0x31;
+const VERIFY: u8 = 0x32;
+const REBOOT: u8 = 0x33;
+
+/// Error codes of the bootloader.
+const ERR_SEQUENCE: u8 = 0x01;
+const ERR_CRC: u8 = 0x02;
+const ERR_STATE: u8 = 0x03;
+const ERR_SIZE: u8 = 0x04;
+
+/// Image bytes per chunk, leaving room for the header in a 244 byte write.
+pub const CHUNK_SIZE: usize = 128;
+
+/// Flash available to the application.
+pub const MAX_IMAGE_SIZE: usize = 448 * 1024;
+
+const CHUNK_RETRIES: u32 = 3;
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Something the updater can send commands to and read answers from.
+pub trait DfuLink {
+    /// Sends `command` and returns the device's response.
+    fn request(&mut self, command: Vec<u8>) -> impl Future<Output = Result<Vec<u8>>>;
+}
+
+/// A firmware image checked to fit the device.
+pub struct FirmwareImage {
+    pub data: Vec<u8>,
+    pub crc: u32,
+}
+
+impl FirmwareImage {
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        Self::new(data)
+    }
+
+    pub fn new(data: Vec<u8>) -> Result<Self> {
+        if data.is_empty() {
+            return Err(anyhow!("firmware image is empty"));
+        }
+        if data.len() > MAX_IMAGE_SIZE {
+            return Err(anyhow!(
+                "firmware image of {} bytes exceeds the {MAX_IMAGE_SIZE} bytes of flash",
+                data.len()
+            ));
+        }
+        // Flash is written in words.
+        if !data.len().is_multiple_of(4) {
+            return Err(anyhow!(
+                "firmware image of {} bytes is not word aligned",
+                data.len()
+            ));
+        }
+        let crc = crc32fast::hash(&data);
+        Ok(Self { data, crc })
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.data.len().div_ceil(CHUNK_SIZE)
+    }
+}
+
+/// Transfers `image` over `link` and reboots the device into it, calling
+/// `progress` with the bytes acknowledged so far.
+pub async fn update<L: DfuLink>(
+    link: &mut L,
+    image: &FirmwareImage,
+    mut progress: impl FnMut(u32, u32),
+) -> Result<()> {
+    let total = image.data.len() as u32;
+    if image.chunk_count() > u16::MAX as usize + 1 {
+        return Err(anyhow!("firmware image has too many chunks"));
+    }
+
+    expect_ok(
+        link.request(vec![ENTER_BOOTLOADER, 0x01, MitchState::BootIdle as u8])
+            .await?,
+        "enter bootloader",
+    )?;
+
+    let mut begin = vec![BEGIN_DOWNLOAD, 0x08];
+    begin.extend_from_slice(&total.to_le_bytes());
+    begin.extend_from_slice(&image.crc.to_le_bytes());
+    expect_ok(link.request(begin).await?, "begin download")?;
+
+    let mut sent = 0u32;
+    for (seq, chunk) in image.data.chunks(CHUNK_SIZE).enumerate() {
+        let seq = seq as u16;
+        let mut command = vec![CHUNK, (chunk.len() + 2) as u8];
+        command.extend_from_slice(&seq.to_le_bytes());
+        command.extend_from_slice(chunk);
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let res = match time::timeout(CHUNK_TIMEOUT, link.request(command.clone())).await {
+                Ok(Ok(res)) => check_ack(&res, seq),
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(anyhow!("no acknowledgement")),
+            };
+            match res {
+                Ok(()) => break,
+                Err(e) if attempt > CHUNK_RETRIES => {
+                    return Err(e.context(format!("chunk {seq} failed {attempt} times")));
+                }
+                Err(e) => warn!("Chunk {}: {:#}, retrying", seq, e),
+            }
+        }
+        sent += chunk.len() as u32;
+        progress(sent, total);
+    }
+
+    expect_ok(link.request(vec![VERIFY, 0x00]).await?, "verify image")?;
+    expect_ok(link.request(vec![REBOOT, 0x00]).await?, "reboot")?;
+    Ok(())
+}
+
+fn expect_ok(response: Vec<u8>, step: &str) -> Result<Vec<u8>> {
+    response_payload(&response)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| anyhow!("{step} failed: {}", describe(&response)))
+}
+
+fn check_ack(response: &[u8], seq: u16) -> Result<()> {
+    let payload =
+        response_payload(response).ok_or_else(|| anyhow!("rejected: {}", describe(response)))?;
+    match payload {
+        [lo, hi, ..] if u16::from_le_bytes([*lo, *hi]) == seq => Ok(()),
+        _ => Err(anyhow!("acknowledgement for another chunk: {payload:02x?}")),
+    }
+}
+
+fn describe(response: &[u8]) -> String {
+    match response.get(3) {
+        Some(&ERR_SEQUENCE) => "chunk out of sequence".to_string(),
+        Some(&ERR_CRC) => "checksum mismatch".to_string(),
+        Some(&ERR_STATE) => "not expected in the bootloader's state".to_string(),
+        Some(&ERR_SIZE) => "image does not fit".to_string(),
+        _ => format!("{response:02x?}"),
+    }
+}
+
+/// A bootloader in memory that follows the same rules as the device's, used
+/// to validate images and the transfer without touching hardware.
+pub struct SimulatedMitch {
+    state: MitchState,
+    size: usize,
+    crc: u32,
+    image: Vec<u8>,
+    next_seq: u16,
+}
+
+impl Default for SimulatedMitch {
+    fn default() -> Self {
+        Self {
+            state: MitchState::SysIdle,
+            size: 0,
+            crc: 0,
+            image: Vec::new(),
+            next_seq: 0,
+        }
+    }
+}
+
+impl SimulatedMitch {
+    pub fn state(&self) -> MitchState {
+        self.state
+    }
+
+    fn handle(&mut self, command: &[u8]) -> Result<Vec<u8>, u8> {
+        let [id, len, body @ ..] = command else {
+            return Err(ERR_SIZE);
+        };
+        if body.len() != *len as usize {
+            return Err(ERR_SIZE);
+        }
+        match (*id, self.state) {
+            (ENTER_BOOTLOADER, MitchState::SysIdle) if body == [MitchState::BootIdle as u8] => {
+                self.state = MitchState::BootIdle;
+                Ok(Vec::new())
+            }
+            (BEGIN_DOWNLOAD, MitchState::BootIdle) => {
+                let &[s0, s1, s2, s3, c0, c1, c2, c3] = body else {
+                    return Err(ERR_SIZE);
+                };
+                let size = u32::from_le_bytes([s0, s1, s2, s3]);
+                let crc = u32::from_le_bytes([c0, c1, c2, c3]);
+                if size as usize > MAX_IMAGE_SIZE {
+                    return Err(ERR_SIZE);
+                }
+                self.size = size as usize;
+                self.crc = crc;
+                self.image.clear();
+                self.next_seq = 0;
+                self.state = MitchState::BootDownload;
+                Ok(Vec::new())
+            }
+            (CHUNK, MitchState::BootDownload) => {
+                let (seq, data) = body.split_first_chunk::<2>().ok_or(ERR_SIZE)?;
+                let resent = !self.image.is_empty()
+                    && u16::from_le_bytes(*seq) == self.next_seq.wrapping_sub(1);
+                if resent {
+                    return Ok(seq.to_vec());
+                }
+                if u16::from_le_bytes(*seq) != self.next_seq {
+                    return Err(ERR_SEQUENCE);
+                }
+                if self.image.len() + data.len() > self.size {
+                    return Err(ERR_SIZE);
+                }
+                self.image.extend_from_slice(data);
+                self.next_seq = self.next_seq.wrapping_add(1);
+                Ok(seq.to_vec())
+            }
+            (VERIFY, MitchState::BootDownload) => {
+                if self.image.len() != self.size || crc32fast::hash(&self.image) != self.crc {
+                    return Err(ERR_CRC);
+                }
+                self.state = MitchState::BootIdle;
+                Ok(Vec::new())
+            }
+            (REBOOT, MitchState::BootIdle) => {
+                self.state = MitchState::SysStartup;
+                Ok(Vec::new())
+            }
+            _ => Err(ERR_STATE),
+        }
+    }
+}
+
+impl DfuLink for SimulatedMitch {
+    async fn request(&mut self, command: Vec<u8>) -> Result<Vec<u8>> {
+        let id = command.first().copied().unwrap_or_default();
+        let (error, payload) = match self.handle(&command) {
+            Ok(payload) => (0, payload),
+            Err(error) => (error, Vec::new()),
+        };
+        let mut response = vec![0x00, (payload.len() + 2) as u8, id, error];
+        response.extend(payload);
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Passes commands to a simulated device but loses the acknowledgement
+    /// of the chunk attempts listed in `lost`, counted from one.
+    struct LossyLink {
+        device: SimulatedMitch,
+        lost: Vec<u32>,
+        chunk_attempts: u32,
+    }
+
+    impl LossyLink {
+        fn new(lost: Vec<u32>) -> Self {
+            Self {
+                device: SimulatedMitch::default(),
+                lost,
+                chunk_attempts: 0,
+            }
+        }
+    }
+
+    impl DfuLink for LossyLink {
+        async fn request(&mut self, command: Vec<u8>) -> Result<Vec<u8>> {
+            let is_chunk = command.first() == Some(&CHUNK);
+            let response = self.device.request(command).await?;
+            if is_chunk {
+                self.chunk_attempts += 1;
+                if self.lost.contains(&self.chunk_attempts) {
+                    return Err(anyhow!("acknowledgement lost"));
+                }
+            }
+            Ok(response)
+        }
+    }
+
+    fn image(len: usize) -> FirmwareImage {
+        FirmwareImage::new((0..len).map(|i| (i * 7) as u8).collect()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn transfers_image_and_reboots() {
+        let image = image(3 * CHUNK_SIZE + 4);
+        let mut device = SimulatedMitch::default();
+        let mut progress = Vec::new();
+        update(&mut device, &image, |sent, total| {
+            progress.push((sent, total))
+        })
+        .await
+        .unwrap();
+        assert_eq!(device.state(), MitchState::SysStartup);
+        assert_eq!(device.image, image.data);
+        let total = image.data.len() as u32;
+        assert_eq!(progress.len(), image.chunk_count());
+        assert_eq!(progress.last(), Some(&(total, total)));
+    }
+
+    #[tokio::test]
+    async fn resends_chunk_whose_ack_got_lost() {
+        let image = image(3 * CHUNK_SIZE);
+        // The second chunk is stored, its acknowledgement lost and it is
+        // sent again.
+        let mut link = LossyLink::new(vec![2]);
+        update(&mut link, &image, |_, _| {}).await.unwrap();
+        assert_eq!(link.chunk_attempts, 4);
+        assert_eq!(link.device.image, image.data);
+        assert_eq!(link.device.state(), MitchState::SysStartup);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_retries() {
+        let image = image(2 * CHUNK_SIZE);
+        let mut link = LossyLink::new((2..=2 + CHUNK_RETRIES).collect());
+        let err = update(&mut link, &image, |_, _| {}).await.unwrap_err();
+        assert!(
+            format!("{err:#}").contains("chunk 1 failed 4 times"),
+            "{err:#}"
+        );
+        assert_eq!(link.chunk_attempts, 2 + CHUNK_RETRIES);
+        assert_eq!(link.device.state(), MitchState::BootDownload);
+    }
+
+    #[tokio::test]
+    async fn verify_rejects_crc_mismatch() {
+        let mut image = image(CHUNK_SIZE);
+        image.crc ^= 1;
+        let mut device = SimulatedMitch::default();
+        let err = update(&mut device, &image, |_, _| {}).await.unwrap_err();
+        assert_eq!(err.to_string(), "verify image failed: checksum mismatch");
+        // Not rebooted into the broken image.
+        assert_eq!(device.state(), MitchState::BootDownload);
+    }
+
+    #[test]
+    fn rejects_images_that_do_not_fit() {
+        assert!(FirmwareImage::new(Vec::new()).is_err());
+        assert!(FirmwareImage::new(vec![0; MAX_IMAGE_SIZE + 4]).is_err());
+        assert!(FirmwareImage::new(vec![0; 6]).is_err());
+        let image = FirmwareImage::new(vec![0; MAX_IMAGE_SIZE]).unwrap();
+        assert_eq!(image.chunk_count(), MAX_IMAGE_SIZE / CHUNK_SIZE);
+    }
+}
@@ -0,0 +1,262 @@
+//! Decoding of the notifications the mitch sends on its data characteristic
+//! while streaming.
+//!
+//! Every notification starts with a four byte header `[tag, length,
+//! counter_lo, counter_hi]` followed by `length` bytes holding one or more
+//! samples. Pressure samples are 16 unsigned bytes, one per sensor in the
+//! order of [`PRESSURE_SENSORS`](super::layout::PRESSURE_SENSORS),
+//! accelerometer samples are three little endian `i16` for x, y and z.
+//!
+//! The tag is the id of the mode streamed, as in the start stream command,
+//! so frames still in flight from a previous mode can be told apart.
+//!
+//! The length counts the samples only, not the counter before them. Command
+//! responses differ there: their length covers everything after it, the
+//! command and error bytes included (see
+//! [`response_payload`](super::response_payload)).
+
+use super::StreamMode;
+use std::fmt;
+
+pub const HEADER_LEN: usize = 4;
+
+/// Nominal pressure per raw count of the insole's sensors.
+pub const PRESSURE_KPA_PER_COUNT: f32 = 2.5;
+
+/// Acceleration per raw count at the firmware's ±4 g full scale.
+pub const ACCELERATION_G_PER_COUNT: f32 = 4.0 / 32768.0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// Fewer bytes than the header.
+    TooShort(usize),
+    /// The header announces a different payload length than was received.
+    LengthMismatch { declared: usize, actual: usize },
+    /// The tag belongs to another mode than the one expected.
+    WrongMode { tag: u8, mode: StreamMode },
+    /// The payload does not divide into whole samples.
+    PartialSample { payload: usize, sample_size: usize },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooShort(len) => {
+                write!(f, "frame of {len} bytes is shorter than its header")
+            }
+            FrameError::LengthMismatch { declared, actual } => write!(
+                f,
+                "frame declares {declared} payload bytes but carries {actual}"
+            ),
+            FrameError::WrongMode { tag, mode } => {
+                write!(f, "frame tagged {tag:#04x} is not a {mode} frame")
+            }
+            FrameError::PartialSample {
+                payload,
+                sample_size,
+            } => write!(
+                f,
+                "payload of {payload} bytes is not a multiple of the {sample_size} byte sample size"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// A counter jump this large is a restarted stream rather than lost frames.
+const MAX_COUNTER_GAP: u16 = 1000;
+
+/// Whether the counter moving on by `gap` (wrapping) means the device
+/// started counting anew, a repeated counter included.
+pub fn is_restart(gap: u16) -> bool {
+    gap == 0 || gap > MAX_COUNTER_GAP
+}
+
+/// Frames lost between the counters `last` and `counter`, none when the
+/// device started counting anew in between.
+pub fn lost_frames(last: u16, counter: u16) -> u64 {
+    let gap = counter.wrapping_sub(last);
+    if is_restart(gap) { 0 } else { gap as u64 - 1 }
+}
+
+/// A decoded notification.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// Counter the device increments with every frame it sends.
+    pub counter: u16,
+    /// Raw sample values, one vector of `mode.channel_count()` per sample.
+    pub samples: Vec<Vec<i16>>,
+}
+
+impl Frame {
+    pub fn parse(mode: StreamMode, data: &[u8]) -> Result<Self, FrameError> {
+        if data.len() < HEADER_LEN {
+            return Err(FrameError::TooShort(data.len()));
+        }
+        if data[0] != mode.id() {
+            return Err(FrameError::WrongMode { tag: data[0], mode });
+        }
+        let declared = data[1] as usize;
+        let payload = &data[HEADER_LEN..];
+        if declared != payload.len() {
+            return Err(FrameError::LengthMismatch {
+                declared,
+                actual: payload.len(),
+            });
+        }
+        let sample_size = mode.sample_size();
+        if payload.is_empty() || !payload.len().is_multiple_of(sample_size) {
+            return Err(FrameError::PartialSample {
+                payload: payload.len(),
+                sample_size,
+            });
+        }
+
+        let samples = payload
+            .chunks_exact(sample_size)
+            .map(|sample| match mode {
+                StreamMode::Pressure => sample.iter().map(|b| *b as i16).collect(),
+                StreamMode::Accelerometry => sample
+                    .chunks_exact(2)
+                    .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                    .collect(),
+            })
+            .collect();
+
+        Ok(Self {
+            counter: u16::from_le_bytes([data[2], data[3]]),
+            samples,
+        })
+    }
+}
+
+/// Converts a raw sample to physical units, kPa for pressure and g for
+/// acceleration.
+pub fn to_physical(mode: StreamMode, sample: &[i16]) -> Vec<f32> {
+    let scale = match mode {
+        StreamMode::Pressure => PRESSURE_KPA_PER_COUNT,
+        StreamMode::Accelerometry => ACCELERATION_G_PER_COUNT,
+    };
+    sample.iter().map(|v| *v as f32 * scale).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(tag: u8, counter: u16, payload: &[u8]) -> Vec<u8> {
+        let [lo, hi] = counter.to_le_bytes();
+        let mut data = vec![tag, payload.len() as u8, lo, hi];
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn parses_pressure_samples() {
+        let payload: Vec<u8> = (0..32).map(|i| i * 8).collect();
+        let parsed = Frame::parse(StreamMode::Pressure, &frame(0x01, 0x0203, &payload)).unwrap();
+        assert_eq!(parsed.counter, 0x0203);
+        assert_eq!(parsed.samples.len(), 2);
+        assert_eq!(
+            parsed.samples[0],
+            (0..16).map(|i| i * 8).collect::<Vec<i16>>()
+        );
+        // Unsigned bytes, not wrapped to negative values.
+        assert_eq!(parsed.samples[1][15], 248);
+    }
+
+    #[test]
+    fn parses_accelerometer_samples() {
+        let payload: Vec<u8> = [1i16, -1, 16384, -32768, 0, 2]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let parsed = Frame::parse(StreamMode::Accelerometry, &frame(0x04, 7, &payload)).unwrap();
+        assert_eq!(parsed.samples, [vec![1, -1, 16384], vec![-32768, 0, 2]]);
+    }
+
+    #[test]
+    fn rejects_short_frames() {
+        assert_eq!(
+            Frame::parse(StreamMode::Pressure, &[0x01, 0x00, 0x00]),
+            Err(FrameError::TooShort(3))
+        );
+    }
+
+    #[test]
+    fn rejects_frames_of_another_mode() {
+        // Eight accelerometer samples fill as many bytes as three pressure
+        // samples.
+        let late = frame(0x04, 3, &[0; 48]);
+        assert_eq!(
+            Frame::parse(StreamMode::Pressure, &late),
+            Err(FrameError::WrongMode {
+                tag: 0x04,
+                mode: StreamMode::Pressure
+            })
+        );
+        assert!(Frame::parse(StreamMode::Accelerometry, &late).is_ok());
+        assert_eq!(
+            Frame::parse(StreamMode::Accelerometry, &frame(0x01, 3, &[0; 48])),
+            Err(FrameError::WrongMode {
+                tag: 0x01,
+                mode: StreamMode::Accelerometry
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_length_mismatch() {
+        let mut data = frame(0x01, 0, &[0; 16]);
+        data.push(0);
+        assert_eq!(
+            Frame::parse(StreamMode::Pressure, &data),
+            Err(FrameError::LengthMismatch {
+                declared: 16,
+                actual: 17
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_partial_samples() {
+        assert_eq!(
+            Frame::parse(StreamMode::Accelerometry, &frame(0x04, 0, &[0; 8])),
+            Err(FrameError::PartialSample {
+                payload: 8,
+                sample_size: 6
+            })
+        );
+        assert_eq!(
+            Frame::parse(StreamMode::Pressure, &frame(0x01, 0, &[])),
+            Err(FrameError::PartialSample {
+                payload: 0,
+                sample_size: 16
+            })
+        );
+    }
+
+    #[test]
+    fn counts_lost_frames_across_wraps_but_not_restarts() {
+        assert_eq!(lost_frames(10, 11), 0);
+        assert_eq!(lost_frames(10, 14), 3);
+        assert_eq!(lost_frames(u16::MAX, 1), 1);
+        // A repeated frame and a counter starting over lose nothing.
+        assert_eq!(lost_frames(10, 10), 0);
+        assert_eq!(lost_frames(500, 0), 0);
+        assert_eq!(lost_frames(10, 5000), 0);
+    }
+
+    #[test]
+    fn converts_to_physical_units() {
+        assert_eq!(
+            to_physical(StreamMode::Pressure, &[0, 4, 255]),
+            [0.0, 10.0, 637.5]
+        );
+        assert_eq!(
+            to_physical(StreamMode::Accelerometry, &[8192, -32768, 0]),
+            [1.0, -4.0, 0.0]
+        );
+    }
+}
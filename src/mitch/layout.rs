@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Which foot an insole is worn on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+impl Side {
+    /// Guesses the side from a device name such as `mitch_L_042`, insoles are
+    /// usually named after the foot they belong to.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let lower = name.to_ascii_lowercase();
+        let mut parts = lower.split(['_', '-', ' ']);
+        parts.find_map(|part| match part {
+            "l" | "left" => Some(Side::Left),
+            "r" | "right" => Some(Side::Right),
+            _ => None,
+        })
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Side::Left => write!(f, "left"),
+            Side::Right => write!(f, "right"),
+        }
+    }
+}
+
+/// Anatomical region a pressure sensor sits under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Region {
+    Heel,
+    Midfoot,
+    Forefoot,
+    Toes,
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Region::Heel => write!(f, "heel"),
+            Region::Midfoot => write!(f, "midfoot"),
+            Region::Forefoot => write!(f, "forefoot"),
+            Region::Toes => write!(f, "toes"),
+        }
+    }
+}
+
+/// Insole sizes, the sensors sit at the same relative positions in each.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum InsoleSize {
+    /// EU 37 to 40
+    Small,
+    /// EU 41 to 43
+    #[default]
+    Medium,
+    /// EU 44 to 47
+    Large,
+}
+
+impl InsoleSize {
+    /// Length of the insole relative to the medium one the sensor positions
+    /// are given for.
+    pub fn scale(&self) -> f32 {
+        match self {
+            InsoleSize::Small => 0.92,
+            InsoleSize::Medium => 1.0,
+            InsoleSize::Large => 1.08,
+        }
+    }
+}
+
+impl fmt::Display for InsoleSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InsoleSize::Small => write!(f, "small"),
+            InsoleSize::Medium => write!(f, "medium"),
+            InsoleSize::Large => write!(f, "large"),
+        }
+    }
+}
+
+/// Sensing area of a single pressure sensor in square millimetres, the same
+/// in every insole size.
+pub const SENSOR_AREA_MM2: f32 = 154.0;
+
+/// A single pressure sensor of the insole.
+#[derive(Debug, Clone, Copy)]
+pub struct Sensor {
+    pub label: &'static str,
+    pub region: Region,
+    /// Millimetres towards the lateral side of the foot, from the heel's
+    /// long axis.
+    pub lateral_mm: f32,
+    /// Millimetres towards the toes, from the back of the heel.
+    pub anterior_mm: f32,
+}
+
+impl Sensor {
+    /// Position in a frame shared by both feet, x points to the wearer's
+    /// right and y towards the toes, so left insoles are mirrored.
+    pub fn position(&self, size: InsoleSize, side: Side) -> (f32, f32) {
+        let lateral = self.lateral_mm * size.scale();
+        let anterior = self.anterior_mm * size.scale();
+        match side {
+            Side::Left => (-lateral, anterior),
+            Side::Right => (lateral, anterior),
+        }
+    }
+}
+
+const fn sensor(label: &'static str, region: Region, lateral_mm: f32, anterior_mm: f32) -> Sensor {
+    Sensor {
+        label,
+        region,
+        lateral_mm,
+        anterior_mm,
+    }
+}
+
+/// The 16 sensors in the order their values appear in a pressure frame,
+/// positioned for a medium (EU 42) insole.
+pub const PRESSURE_SENSORS: [Sensor; 16] = [
+    sensor("hallux", Region::Toes, -22.0, 240.0),
+    sensor("toe_2_3", Region::Toes, 0.0, 235.0),
+    sensor("toe_4_5", Region::Toes, 22.0, 220.0),
+    sensor("mth1", Region::Forefoot, -28.0, 190.0),
+    sensor("mth2", Region::Forefoot, -10.0, 195.0),
+    sensor("mth3", Region::Forefoot, 5.0, 190.0),
+    sensor("mth4", Region::Forefoot, 20.0, 182.0),
+    sensor("mth5", Region::Forefoot, 33.0, 170.0),
+    sensor("midfoot_medial", Region::Midfoot, -15.0, 120.0),
+    sensor("midfoot_center", Region::Midfoot, 5.0, 115.0),
+    sensor("midfoot_lateral", Region::Midfoot, 28.0, 110.0),
+    sensor("heel_anterior_medial", Region::Heel, -15.0, 60.0),
+    sensor("heel_anterior_lateral", Region::Heel, 15.0, 60.0),
+    sensor("heel_medial", Region::Heel, -18.0, 35.0),
+    sensor("heel_lateral", Region::Heel, 18.0, 35.0),
+    sensor("heel_posterior", Region::Heel, 0.0, 15.0),
+];
@@ -0,0 +1,129 @@
+//! The mitch's internal memory, which it logs to while in the `SysLog` state
+//! and which is read back in the `SysReadout` state.
+//!
+//! Logs are read out as notifications on the data characteristic, laid out
+//! like streaming frames: a four byte header `[tag, length, counter_lo,
+//! counter_hi]` followed by `length` bytes of the log. The counter starts at
+//! zero and increments with every chunk.
+
+use super::{
+    SAMPLE_RATES, StreamMode,
+    frame::{FrameError, HEADER_LEN},
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A log stored in the device's memory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredLog {
+    pub index: u16,
+    /// Mode and rate the log was recorded with, unknown codes are kept out.
+    pub mode: Option<StreamMode>,
+    pub rate: Option<u16>,
+    /// Size in bytes.
+    pub size: u32,
+    /// CRC-32 of the log as computed by the device.
+    pub crc: u32,
+}
+
+impl fmt::Display for StoredLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "log {}: {} bytes", self.index, self.size)?;
+        if let Some(mode) = self.mode {
+            write!(f, ", {mode}")?;
+        }
+        if let Some(rate) = self.rate {
+            write!(f, " @ {rate} Hz")?;
+        }
+        write!(f, ", crc {:08x}", self.crc)
+    }
+}
+
+/// Parses the memory info response into the number of stored logs.
+pub fn parse_log_count(payload: &[u8]) -> Option<u16> {
+    Some(u16::from_le_bytes([*payload.first()?, *payload.get(1)?]))
+}
+
+/// Parses a log info response, laid out as `[mode, rate_code, size (u32),
+/// crc (u32)]` with little endian integers.
+pub fn parse_log_info(index: u16, payload: &[u8]) -> Option<StoredLog> {
+    let [mode, rate_code, rest @ ..] = payload else {
+        return None;
+    };
+    let size = u32::from_le_bytes(rest.get(0..4)?.try_into().ok()?);
+    let crc = u32::from_le_bytes(rest.get(4..8)?.try_into().ok()?);
+    Some(StoredLog {
+        index,
+        mode: StreamMode::from_id(*mode),
+        rate: SAMPLE_RATES
+            .iter()
+            .find(|(_, code)| code == rate_code)
+            .map(|(rate, _)| *rate),
+        size,
+        crc,
+    })
+}
+
+/// Splits a readout notification into its chunk counter and data.
+pub fn parse_chunk(data: &[u8]) -> Result<(u16, &[u8]), FrameError> {
+    if data.len() < HEADER_LEN {
+        return Err(FrameError::TooShort(data.len()));
+    }
+    let declared = data[1] as usize;
+    let chunk = &data[HEADER_LEN..];
+    if declared != chunk.len() {
+        return Err(FrameError::LengthMismatch {
+            declared,
+            actual: chunk.len(),
+        });
+    }
+    Ok((u16::from_le_bytes([data[2], data[3]]), chunk))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_log_count() {
+        assert_eq!(parse_log_count(&[0x03, 0x01]), Some(0x0103));
+        assert_eq!(parse_log_count(&[0x03, 0x01, 0xff]), Some(0x0103));
+        assert_eq!(parse_log_count(&[0x03]), None);
+    }
+
+    #[test]
+    fn parses_log_info() {
+        let mut payload = vec![0x04, 0x05];
+        payload.extend_from_slice(&1000u32.to_le_bytes());
+        payload.extend_from_slice(&0xdead_beefu32.to_le_bytes());
+        let log = parse_log_info(2, &payload).unwrap();
+        assert_eq!(log.index, 2);
+        assert_eq!(log.mode, Some(StreamMode::Accelerometry));
+        assert_eq!(log.rate, Some(100));
+        assert_eq!(log.size, 1000);
+        assert_eq!(log.crc, 0xdead_beef);
+
+        // Unknown codes are kept out, the sizes still count.
+        payload[0] = 0x7f;
+        payload[1] = 0x7f;
+        let log = parse_log_info(2, &payload).unwrap();
+        assert_eq!((log.mode, log.rate, log.size), (None, None, 1000));
+
+        assert!(parse_log_info(2, &payload[..9]).is_none());
+        assert!(parse_log_info(2, &[]).is_none());
+    }
+
+    #[test]
+    fn parses_chunk() {
+        let data = [0x00, 0x03, 0x02, 0x01, 0xaa, 0xbb, 0xcc];
+        assert_eq!(parse_chunk(&data), Ok((0x0102, &data[4..])));
+        assert_eq!(parse_chunk(&data[..3]), Err(FrameError::TooShort(3)));
+        assert_eq!(
+            parse_chunk(&data[..6]),
+            Err(FrameError::LengthMismatch {
+                declared: 3,
+                actual: 2
+            })
+        );
+    }
+}
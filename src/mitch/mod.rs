@@ -0,0 +1,303 @@
+pub mod dfu;
+pub mod frame;
+pub mod layout;
+pub mod memory;
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum MitchState {
+    SysStartup = 0x01,
+    SysIdle = 0x02,
+    SysStandby = 0x03,
+    SysLog = 0x04,
+    SysReadout = 0x05,
+    SysTx = 0xF8,
+    SysError = 0xFF,
+    BootStartup = 0xf0,
+    BootIdle = 0xf1,
+    BootDownload = 0xf2,
+}
+
+impl TryFrom<u8> for MitchState {
+    type Error = &'static str;
+
+    fn try_from(value: u8) -> std::result::Result<MitchState, &'static str> {
+        if (1_u8..=5_u8).contains(&value)
+            || value == 0xf8
+            || value == 0xff
+            || value == 0xf0
+            || value == 0xf1
+            || value == 0xf2
+        {
+            return Ok(unsafe { *(&value as *const _ as *const MitchState) });
+        }
+        Err("Unknown state")
+    }
+}
+
+pub enum Commands {
+    GetState,
+    GetPower,
+    GetFirmwareVersion,
+    GetDeviceId,
+    StartStream(StreamConfig),
+    StopStream,
+    /// Record to internal memory instead of streaming.
+    StartLog(StreamConfig),
+    /// Leave logging or readout, back to idle. The same state change as
+    /// `StopStream`, named for where it is used.
+    StopLog,
+    GetMemoryInfo,
+    GetLogInfo(u16),
+    ReadoutLog(u16),
+    EraseMemory,
+}
+
+impl Commands {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Commands::GetState => vec![130, 0],
+            Commands::StartStream(config) => {
+                vec![0x02, 0x03, 0xF8, config.mode.id(), config.rate_code()]
+            }
+            Commands::StopStream | Commands::StopLog => {
+                vec![0x02, 0x01, MitchState::SysIdle as u8]
+            }
+            Commands::GetPower => vec![87, 0],
+            Commands::GetFirmwareVersion => vec![0x8A, 0],
+            Commands::GetDeviceId => vec![0x8E, 0],
+            Commands::StartLog(config) => {
+                vec![0x02, 0x03, 0x04, config.mode.id(), config.rate_code()]
+            }
+            Commands::GetMemoryInfo => vec![0xA0, 0],
+            Commands::GetLogInfo(index) => {
+                let [lo, hi] = index.to_le_bytes();
+                vec![0xA1, 0x02, lo, hi]
+            }
+            Commands::ReadoutLog(index) => {
+                let [lo, hi] = index.to_le_bytes();
+                vec![0x22, 0x02, lo, hi]
+            }
+            Commands::EraseMemory => vec![0x20, 0x01, 0x01],
+        }
+    }
+}
+
+/// Returns the payload of a command response, `None` if the device rejected
+/// the command.
+///
+/// Responses are laid out as `[ack, length, command, error, payload..]`,
+/// with `length` counting the command and error bytes as well as the
+/// payload. Data frames count their payload only.
+pub fn response_payload(response: &[u8]) -> Option<&[u8]> {
+    if response.get(3) != Some(&0) {
+        return None;
+    }
+    response.get(4..)
+}
+
+/// Splits a firmware version response into bootloader and application
+/// version, which the device sends as two NUL terminated strings.
+pub fn parse_firmware_version(payload: &[u8]) -> (Option<String>, Option<String>) {
+    let mut parts = payload
+        .split(|b| *b == 0)
+        .map(|part| String::from_utf8_lossy(part).trim().to_string())
+        .filter(|part| !part.is_empty());
+    let bootloader = parts.next();
+    let application = parts.next();
+    (bootloader, application)
+}
+
+/// Formats the unique id the device reports as a hex string.
+pub fn parse_device_id(payload: &[u8]) -> Option<String> {
+    if payload.is_empty() {
+        return None;
+    }
+    Some(payload.iter().rev().map(|b| format!("{b:02X}")).collect())
+}
+
+/// What the device streams on its data characteristic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamMode {
+    Pressure,
+    Accelerometry,
+}
+
+/// Labels of the accelerometer channels, in frame order.
+pub const ACCELEROMETER_AXES: [&str; 3] = ["acc_x", "acc_y", "acc_z"];
+
+/// Sampling rates in Hz the firmware can stream at, with their command codes.
+const SAMPLE_RATES: [(u16, u8); 6] = [
+    (5, 0x01),
+    (10, 0x02),
+    (25, 0x03),
+    (50, 0x04),
+    (100, 0x05),
+    (200, 0x06),
+];
+
+impl StreamMode {
+    /// Identifier of the mode in the start stream command.
+    pub fn id(&self) -> u8 {
+        match self {
+            StreamMode::Pressure => 0x01,
+            StreamMode::Accelerometry => 0x04,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x01 => Some(StreamMode::Pressure),
+            0x04 => Some(StreamMode::Accelerometry),
+            _ => None,
+        }
+    }
+
+    /// Highest sampling rate the firmware supports in this mode.
+    pub fn max_rate(&self) -> u16 {
+        match self {
+            StreamMode::Pressure => 100,
+            StreamMode::Accelerometry => 200,
+        }
+    }
+
+    pub fn supported_rates(&self) -> impl Iterator<Item = u16> {
+        let max = self.max_rate();
+        SAMPLE_RATES
+            .iter()
+            .map(|(rate, _)| *rate)
+            .filter(move |rate| *rate <= max)
+    }
+
+    pub fn channel_count(&self) -> usize {
+        match self {
+            StreamMode::Pressure => layout::PRESSURE_SENSORS.len(),
+            StreamMode::Accelerometry => ACCELEROMETER_AXES.len(),
+        }
+    }
+
+    /// Channel labels in frame order.
+    pub fn channel_labels(&self) -> Vec<&'static str> {
+        match self {
+            StreamMode::Pressure => layout::PRESSURE_SENSORS
+                .iter()
+                .map(|sensor| sensor.label)
+                .collect(),
+            StreamMode::Accelerometry => ACCELEROMETER_AXES.to_vec(),
+        }
+    }
+
+    /// Bytes per sample in a data frame.
+    pub fn sample_size(&self) -> usize {
+        match self {
+            StreamMode::Pressure => layout::PRESSURE_SENSORS.len(),
+            StreamMode::Accelerometry => ACCELEROMETER_AXES.len() * 2,
+        }
+    }
+
+    /// Default LSL stream type.
+    pub fn stream_type(&self) -> &'static str {
+        match self {
+            StreamMode::Pressure => "Pressure",
+            StreamMode::Accelerometry => "Accelerometer",
+        }
+    }
+
+    /// Unit of the values once converted with [`frame::to_physical`].
+    pub fn physical_unit(&self) -> &'static str {
+        match self {
+            StreamMode::Pressure => "kPa",
+            StreamMode::Accelerometry => "g",
+        }
+    }
+}
+
+impl fmt::Display for StreamMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamMode::Pressure => write!(f, "pressure"),
+            StreamMode::Accelerometry => write!(f, "accelerometry"),
+        }
+    }
+}
+
+/// A validated combination of streaming parameters, only built through
+/// [`StreamConfig::new`] so the rate is always one the mode supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamConfig {
+    mode: StreamMode,
+    rate: u16,
+}
+
+impl StreamConfig {
+    pub fn new(mode: StreamMode, rate: u16) -> Result<Self, String> {
+        if !mode.supported_rates().any(|supported| supported == rate) {
+            let supported: Vec<_> = mode.supported_rates().map(|r| r.to_string()).collect();
+            return Err(format!(
+                "{rate} Hz is not supported in {mode} mode, choose one of {} Hz",
+                supported.join(", ")
+            ));
+        }
+        Ok(Self { mode, rate })
+    }
+
+    pub fn mode(&self) -> StreamMode {
+        self.mode
+    }
+
+    /// Sampling rate in Hz.
+    pub fn rate(&self) -> u16 {
+        self.rate
+    }
+
+    fn rate_code(&self) -> u8 {
+        SAMPLE_RATES
+            .iter()
+            .find(|(rate, _)| *rate == self.rate)
+            .map(|(_, code)| *code)
+            .expect("stream config to hold a supported rate")
+    }
+}
+
+/// How sample values are published.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Units {
+    /// The device's raw counts as int16.
+    Raw,
+    /// Physical units (kPa, g) as float32.
+    Physical,
+}
+
+impl fmt::Display for Units {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Units::Raw => write!(f, "raw"),
+            Units::Physical => write!(f, "physical"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_config_only_holds_supported_rates() {
+        assert!(StreamConfig::new(StreamMode::Pressure, 200).is_err());
+        assert!(StreamConfig::new(StreamMode::Pressure, 60).is_err());
+        let config = StreamConfig::new(StreamMode::Accelerometry, 200).unwrap();
+        assert_eq!(
+            (config.mode(), config.rate()),
+            (StreamMode::Accelerometry, 200)
+        );
+        assert_eq!(
+            Commands::StartStream(config).to_bytes(),
+            [0x02, 0x03, 0xF8, 0x04, 0x06]
+        );
+    }
+}
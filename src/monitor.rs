@@ -0,0 +1,315 @@
+//! A terminal preview of a device's samples, meant for checking that every
+//! sensor responds before a trial without firing up an LSL viewer.
+
+use crate::{
+    client,
+    mitch::{
+        StreamMode, frame,
+        layout::{InsoleSize, PRESSURE_SENSORS, Side},
+    },
+    protocol::{ClientCommand, DaemonResponse, SampleBatch, read_frame},
+};
+use anyhow::{Result, anyhow};
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
+    widgets::{
+        Block, Paragraph, RenderDirection, Sparkline,
+        canvas::{Canvas, Context},
+    },
+};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+use tokio::{sync::mpsc, time};
+
+/// Samples kept per channel, wider than any sparkline is likely to be.
+const HISTORY_LEN: usize = 512;
+
+/// Window the effective sample rate is measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Largest raw pressure value, the sensors report a single byte.
+const PRESSURE_MAX: u64 = 255;
+
+/// Raw accelerometer values are signed, they are shifted by this much so the
+/// sparklines can draw them.
+const ACCELERATION_OFFSET: i32 = 32768;
+
+/// Subscribes to `name`'s samples and draws them until the user quits.
+pub async fn run(name: String, mode: StreamMode, rate: u16, side: Option<Side>) -> Result<()> {
+    let side = side
+        .or_else(|| Side::from_name(&name))
+        .unwrap_or(Side::Right);
+    let mut stream = client::send_command(ClientCommand::Subscribe {
+        name: name.clone(),
+        mode,
+        rate,
+    })
+    .await?;
+
+    // Errors such as an unknown device arrive first, report them before
+    // taking over the terminal.
+    let first = match read_frame::<_, DaemonResponse>(&mut stream).await? {
+        Some(DaemonResponse::Samples(batch)) => batch,
+        Some(DaemonResponse::Error(e)) => return Err(anyhow!(e)),
+        Some(other) => return Err(anyhow!("unexpected response {other:?}")),
+        None => return Err(anyhow!("daemon closed the connection")),
+    };
+
+    let (batch_tx, mut batches) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok(Some(DaemonResponse::Samples(batch))) =
+            read_frame::<_, DaemonResponse>(&mut stream).await
+        {
+            if batch_tx.send(batch).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Crossterm's event reading blocks, so it gets a thread of its own.
+    let (quit_tx, mut quit) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while !quit_tx.is_closed() {
+            match event::poll(Duration::from_millis(100)) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(_) => break,
+            }
+            if let Ok(Event::Key(key)) = event::read()
+                && key.kind == KeyEventKind::Press
+                && (matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+                    || (key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL)))
+            {
+                quit_tx.send(()).ok();
+                break;
+            }
+        }
+    });
+
+    let mut monitor = Monitor::new(name, side, first.mode, first.rate);
+    monitor.push(first);
+
+    let mut terminal = ratatui::init();
+    let res = monitor.run(&mut terminal, &mut batches, &mut quit).await;
+    ratatui::restore();
+    res
+}
+
+struct Monitor {
+    name: String,
+    side: Side,
+    mode: StreamMode,
+    rate: u16,
+    /// Raw values per channel, newest last.
+    history: Vec<VecDeque<i16>>,
+    /// Arrival time and sample count of recent frames.
+    arrivals: VecDeque<(Instant, usize)>,
+    last_counter: Option<u16>,
+    received: u64,
+    lost: u64,
+    /// Set once the daemon stops sending, the last picture stays up.
+    ended: bool,
+}
+
+impl Monitor {
+    fn new(name: String, side: Side, mode: StreamMode, rate: u16) -> Self {
+        Self {
+            name,
+            side,
+            mode,
+            rate,
+            history: vec![VecDeque::with_capacity(HISTORY_LEN); mode.channel_count()],
+            arrivals: VecDeque::new(),
+            last_counter: None,
+            received: 0,
+            lost: 0,
+            ended: false,
+        }
+    }
+
+    async fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        batches: &mut mpsc::UnboundedReceiver<SampleBatch>,
+        quit: &mut mpsc::UnboundedReceiver<()>,
+    ) -> Result<()> {
+        let mut redraw = time::interval(REDRAW_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = redraw.tick() => {
+                    terminal.draw(|frame| self.draw(frame))?;
+                }
+                batch = batches.recv(), if !self.ended => match batch {
+                    Some(batch) => self.push(batch),
+                    None => self.ended = true,
+                },
+                _ = quit.recv() => return Ok(()),
+            }
+        }
+    }
+
+    fn push(&mut self, batch: SampleBatch) {
+        if let Some(last) = self.last_counter {
+            self.lost += frame::lost_frames(last, batch.counter);
+        }
+        self.last_counter = Some(batch.counter);
+        self.received += 1;
+        self.arrivals
+            .push_back((Instant::now(), batch.samples.len()));
+
+        for sample in batch.samples {
+            for (history, value) in self.history.iter_mut().zip(sample.values) {
+                if history.len() == HISTORY_LEN {
+                    history.pop_front();
+                }
+                history.push_back(value);
+            }
+        }
+    }
+
+    /// Samples per second that actually arrived over the last second.
+    fn effective_rate(&mut self) -> f64 {
+        let now = Instant::now();
+        while self
+            .arrivals
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > RATE_WINDOW)
+        {
+            self.arrivals.pop_front();
+        }
+        let samples: usize = self.arrivals.iter().map(|(_, count)| count).sum();
+        samples as f64 / RATE_WINDOW.as_secs_f64()
+    }
+
+    fn packet_loss(&self) -> f64 {
+        let expected = self.received + self.lost;
+        if expected == 0 {
+            return 0.0;
+        }
+        self.lost as f64 * 100.0 / expected as f64
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [header, body] =
+            Layout::vertical([Constraint::Length(1), Constraint::Min(0)]).areas(frame.area());
+
+        let effective_rate = self.effective_rate();
+        let mut status = vec![
+            Span::from(format!(" {} ", self.name)).bold(),
+            Span::from(format!(
+                "{} @ {} Hz | effective {:.1} Hz | lost {} frames ({:.1}%)",
+                self.mode,
+                self.rate,
+                effective_rate,
+                self.lost,
+                self.packet_loss()
+            )),
+        ];
+        if self.ended {
+            status.push(Span::from(" | stream ended").red());
+        }
+        status.push(Span::from(" | q to quit").dark_gray());
+        frame.render_widget(Line::from(status), header);
+
+        match self.mode {
+            StreamMode::Pressure => {
+                let [heatmap, sparklines] =
+                    Layout::horizontal([Constraint::Length(34), Constraint::Min(0)]).areas(body);
+                self.draw_heatmap(frame, heatmap);
+                self.draw_sparklines(frame, sparklines);
+            }
+            StreamMode::Accelerometry => self.draw_sparklines(frame, body),
+        }
+    }
+
+    /// Draws every sensor at its place on the insole, coloured by its
+    /// latest value.
+    fn draw_heatmap(&self, frame: &mut Frame, area: Rect) {
+        let latest: Vec<i16> = self
+            .history
+            .iter()
+            .map(|history| history.back().copied().unwrap_or_default())
+            .collect();
+        let side = self.side;
+        let canvas = Canvas::default()
+            .block(Block::bordered().title(format!(" {side} insole ")))
+            .x_bounds([-50.0, 50.0])
+            .y_bounds([0.0, 260.0])
+            .paint(move |ctx: &mut Context| {
+                for (sensor, value) in PRESSURE_SENSORS.iter().zip(&latest) {
+                    let (x, y) = sensor.position(InsoleSize::Medium, side);
+                    ctx.print(
+                        x as f64,
+                        y as f64,
+                        Span::styled(format!("{value:>3}"), Style::new().bg(heat(*value))),
+                    );
+                }
+            });
+        frame.render_widget(canvas, area);
+    }
+
+    fn draw_sparklines(&self, frame: &mut Frame, area: Rect) {
+        let labels = self.mode.channel_labels();
+        let block = Block::bordered().title(" channels ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        let rows = Layout::vertical(vec![Constraint::Length(1); labels.len()]).split(inner);
+        for ((row, label), history) in rows.iter().zip(labels).zip(&self.history) {
+            let [label_area, value_area, spark_area] = Layout::horizontal([
+                Constraint::Length(22),
+                Constraint::Length(7),
+                Constraint::Min(0),
+            ])
+            .areas(*row);
+            let latest = history.back().copied().unwrap_or_default();
+            frame.render_widget(Paragraph::new(label), label_area);
+            frame.render_widget(Paragraph::new(format!("{latest:>6}")), value_area);
+
+            let (data, max): (Vec<u64>, u64) = match self.mode {
+                StreamMode::Pressure => (
+                    history.iter().rev().map(|v| (*v).max(0) as u64).collect(),
+                    PRESSURE_MAX,
+                ),
+                StreamMode::Accelerometry => (
+                    history
+                        .iter()
+                        .rev()
+                        .map(|v| (*v as i32 + ACCELERATION_OFFSET) as u64)
+                        .collect(),
+                    u16::MAX as u64,
+                ),
+            };
+            frame.render_widget(
+                Sparkline::default()
+                    .data(&data)
+                    .max(max)
+                    .direction(RenderDirection::RightToLeft)
+                    .style(Style::new().cyan()),
+                spark_area,
+            );
+        }
+    }
+}
+
+/// Maps a raw pressure value to a colour from blue over green to red.
+fn heat(value: i16) -> Color {
+    let level = value.clamp(0, PRESSURE_MAX as i16) as f32 / PRESSURE_MAX as f32;
+    let (r, g, b) = if level < 0.5 {
+        let t = level * 2.0;
+        (0.0, t, 1.0 - t)
+    } else {
+        let t = (level - 0.5) * 2.0;
+        (t, 1.0 - t, 0.0)
+    };
+    Color::Rgb((r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8)
+}
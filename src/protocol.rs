@@ -1,15 +1,221 @@
-use serde::{Deserialize, Serialize};
+use crate::mitch::{
+    StreamMode, Units, frame,
+    layout::{InsoleSize, Region, Side},
+    memory::StoredLog,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[cfg(unix)]
 pub const IPC_SOCKET_PATH: &str = "/tmp/mitch.sock";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientCommand {
-    Scan { timeout_ms: u64 },
+    Scan {
+        timeout_ms: u64,
+    },
     Status,
-    Connect { name: String },
-    Disconnect { name: String },
-    Record { name: String },
+    Connect {
+        name: String,
+        max_restarts: u32,
+        side: Option<Side>,
+    },
+    Disconnect {
+        name: String,
+    },
+    Record {
+        name: String,
+        options: RecordOptions,
+    },
+    /// Follow daemon events until the client disconnects.
+    Events,
+    /// Follow a device's decoded samples until the client disconnects. The
+    /// device is started with `mode` and `rate` if it is not streaming yet.
+    Subscribe {
+        name: String,
+        mode: StreamMode,
+        rate: u16,
+    },
+    /// Annotate the recordings with `text`, timestamped on arrival.
+    Marker {
+        text: String,
+    },
+    StopRecording {
+        name: String,
+    },
+    ListSessions,
+    ShowSession {
+        name: String,
+    },
+    /// Work with the logs in a device's internal memory.
+    DeviceLog {
+        name: String,
+        action: LogAction,
+    },
+    /// Flash the firmware image at `image` and reboot the device into it.
+    FirmwareUpdate {
+        name: String,
+        image: PathBuf,
+    },
+    /// Average the unloaded device's pressure over `duration` and subtract
+    /// it from everything it streams from then on.
+    Tare {
+        name: String,
+        duration: Duration,
+    },
+    /// Save gain curves fitted against known loads, one per pressure sensor,
+    /// `None` for sensors that keep the nominal gain.
+    SetGains {
+        name: String,
+        gains: Vec<Option<GainCurve>>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LogAction {
+    Start {
+        mode: StreamMode,
+        rate: u16,
+    },
+    Stop,
+    List,
+    /// Read log `index` into `output`, which must not exist.
+    Readout {
+        index: u16,
+        output: PathBuf,
+    },
+    Erase,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, clap::Args)]
+pub struct RecordOptions {
+    /// What the device should stream
+    #[clap(long, value_enum, default_value_t = StreamMode::Pressure)]
+    pub mode: StreamMode,
+    /// Sampling rate in Hz, the LSL stream's nominal rate follows it
+    #[clap(long, default_value_t = 50)]
+    pub rate: u16,
+    /// Publish raw counts or physical units
+    #[clap(long, value_enum, default_value_t = Units::Raw)]
+    pub units: Units,
+    /// Name of the LSL stream, defaults to the device name
+    #[clap(long)]
+    pub stream_name: Option<String>,
+    /// Type of the LSL stream, defaults to one matching the mode
+    #[clap(long)]
+    pub stream_type: Option<String>,
+    /// Start even if the battery is below the daemon's minimum charge
+    #[clap(long)]
+    pub force: bool,
+    /// Also write samples and markers to this CSV file, which must not exist.
+    /// Defaults to a file in the session's directory when recording a session
+    #[clap(long)]
+    pub output: Option<PathBuf>,
+    /// Format of the output file, by default Parquet for a `.parquet` file
+    /// and CSV otherwise
+    #[clap(long, value_enum)]
+    pub output_format: Option<OutputFormat>,
+    /// Also write every notification and command exchanged with the device
+    /// to this file, which must not exist, for debugging the firmware. Log
+    /// readouts are not captured, they cannot run while recording
+    #[clap(long)]
+    pub capture: Option<PathBuf>,
+    /// Record as part of this session, logged in the daemon's data directory
+    #[clap(long)]
+    pub session: Option<String>,
+    /// Stop recording on its own after this long, e.g. 120s or 5m
+    #[clap(long, value_parser = humantime::parse_duration)]
+    pub duration: Option<Duration>,
+    /// Metadata stored in the session log, may be given multiple times
+    #[clap(long = "meta", value_name = "KEY=VALUE", value_parser = parse_meta)]
+    pub meta: Vec<(String, String)>,
+    /// Size of the insole, sensor positions scale with it
+    #[clap(long, value_enum, default_value_t)]
+    pub insole_size: InsoleSize,
+    /// Also publish total force, center of pressure and regional forces as a
+    /// second LSL stream, needs the pressure mode and a known side
+    #[clap(long)]
+    pub derived: bool,
+    /// Do not publish the samples on LSL, e.g. when only sending OSC
+    #[clap(long)]
+    pub no_lsl: bool,
+    #[clap(flatten)]
+    pub osc: OscOptions,
+    #[clap(flatten)]
+    pub gait: GaitOptions,
+}
+
+/// What the daemon writes a recording's samples and markers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// One row per sample or marker, readable anywhere
+    Csv,
+    /// Columnar, for analysis with pandas, polars or arrow
+    Parquet,
+}
+
+impl OutputFormat {
+    /// The format a file named `path` is expected to have.
+    pub fn of_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("parquet") => OutputFormat::Parquet,
+            _ => OutputFormat::Csv,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Parquet => "parquet",
+        }
+    }
+}
+
+/// Sending the samples as OSC messages over UDP.
+#[derive(Debug, Clone, Serialize, Deserialize, clap::Args)]
+pub struct OscOptions {
+    /// Send every sample as OSC to this HOST:PORT
+    #[clap(long, value_name = "HOST:PORT")]
+    pub osc: Option<String>,
+    /// Address the messages go to, `/raw` and `/derived` are appended.
+    /// `{name}` and `{side}` are replaced with the device's
+    #[clap(long, default_value = "/mitch/{name}")]
+    pub osc_address: String,
+}
+
+/// Real-time detection of heel strikes and toe offs while recording pressure.
+#[derive(Debug, Clone, Serialize, Deserialize, clap::Args)]
+pub struct GaitOptions {
+    /// Publish gait events on the `mitch_gait` LSL marker stream and into the
+    /// output file
+    #[clap(long)]
+    pub gait: bool,
+    /// Summed pressure in kPa above which the foot is on the ground
+    #[clap(long, default_value_t = 200.0)]
+    pub gait_contact: f32,
+    /// Summed pressure in kPa below which the foot has left the ground, must
+    /// be below the contact threshold
+    #[clap(long, default_value_t = 100.0)]
+    pub gait_release: f32,
+    /// Only sum the sensors under this region, defaults to the whole foot
+    #[clap(long, value_enum)]
+    pub gait_region: Option<Region>,
+}
+
+fn parse_meta(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{s}`"))?;
+    Ok((key.trim().to_string(), value.trim().to_string()))
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -17,11 +223,490 @@ pub enum DaemonResponse {
     Ok,
     Devices(Vec<String>),
     Status(Vec<DeviceStatus>),
+    Event(DaemonEvent),
+    Samples(SampleBatch),
+    Sessions(Vec<SessionLog>),
+    Session(SessionLog),
+    Logs(Vec<StoredLog>),
+    Calibration(Calibration),
     Error(String),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The decoded samples of one data frame.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SampleBatch {
+    /// Frame counter, gaps mean frames were lost on the way.
+    pub counter: u16,
+    pub mode: StreamMode,
+    /// Sampling rate in Hz the device was configured to.
+    pub rate: u16,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sample {
+    /// LSL local clock time in seconds.
+    pub timestamp: f64,
+    /// Raw values, one per channel.
+    pub values: Vec<i16>,
+}
+
+/// What the daemon logs about a session, saved as JSON next to its data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionLog {
+    pub name: String,
+    /// Wall clock times in RFC 3339.
+    pub started_at: String,
+    pub stopped_at: Option<String>,
+    pub meta: BTreeMap<String, String>,
+    pub devices: Vec<SessionDevice>,
+}
+
+/// One device's recording within a session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionDevice {
+    pub name: String,
+    pub side: Option<Side>,
+    pub identity: DeviceIdentity,
+    pub mode: StreamMode,
+    pub rate: u16,
+    pub units: Units,
+    pub duration: Option<Duration>,
+    pub file: Option<PathBuf>,
+    pub started_at: String,
+    pub stopped_at: Option<String>,
+    /// LSL clock at start and stop, to line the log up with the samples.
+    pub lsl_start: f64,
+    pub lsl_stop: Option<f64>,
+    pub stop_reason: Option<String>,
+    pub reconnects: u32,
+    pub frames: u64,
+    /// Frames missing according to the device's frame counter.
+    pub lost_frames: u64,
+    /// Clock estimate when the recording stopped.
+    pub clock: Option<ClockEstimate>,
+    /// Applied to the samples, so the raw values can be recovered.
+    pub calibration: Option<Calibration>,
+}
+
+impl SessionDevice {
+    pub fn packet_loss(&self) -> f64 {
+        let expected = self.frames + self.lost_frames;
+        if expected == 0 {
+            return 0.0;
+        }
+        self.lost_frames as f64 * 100.0 / expected as f64
+    }
+}
+
+impl SessionLog {
+    /// A single line for listings.
+    pub fn summary(&self) -> String {
+        let names: Vec<_> = self.devices.iter().map(|d| d.name.as_str()).collect();
+        format!(
+            "{}: {} until {}, {}",
+            self.name,
+            self.started_at,
+            self.stopped_at.as_deref().unwrap_or("now"),
+            names.join(", ")
+        )
+    }
+}
+
+impl fmt::Display for SessionLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.summary())?;
+        for (key, value) in &self.meta {
+            writeln!(f, "  {key} = {value}")?;
+        }
+        for device in &self.devices {
+            writeln!(
+                f,
+                "  {} ({} @ {} Hz, {}): {} until {}, {}",
+                device.name,
+                device.mode,
+                device.rate,
+                device.units,
+                device.started_at,
+                device.stopped_at.as_deref().unwrap_or("now"),
+                device.stop_reason.as_deref().unwrap_or("recording"),
+            )?;
+            writeln!(
+                f,
+                "    {} frames, {} lost ({:.2}%), {} reconnects",
+                device.frames,
+                device.lost_frames,
+                device.packet_loss(),
+                device.reconnects
+            )?;
+            if let Some(clock) = &device.clock {
+                writeln!(f, "    {clock}")?;
+            }
+            if let Some(calibration) = &device.calibration {
+                writeln!(f, "    {calibration}")?;
+            }
+            if let Some(file) = &device.file {
+                writeln!(f, "    {}", file.display())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Things happening in the daemon that clients may want to react to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DaemonEvent {
+    /// A device's charge dropped below one of the configured thresholds.
+    LowBattery {
+        name: String,
+        charge: u8,
+        threshold: u8,
+    },
+    /// Part of a log was read out of a device's memory.
+    ReadoutProgress {
+        name: String,
+        index: u16,
+        received: u32,
+        total: u32,
+    },
+    /// Part of a firmware image was acknowledged by a device's bootloader.
+    FirmwareProgress { name: String, sent: u32, total: u32 },
+}
+
+impl DaemonEvent {
+    /// Whether this reports the progress of a long running operation on
+    /// device `name`.
+    pub fn is_progress_of(&self, name: &str) -> bool {
+        match self {
+            DaemonEvent::ReadoutProgress { name: device, .. }
+            | DaemonEvent::FirmwareProgress { name: device, .. } => device == name,
+            DaemonEvent::LowBattery { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for DaemonEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DaemonEvent::LowBattery {
+                name,
+                charge,
+                threshold,
+            } => write!(f, "{name}: battery at {charge}% (below {threshold}%)"),
+            DaemonEvent::ReadoutProgress {
+                name,
+                index,
+                received,
+                total,
+            } => write!(
+                f,
+                "{name}: log {index} {:.0}% ({received}/{total} bytes)",
+                *received as f64 * 100.0 / (*total).max(1) as f64
+            ),
+            DaemonEvent::FirmwareProgress { name, sent, total } => write!(
+                f,
+                "{name}: firmware {:.0}% ({sent}/{total} bytes)",
+                *sent as f64 * 100.0 / (*total).max(1) as f64
+            ),
+        }
+    }
+}
+
+/// How the daemon was able to reach a device when answering a status request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceHealth {
+    /// The actor answered with fresh values.
+    Ok,
+    /// The actor did not answer in time, values are the last known ones.
+    Timeout,
+    /// The device lost its connection and the actor is trying to reconnect.
+    Reconnecting,
+    /// The actor could not produce a status.
+    Error(String),
+}
+
+impl fmt::Display for DeviceHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceHealth::Ok => write!(f, "ok"),
+            DeviceHealth::Timeout => write!(f, "timeout"),
+            DeviceHealth::Reconnecting => write!(f, "reconnecting"),
+            DeviceHealth::Error(e) => write!(f, "error: {e}"),
+        }
+    }
+}
+
+/// What a device reports about itself, read once after connecting.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceIdentity {
+    pub mac: String,
+    pub serial: Option<String>,
+    pub firmware_version: Option<String>,
+    pub bootloader_version: Option<String>,
+    pub hardware_revision: Option<String>,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+}
+
+impl fmt::Display for DeviceIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = || "unknown".to_string();
+        write!(
+            f,
+            "mac {}, serial {}, firmware {}, bootloader {}, hardware {}",
+            self.mac,
+            self.serial.clone().unwrap_or_else(unknown),
+            self.firmware_version.clone().unwrap_or_else(unknown),
+            self.bootloader_version.clone().unwrap_or_else(unknown),
+            self.hardware_revision.clone().unwrap_or_else(unknown),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceStatus {
     pub name: String,
+    pub side: Option<Side>,
+    pub identity: DeviceIdentity,
+    pub health: DeviceHealth,
+    pub recording: bool,
+    /// The device records to its internal memory.
+    pub logging: bool,
     pub battery_charge: Option<u8>,
+    /// Change of the charge in percent per hour over the recent history.
+    pub battery_trend: Option<f32>,
+    /// How the device's clock relates to the LSL clock while streaming.
+    pub clock: Option<ClockEstimate>,
+}
+
+/// The fitted relation between a device's sample clock and the LSL clock.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ClockEstimate {
+    /// LSL time in seconds at which the device's stream started, according
+    /// to the device's clock.
+    pub offset: f64,
+    /// How much faster the device's clock runs than the LSL clock.
+    pub drift_ppm: f64,
+    /// Spread of the arrival times around the fit, i.e. the BLE jitter the
+    /// timestamps no longer carry.
+    pub jitter_ms: f64,
+}
+
+impl fmt::Display for ClockEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "clock offset {:.3}s, drift {:+.1}ppm, jitter {:.1}ms",
+            self.offset, self.drift_ppm, self.jitter_ms
+        )
+    }
+}
+
+/// What the daemon knows about a device's sensors beyond the datasheet.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    /// Raw counts of every pressure sensor while unloaded, subtracted from
+    /// the samples.
+    pub offsets: Option<Vec<i16>>,
+    pub tared_at: Option<String>,
+    /// Converts tared counts of every pressure sensor to kPa in place of
+    /// the nominal gain.
+    pub gains: Option<Vec<Option<GainCurve>>>,
+    pub calibrated_at: Option<String>,
+}
+
+/// Pressure in kPa as a function of tared counts, fitted against known
+/// loads.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GainCurve {
+    pub linear: f32,
+    pub quadratic: f32,
+}
+
+impl GainCurve {
+    pub fn kpa(&self, counts: i16) -> f32 {
+        let c = counts as f32;
+        // Mirrored for noise below zero so the curve stays monotonic.
+        self.linear * c + self.quadratic * c * c.abs()
+    }
+}
+
+impl Calibration {
+    /// Subtracts the offsets from a raw pressure sample.
+    pub fn apply(&self, values: &mut [i16]) {
+        if let Some(offsets) = &self.offsets {
+            for (value, offset) in values.iter_mut().zip(offsets) {
+                *value = value.saturating_sub(*offset);
+            }
+        }
+    }
+
+    /// Converts a tared sample to physical units, using the gain curves for
+    /// the pressure sensors that have one.
+    pub fn to_physical(&self, mode: StreamMode, values: &[i16]) -> Vec<f32> {
+        let mut physical = frame::to_physical(mode, values);
+        if mode == StreamMode::Pressure
+            && let Some(gains) = &self.gains
+        {
+            for ((value, counts), gain) in physical.iter_mut().zip(values).zip(gains) {
+                if let Some(gain) = gain {
+                    *value = gain.kpa(*counts);
+                }
+            }
+        }
+        physical
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_none() && self.gains.is_none()
+    }
+}
+
+impl fmt::Display for Calibration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.offsets, &self.tared_at) {
+            (Some(offsets), Some(at)) => write!(f, "tared at {at}, offsets {offsets:?}")?,
+            (Some(offsets), None) => write!(f, "offsets {offsets:?}")?,
+            (None, _) => write!(f, "not tared")?,
+        }
+        if let Some(gains) = &self.gains {
+            let fitted = gains.iter().flatten().count();
+            write!(f, ", {fitted} of {} sensors calibrated", gains.len())?;
+            if let Some(at) = &self.calibrated_at {
+                write!(f, " at {at}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DeviceStatus {
+    pub fn new(name: &str, side: Option<Side>, mac: String) -> Self {
+        Self {
+            name: name.to_string(),
+            side,
+            identity: DeviceIdentity {
+                mac,
+                ..Default::default()
+            },
+            health: DeviceHealth::Ok,
+            recording: false,
+            logging: false,
+            battery_charge: None,
+            battery_trend: None,
+            clock: None,
+        }
+    }
+}
+
+impl fmt::Display for DeviceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.health)?;
+        if let Some(side) = self.side {
+            write!(f, ", {side}")?;
+        }
+        if self.recording {
+            write!(f, ", recording")?;
+        }
+        if self.logging {
+            write!(f, ", logging to memory")?;
+        }
+        match self.battery_charge {
+            Some(charge) => write!(f, ", battery {charge}%")?,
+            None => write!(f, ", battery unknown")?,
+        }
+        if let Some(trend) = self.battery_trend {
+            write!(f, " ({trend:+.1}%/h)")?;
+        }
+        if let Some(clock) = &self.clock {
+            write!(f, "\n  {clock}")?;
+        }
+        write!(f, "\n  {}", self.identity)
+    }
+}
+
+/// Writes a length prefixed JSON frame.
+pub async fn write_frame<W, T>(stream: &mut W, value: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let json = serde_json::to_vec(value)?;
+    let len = json.len() as u64;
+    stream.write_all(&len.to_le_bytes()).await?;
+    stream.write_all(&json).await?;
+    Ok(())
+}
+
+/// Reads a length prefixed JSON frame, returns `None` once the other side
+/// closed the stream.
+pub async fn read_frame<R, T>(stream: &mut R) -> Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut len_buf = [0u8; 8];
+    match stream.read_exact(&mut len_buf).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u64::from_le_bytes(len_buf) as usize;
+    let mut json = vec![0; len];
+    stream.read_exact(&mut json).await?;
+    Ok(Some(serde_json::from_slice(&json)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVE: GainCurve = GainCurve {
+        linear: 2.0,
+        quadratic: 0.01,
+    };
+
+    #[test]
+    fn gain_curve_is_mirrored_below_zero() {
+        assert_eq!(CURVE.kpa(0), 0.0);
+        assert_eq!(CURVE.kpa(100), 300.0);
+        assert_eq!(CURVE.kpa(-100), -300.0);
+        assert!(CURVE.kpa(-1) < CURVE.kpa(0));
+    }
+
+    #[test]
+    fn to_physical_uses_gains_where_fitted() {
+        let mut gains = vec![None; StreamMode::Pressure.channel_count()];
+        gains[1] = Some(CURVE);
+        let calibration = Calibration {
+            gains: Some(gains),
+            ..Default::default()
+        };
+        let mut values = vec![0i16; StreamMode::Pressure.channel_count()];
+        values[0] = 10;
+        values[1] = 100;
+        let physical = calibration.to_physical(StreamMode::Pressure, &values);
+        assert_eq!(physical[0], 10.0 * frame::PRESSURE_KPA_PER_COUNT);
+        assert_eq!(physical[1], 300.0);
+        assert_eq!(physical[2], 0.0);
+
+        // Gains only concern the pressure sensors.
+        let accel = [100, -200, 300];
+        assert_eq!(
+            calibration.to_physical(StreamMode::Accelerometry, &accel),
+            frame::to_physical(StreamMode::Accelerometry, &accel)
+        );
+    }
+
+    #[test]
+    fn apply_subtracts_offsets() {
+        let calibration = Calibration {
+            offsets: Some(vec![10, -5, 0]),
+            ..Default::default()
+        };
+        let mut values = [15, i16::MAX, i16::MIN];
+        calibration.apply(&mut values);
+        assert_eq!(values, [5, i16::MAX, i16::MIN]);
+    }
 }
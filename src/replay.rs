@@ -0,0 +1,517 @@
+//! Republishes a recording written by the daemon as if the device were
+//! streaming, for developing analyses without sensors at hand. Captures of
+//! the raw traffic go through the same decoding as in the actor, which
+//! makes decoding bugs reproducible.
+
+use crate::{
+    daemon::{
+        Marker,
+        capture::{self, CaptureKind},
+        decode::Decoder,
+        outlet::{self, MarkerOutlet, Outlet, StreamMeta},
+    },
+    mitch::{
+        StreamConfig, StreamMode, Units,
+        layout::{InsoleSize, Side},
+    },
+    protocol::{DeviceIdentity, SessionDevice, SessionLog},
+    xdf::{self, Format, Values, XdfStream},
+};
+use anyhow::{Context, Result, anyhow};
+use lsl::ExPushable as _;
+use std::{fs, path::Path, time::Duration};
+use tracing::{info, warn};
+
+/// Gait events share the marker column with everything else, these go to
+/// their own stream like the detector's.
+const GAIT_PREFIXES: [&str; 2] = ["HS_", "TO_"];
+
+/// A row of a recording, XDF files carry no frame counters.
+pub enum Row {
+    Sample {
+        timestamp: f64,
+        counter: Option<u16>,
+        values: Vec<f32>,
+    },
+    Marker(Marker),
+}
+
+/// Replays `file` at `speed` times real time, over and over if `looping`.
+/// `stream` picks the device of an XDF file holding several.
+pub async fn run(file: &Path, stream: Option<&str>, speed: f64, looping: bool) -> Result<()> {
+    if !(speed > 0.0 && speed.is_finite()) {
+        return Err(anyhow!("--speed must be positive"));
+    }
+    if capture::is_capture(file) {
+        return replay_capture(file, speed, looping).await;
+    }
+    let (meta, rows) = if is_xdf(file) {
+        read_xdf(file, stream)?
+    } else {
+        let (mode, rows) = read_csv(file)?;
+        let meta = match session_entry(file) {
+            Some((_, device)) => {
+                info!("Using metadata of {} from its session log", device.name);
+                meta_from_session(device)?
+            }
+            None => {
+                warn!("No session log mentions {}, guessing", file.display());
+                guess_meta(file, mode, &rows)?
+            }
+        };
+        (meta, rows)
+    };
+    let Some(first) = rows.first().map(Row::timestamp) else {
+        return Err(anyhow!("{} holds no samples", file.display()));
+    };
+    let last = rows.last().map(Row::timestamp).unwrap_or(first);
+
+    let mode = meta.stream.mode();
+    let units = meta.units;
+    let rate = meta.stream.rate();
+    info!(
+        "Replaying {} as {} ({} @ {} Hz, {}) at {}x",
+        file.display(),
+        meta.name,
+        mode,
+        rate,
+        units,
+        speed
+    );
+    let outlet = Outlet::new(meta)?;
+    let markers = MarkerOutlet::new("mitch_markers")?;
+    let gait = MarkerOutlet::new("mitch_gait")?;
+
+    loop {
+        // Recorded times map onto the LSL clock from now on.
+        let start = lsl::local_clock();
+        for row in &rows {
+            let timestamp = start + (row.timestamp() - first) / speed;
+            let ahead = timestamp - lsl::local_clock();
+            if ahead > 0.0 {
+                tokio::time::sleep(Duration::from_secs_f64(ahead)).await;
+            }
+            match row {
+                Row::Sample { values, .. } => {
+                    let res = match units {
+                        Units::Raw => {
+                            let raw: Vec<i16> = values.iter().map(|v| *v as i16).collect();
+                            outlet.outlet.push_sample_ex(&raw, timestamp, false)
+                        }
+                        Units::Physical => outlet.outlet.push_sample_ex(values, timestamp, false),
+                    };
+                    if let Err(e) = res {
+                        warn!("Failed to push sample: {:?}", e);
+                    }
+                }
+                Row::Marker(marker) => {
+                    let marker = Marker {
+                        timestamp,
+                        text: marker.text.clone(),
+                    };
+                    let outlet = if GAIT_PREFIXES.iter().any(|p| marker.text.starts_with(p)) {
+                        &gait
+                    } else {
+                        &markers
+                    };
+                    if let Err(e) = outlet.push(&marker) {
+                        warn!("{:#}", e);
+                    }
+                }
+            }
+        }
+        if !looping {
+            return Ok(());
+        }
+        // Leave a sample period before starting over, so timestamps keep
+        // increasing.
+        let gap = 1.0 / rate as f64 / speed;
+        tokio::time::sleep(Duration::from_secs_f64(gap)).await;
+        info!("Looping after {:.1}s of recording", last - first);
+    }
+}
+
+/// Feeds the notifications of a capture through the actor's decoding and
+/// publishes the raw samples, logging the commands exchanged on the way.
+async fn replay_capture(file: &Path, speed: f64, looping: bool) -> Result<()> {
+    let records = capture::read_capture(file)?;
+    let stream = records
+        .iter()
+        .find(|record| record.kind == CaptureKind::Stream)
+        .map(|record| stream_config(&record.data))
+        .transpose()?
+        .ok_or_else(|| anyhow!("{} never starts a stream", file.display()))?;
+    let first = records
+        .first()
+        .map(|record| record.timestamp)
+        .unwrap_or_default();
+    let meta = file_meta(file, stream, Units::Raw);
+    info!(
+        "Replaying capture {} as {} ({} @ {} Hz) at {}x",
+        file.display(),
+        meta.name,
+        stream.mode(),
+        stream.rate(),
+        speed
+    );
+    let outlet = Outlet::new(meta)?;
+
+    loop {
+        let start = lsl::local_clock();
+        let replayed = |t: f64| start + (t - first) / speed;
+        let mut decoder = Decoder::new(stream);
+        for record in &records {
+            let ahead = replayed(record.timestamp) - lsl::local_clock();
+            if ahead > 0.0 {
+                tokio::time::sleep(Duration::from_secs_f64(ahead)).await;
+            }
+            match record.kind {
+                CaptureKind::Stream => {
+                    if stream_config(&record.data)? != stream {
+                        return Err(anyhow!("the capture switches to another stream"));
+                    }
+                    decoder = Decoder::new(stream);
+                }
+                CaptureKind::Write => info!("Command {:02x?}", record.data),
+                CaptureKind::Read => info!("Response {:02x?}", record.data),
+                CaptureKind::Notification => {
+                    // Decoded on the capture's own clock, like it was live.
+                    let samples = match decoder.decode(&record.data, record.timestamp) {
+                        Ok((_, samples)) => samples,
+                        Err(e) => {
+                            warn!("Dropping frame {:02x?}: {}", record.data, e);
+                            continue;
+                        }
+                    };
+                    for sample in samples {
+                        let timestamp = replayed(sample.timestamp);
+                        if let Err(e) =
+                            outlet
+                                .outlet
+                                .push_sample_ex(&sample.values, timestamp, false)
+                        {
+                            warn!("Failed to push sample: {:?}", e);
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(clock) = decoder.clock() {
+            info!("{}", clock);
+        }
+        if !looping {
+            return Ok(());
+        }
+        let gap = 1.0 / stream.rate() as f64 / speed;
+        tokio::time::sleep(Duration::from_secs_f64(gap)).await;
+    }
+}
+
+/// Reads a CSV file written by the daemon's sink.
+pub fn read_csv(file: &Path) -> Result<(StreamMode, Vec<Row>)> {
+    let text =
+        fs::read_to_string(file).with_context(|| format!("failed to read {}", file.display()))?;
+    let mut lines = text.lines();
+    let header = lines
+        .next()
+        .ok_or_else(|| anyhow!("{} is empty", file.display()))?;
+    let mode = mode_from_header(header)?;
+    let rows = lines
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| parse_row(line, mode).with_context(|| format!("line {}", i + 2)))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((mode, rows))
+}
+
+pub fn is_xdf(file: &Path) -> bool {
+    file.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("xdf"))
+}
+
+/// Reads the samples of a device from an XDF file, e.g. recorded with
+/// LabRecorder, with the markers of every marker stream in it. `stream`
+/// names the device when the file holds several.
+pub fn read_xdf(file: &Path, stream: Option<&str>) -> Result<(StreamMeta, Vec<Row>)> {
+    let streams = xdf::read(file)?;
+    let devices: Vec<(&XdfStream, StreamMode)> = streams
+        .iter()
+        .filter_map(|s| xdf_mode(s).map(|mode| (s, mode)))
+        .collect();
+    let names = || {
+        devices
+            .iter()
+            .map(|(s, _)| s.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let (device, mode) = match (stream, devices.as_slice()) {
+        (Some(name), _) => *devices
+            .iter()
+            .find(|(s, _)| s.name == name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} holds no stream {name}, only {}",
+                    file.display(),
+                    names()
+                )
+            })?,
+        (None, [device]) => *device,
+        (None, []) => return Err(anyhow!("{} holds no mitch stream", file.display())),
+        (None, _) => {
+            return Err(anyhow!(
+                "{} holds several devices ({}), pick one with --stream",
+                file.display(),
+                names()
+            ));
+        }
+    };
+
+    let mut rows: Vec<Row> = device
+        .samples
+        .iter()
+        .filter_map(|(timestamp, values)| match values {
+            Values::Numeric(values) => Some(Row::Sample {
+                timestamp: *timestamp,
+                counter: None,
+                values: values.iter().map(|v| *v as f32).collect(),
+            }),
+            Values::Strings(_) => None,
+        })
+        .collect();
+    for (timestamp, values) in streams.iter().flat_map(|s| &s.samples) {
+        if let Values::Strings(text) = values {
+            rows.push(Row::Marker(Marker {
+                timestamp: *timestamp,
+                text: text.first().cloned().unwrap_or_default(),
+            }));
+        }
+    }
+    rows.sort_by(|a, b| a.timestamp().total_cmp(&b.timestamp()));
+    Ok((xdf_meta(device, mode)?, rows))
+}
+
+/// The mode of a stream published by the daemon, or any stream with the
+/// type and channels of one.
+fn xdf_mode(stream: &XdfStream) -> Option<StreamMode> {
+    if stream.format == Format::String {
+        return None;
+    }
+    let mode = match stream.value("mode") {
+        Some(mode) => clap::ValueEnum::from_str(&mode, true).ok()?,
+        None => [StreamMode::Pressure, StreamMode::Accelerometry]
+            .into_iter()
+            .find(|mode| stream.value("type").as_deref() == Some(mode.stream_type()))?,
+    };
+    (stream.channel_count == mode.channel_count()).then_some(mode)
+}
+
+/// Metadata from the description the daemon gives its streams.
+fn xdf_meta(stream: &XdfStream, mode: StreamMode) -> Result<StreamMeta> {
+    let rate = match stream.value("sampling_rate").and_then(|r| r.parse().ok()) {
+        Some(rate) => rate,
+        None => closest_rate(mode, stream.nominal_srate)?,
+    };
+    let units = match stream.format {
+        Format::Int16 => Units::Raw,
+        _ => Units::Physical,
+    };
+    let identity = DeviceIdentity {
+        mac: stream
+            .value("mac")
+            .unwrap_or_else(|| format!("replay_{}", stream.name)),
+        serial: stream.value("serial_number"),
+        firmware_version: stream.value("firmware_version"),
+        bootloader_version: stream.value("bootloader_version"),
+        hardware_revision: stream.value("hardware_revision"),
+        manufacturer: stream.value("manufacturer"),
+        model: stream.value("model"),
+    };
+    let side = match stream.value("side").as_deref() {
+        Some("left") => Some(Side::Left),
+        Some("right") => Some(Side::Right),
+        _ => Side::from_name(&stream.name),
+    };
+    Ok(StreamMeta {
+        stream_type: mode.stream_type().to_string(),
+        source_id: stream
+            .value("source_id")
+            .unwrap_or_else(|| outlet::source_id(&identity.mac, mode)),
+        stream: StreamConfig::new(mode, rate).map_err(|e| anyhow!(e))?,
+        units,
+        side,
+        size: stream
+            .value("insole_size")
+            .and_then(|size| clap::ValueEnum::from_str(&size, true).ok())
+            .unwrap_or_default(),
+        derived: false,
+        calibration: None,
+        identity,
+        name: stream.name.clone(),
+    })
+}
+
+/// Parses the data of a stream record, `[mode, rate (u16)]`.
+pub fn stream_config(data: &[u8]) -> Result<StreamConfig> {
+    let [mode, r0, r1] = data else {
+        return Err(anyhow!("bad stream record {data:02x?}"));
+    };
+    let mode = StreamMode::from_id(*mode).ok_or_else(|| anyhow!("unknown mode {mode:#04x}"))?;
+    StreamConfig::new(mode, u16::from_le_bytes([*r0, *r1])).map_err(|e| anyhow!(e))
+}
+
+impl Row {
+    pub fn timestamp(&self) -> f64 {
+        match self {
+            Row::Sample { timestamp, .. } => *timestamp,
+            Row::Marker(marker) => marker.timestamp,
+        }
+    }
+}
+
+fn mode_from_header(header: &str) -> Result<StreamMode> {
+    let labels: Vec<&str> = header.split(',').collect();
+    let [_, _, channels @ .., _] = labels.as_slice() else {
+        return Err(anyhow!("unexpected header `{header}`"));
+    };
+    [StreamMode::Pressure, StreamMode::Accelerometry]
+        .into_iter()
+        .find(|mode| mode.channel_labels() == channels)
+        .ok_or_else(|| anyhow!("header `{header}` matches no stream mode"))
+}
+
+/// Parses a row as written by the daemon's CSV sink, samples leave the
+/// marker empty and markers the counter and channels.
+fn parse_row(line: &str, mode: StreamMode) -> Result<Row> {
+    let mut fields = line.splitn(mode.channel_count() + 3, ',');
+    let timestamp: f64 = fields
+        .next()
+        .unwrap_or_default()
+        .parse()
+        .context("bad timestamp")?;
+    let counter = fields.next().unwrap_or_default();
+    let values: Vec<&str> = fields.by_ref().take(mode.channel_count()).collect();
+    let marker = fields.next().unwrap_or_default();
+    if counter.is_empty() {
+        return Ok(Row::Marker(Marker {
+            timestamp,
+            text: unquote(marker),
+        }));
+    }
+    let counter = Some(counter.parse().context("bad counter")?);
+    let values = values
+        .iter()
+        .map(|v| v.parse::<f32>().context("bad value"))
+        .collect::<Result<Vec<_>>>()?;
+    if values.len() != mode.channel_count() {
+        return Err(anyhow!("expected {} values", mode.channel_count()));
+    }
+    Ok(Row::Sample {
+        timestamp,
+        counter,
+        values,
+    })
+}
+
+fn unquote(field: &str) -> String {
+    match field.strip_prefix('"').and_then(|f| f.strip_suffix('"')) {
+        Some(inner) => inner.replace("\"\"", "\""),
+        None => field.to_string(),
+    }
+}
+
+/// The session log and its entry of the device that wrote `file`, if the
+/// file sits in a session directory.
+pub fn session_entry(file: &Path) -> Option<(SessionLog, SessionDevice)> {
+    let json = fs::read(file.parent()?.join("session.json")).ok()?;
+    let log: SessionLog = serde_json::from_slice(&json).ok()?;
+    let name = file.file_name()?;
+    let device = log
+        .devices
+        .iter()
+        .rev()
+        .find(|device| device.file.as_deref().and_then(Path::file_name) == Some(name))?
+        .clone();
+    Some((log, device))
+}
+
+pub fn meta_from_session(device: SessionDevice) -> Result<StreamMeta> {
+    Ok(StreamMeta {
+        stream_type: device.mode.stream_type().to_string(),
+        source_id: outlet::source_id(&device.identity.mac, device.mode),
+        stream: StreamConfig::new(device.mode, device.rate).map_err(|e| anyhow!(e))?,
+        units: device.units,
+        side: device.side,
+        size: InsoleSize::default(),
+        derived: false,
+        calibration: device.calibration,
+        identity: device.identity,
+        name: device.name,
+    })
+}
+
+/// Builds metadata from the file alone, the rate from the spacing of the
+/// samples and the units from whether the values have decimals.
+pub fn guess_meta(file: &Path, mode: StreamMode, rows: &[Row]) -> Result<StreamMeta> {
+    let timestamps: Vec<f64> = rows
+        .iter()
+        .filter_map(|row| match row {
+            Row::Sample { timestamp, .. } => Some(*timestamp),
+            Row::Marker(_) => None,
+        })
+        .collect();
+    let mut periods: Vec<f64> = timestamps.windows(2).map(|w| w[1] - w[0]).collect();
+    periods.sort_by(f64::total_cmp);
+    let period = periods
+        .get(periods.len() / 2)
+        .copied()
+        .filter(|p| *p > 0.0)
+        .ok_or_else(|| anyhow!("too few samples to tell the rate"))?;
+    let rate = closest_rate(mode, 1.0 / period)?;
+    let physical = rows.iter().any(|row| match row {
+        Row::Sample { values, .. } => values.iter().any(|v| v.fract() != 0.0),
+        Row::Marker(_) => false,
+    });
+    let stream = StreamConfig::new(mode, rate).map_err(|e| anyhow!(e))?;
+    let units = if physical {
+        Units::Physical
+    } else {
+        Units::Raw
+    };
+    Ok(file_meta(file, stream, units))
+}
+
+fn closest_rate(mode: StreamMode, rate: f64) -> Result<u16> {
+    mode.supported_rates()
+        .min_by(|a, b| {
+            let error = |r: &u16| (rate - *r as f64).abs();
+            error(a).total_cmp(&error(b))
+        })
+        .ok_or_else(|| anyhow!("{mode} mode supports no rate"))
+}
+
+/// Metadata for a file of an unknown device, named after the file.
+pub fn file_meta(file: &Path, stream: StreamConfig, units: Units) -> StreamMeta {
+    let name = file
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("replay")
+        .to_string();
+    let identity = DeviceIdentity {
+        mac: format!("replay_{name}"),
+        ..Default::default()
+    };
+    StreamMeta {
+        stream_type: stream.mode().stream_type().to_string(),
+        source_id: outlet::source_id(&identity.mac, stream.mode()),
+        stream,
+        units,
+        side: Side::from_name(&name),
+        size: InsoleSize::default(),
+        derived: false,
+        calibration: None,
+        identity,
+        name,
+    }
+}
@@ -0,0 +1,367 @@
+//! Reading of XDF files as written by LabRecorder, enough to get the mitch
+//! streams and markers back out of a recording.
+//!
+//! An XDF file is `XDF:` followed by chunks laid out as `[length bytes (1, 4
+//! or 8), length, tag (u16), content..]` with little endian numbers. See
+//! <https://github.com/sccn/xdf/wiki/Specifications>.
+
+use anyhow::{Context, Result, anyhow};
+use std::{collections::HashMap, fs, path::Path};
+
+const MAGIC: &[u8; 4] = b"XDF:";
+
+const TAG_STREAM_HEADER: u16 = 2;
+const TAG_SAMPLES: u16 = 3;
+const TAG_CLOCK_OFFSET: u16 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Int8,
+    Int16,
+    Int32,
+    Int64,
+    Float32,
+    Double64,
+    String,
+}
+
+impl Format {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "int8" => Format::Int8,
+            "int16" => Format::Int16,
+            "int32" => Format::Int32,
+            "int64" => Format::Int64,
+            "float32" => Format::Float32,
+            "double64" => Format::Double64,
+            "string" => Format::String,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Values {
+    Numeric(Vec<f64>),
+    Strings(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct XdfStream {
+    /// The stream's info as XML, including its description.
+    pub header: String,
+    pub name: String,
+    pub format: Format,
+    pub channel_count: usize,
+    pub nominal_srate: f64,
+    /// Timestamps on the recording computer's clock.
+    pub samples: Vec<(f64, Values)>,
+    /// Pairs of collection time and offset to add to the stream's
+    /// timestamps, measured while recording.
+    clock_offsets: Vec<(f64, f64)>,
+}
+
+impl XdfStream {
+    /// First value of `tag` in the stream's header, e.g. `source_id` or
+    /// `side` from the description.
+    pub fn value(&self, tag: &str) -> Option<String> {
+        xml_value(&self.header, tag)
+    }
+
+    /// Moves every timestamp onto the recording computer's clock, using the
+    /// offsets measured closest in time.
+    fn synchronize(&mut self) {
+        if self.clock_offsets.is_empty() {
+            return;
+        }
+        let offsets = &self.clock_offsets;
+        for (timestamp, _) in &mut self.samples {
+            let next = offsets.partition_point(|(t, _)| t < timestamp);
+            let offset = match (
+                next.checked_sub(1).map(|i| offsets[i]),
+                offsets.get(next).copied(),
+            ) {
+                (Some((t0, o0)), Some((t1, o1))) if t1 > t0 => {
+                    o0 + (o1 - o0) * (*timestamp - t0) / (t1 - t0)
+                }
+                (Some((_, o)), _) | (None, Some((_, o))) => o,
+                (None, None) => 0.0,
+            };
+            *timestamp += offset;
+        }
+    }
+}
+
+/// Reads every stream in the XDF file at `path`.
+pub fn read(path: &Path) -> Result<Vec<XdfStream>> {
+    let bytes = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    parse(&bytes).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Vec<XdfStream>> {
+    let mut reader = Reader(
+        bytes
+            .strip_prefix(MAGIC.as_slice())
+            .ok_or_else(|| anyhow!("not an XDF file"))?,
+    );
+    let mut streams: HashMap<u32, XdfStream> = HashMap::new();
+    let mut order = Vec::new();
+    while !reader.0.is_empty() {
+        let len = reader.varlen()?;
+        let mut chunk = Reader(reader.take(len)?);
+        let tag = u16::from_le_bytes(chunk.array()?);
+        match tag {
+            TAG_STREAM_HEADER => {
+                let id = u32::from_le_bytes(chunk.array()?);
+                let header = String::from_utf8_lossy(chunk.0).into_owned();
+                let format = xml_value(&header, "channel_format")
+                    .and_then(|f| Format::parse(&f))
+                    .ok_or_else(|| anyhow!("stream {id} has no known channel format"))?;
+                let channel_count = xml_value(&header, "channel_count")
+                    .and_then(|c| c.parse().ok())
+                    .ok_or_else(|| anyhow!("stream {id} has no channel count"))?;
+                let stream = XdfStream {
+                    name: xml_value(&header, "name").unwrap_or_default(),
+                    nominal_srate: xml_value(&header, "nominal_srate")
+                        .and_then(|r| r.parse().ok())
+                        .unwrap_or_default(),
+                    header,
+                    format,
+                    channel_count,
+                    samples: Vec::new(),
+                    clock_offsets: Vec::new(),
+                };
+                if streams.insert(id, stream).is_none() {
+                    order.push(id);
+                }
+            }
+            TAG_SAMPLES => {
+                let id = u32::from_le_bytes(chunk.array()?);
+                let stream = streams
+                    .get_mut(&id)
+                    .ok_or_else(|| anyhow!("samples of stream {id} before its header"))?;
+                read_samples(&mut chunk, stream)
+                    .with_context(|| format!("bad samples of stream {id}"))?;
+            }
+            TAG_CLOCK_OFFSET => {
+                let id = u32::from_le_bytes(chunk.array()?);
+                let time = f64::from_le_bytes(chunk.array()?);
+                let offset = f64::from_le_bytes(chunk.array()?);
+                if let Some(stream) = streams.get_mut(&id) {
+                    stream.clock_offsets.push((time, offset));
+                }
+            }
+            // The file header, boundaries and footers hold nothing needed.
+            _ => {}
+        }
+    }
+    Ok(order
+        .into_iter()
+        .filter_map(|id| streams.remove(&id))
+        .map(|mut stream| {
+            stream.synchronize();
+            stream
+        })
+        .collect())
+}
+
+fn read_samples(chunk: &mut Reader, stream: &mut XdfStream) -> Result<()> {
+    let count = chunk.varlen()?;
+    let period = if stream.nominal_srate > 0.0 {
+        1.0 / stream.nominal_srate
+    } else {
+        0.0
+    };
+    for _ in 0..count {
+        // Samples without a timestamp follow the previous one at the
+        // nominal rate.
+        let timestamp = match chunk.array::<1>()?[0] {
+            8 => f64::from_le_bytes(chunk.array()?),
+            0 => stream
+                .samples
+                .last()
+                .map(|(t, _)| t + period)
+                .unwrap_or(0.0),
+            other => return Err(anyhow!("timestamp of {other} bytes")),
+        };
+        let n = stream.channel_count;
+        let values = match stream.format {
+            Format::String => Values::Strings(
+                (0..n)
+                    .map(|_| {
+                        let len = chunk.varlen()?;
+                        Ok(String::from_utf8_lossy(chunk.take(len)?).into_owned())
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            format => Values::Numeric(
+                (0..n)
+                    .map(|_| {
+                        Ok(match format {
+                            Format::Int8 => i8::from_le_bytes(chunk.array()?) as f64,
+                            Format::Int16 => i16::from_le_bytes(chunk.array()?) as f64,
+                            Format::Int32 => i32::from_le_bytes(chunk.array()?) as f64,
+                            Format::Int64 => i64::from_le_bytes(chunk.array()?) as f64,
+                            Format::Float32 => f32::from_le_bytes(chunk.array()?) as f64,
+                            Format::Double64 => f64::from_le_bytes(chunk.array()?),
+                            Format::String => unreachable!(),
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+        };
+        stream.samples.push((timestamp, values));
+    }
+    Ok(())
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let (head, tail) = self
+            .0
+            .split_at_checked(len)
+            .ok_or_else(|| anyhow!("file ends within a chunk"))?;
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("N bytes taken"))
+    }
+
+    /// A length prefixed by how many bytes it takes.
+    fn varlen(&mut self) -> Result<usize> {
+        let len = match self.array::<1>()?[0] {
+            1 => self.array::<1>()?[0] as u64,
+            4 => u32::from_le_bytes(self.array()?) as u64,
+            8 => u64::from_le_bytes(self.array()?),
+            other => return Err(anyhow!("length of {other} bytes")),
+        };
+        usize::try_from(len).map_err(|_| anyhow!("length {len} too large"))
+    }
+}
+
+/// Text of the first `<tag>` element in `xml`, good enough for the flat
+/// headers LSL writes.
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{tag}>"))?;
+    Some(
+        xml[start..end]
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(file: &mut Vec<u8>, tag: u16, content: &[u8]) {
+        file.push(4);
+        file.extend_from_slice(&(content.len() as u32 + 2).to_le_bytes());
+        file.extend_from_slice(&tag.to_le_bytes());
+        file.extend_from_slice(content);
+    }
+
+    fn header(id: u32, name: &str, format: &str, channels: usize, srate: f64) -> Vec<u8> {
+        let mut content = id.to_le_bytes().to_vec();
+        content.extend_from_slice(
+            format!(
+                "<?xml version=\"1.0\"?><info><name>{name}</name><type>Pressure</type>\
+                 <channel_count>{channels}</channel_count><nominal_srate>{srate}</nominal_srate>\
+                 <channel_format>{format}</channel_format><desc><setup><side>left</side>\
+                 </setup></desc></info>"
+            )
+            .as_bytes(),
+        );
+        content
+    }
+
+    fn file() -> Vec<u8> {
+        let mut file = MAGIC.to_vec();
+        chunk(
+            &mut file,
+            1,
+            b"<?xml version=\"1.0\"?><info><version>1.0</version></info>",
+        );
+        chunk(&mut file, 2, &header(1, "mitch_L", "int16", 2, 10.0));
+        chunk(&mut file, 2, &header(2, "mitch_markers", "string", 1, 0.0));
+
+        // Three samples, only the first with a timestamp.
+        let mut samples = 1u32.to_le_bytes().to_vec();
+        samples.extend_from_slice(&[1, 3]);
+        samples.push(8);
+        samples.extend_from_slice(&5.0f64.to_le_bytes());
+        for (i, (a, b)) in [(1i16, -1i16), (2, -2), (3, -3)].into_iter().enumerate() {
+            if i > 0 {
+                samples.push(0);
+            }
+            samples.extend_from_slice(&a.to_le_bytes());
+            samples.extend_from_slice(&b.to_le_bytes());
+        }
+        chunk(&mut file, 3, &samples);
+
+        let mut markers = 2u32.to_le_bytes().to_vec();
+        markers.extend_from_slice(&[1, 1, 8]);
+        markers.extend_from_slice(&5.15f64.to_le_bytes());
+        markers.extend_from_slice(&[1, 5]);
+        markers.extend_from_slice(b"start");
+        chunk(&mut file, 3, &markers);
+
+        let mut offset = 1u32.to_le_bytes().to_vec();
+        offset.extend_from_slice(&5.0f64.to_le_bytes());
+        offset.extend_from_slice(&0.5f64.to_le_bytes());
+        chunk(&mut file, 4, &offset);
+        // Footers and boundaries are skipped.
+        chunk(&mut file, 6, &1u32.to_le_bytes());
+        file
+    }
+
+    #[test]
+    fn reads_streams_samples_and_markers() {
+        let streams = parse(&file()).unwrap();
+        assert_eq!(streams.len(), 2);
+        let data = &streams[0];
+        assert_eq!(data.name, "mitch_L");
+        assert_eq!(data.format, Format::Int16);
+        assert_eq!(data.channel_count, 2);
+        assert_eq!(data.value("side").as_deref(), Some("left"));
+        // Deduced at the nominal rate, then shifted by the clock offset.
+        for ((timestamp, _), expected) in data.samples.iter().zip([5.5, 5.6, 5.7]) {
+            assert!(
+                (timestamp - expected).abs() < 1e-9,
+                "{timestamp} != {expected}"
+            );
+        }
+        assert_eq!(data.samples[2].1, Values::Numeric(vec![3.0, -3.0]));
+
+        let markers = &streams[1];
+        assert_eq!(markers.format, Format::String);
+        assert_eq!(
+            markers.samples,
+            [(5.15, Values::Strings(vec!["start".to_string()]))]
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let file = file();
+        assert!(parse(&file[..file.len() - 3]).is_err());
+        assert!(parse(b"CSV:").is_err());
+    }
+
+    #[test]
+    fn unescapes_xml_values() {
+        assert_eq!(
+            xml_value("<a><name>L &amp; R</name></a>", "name").as_deref(),
+            Some("L & R")
+        );
+        assert_eq!(xml_value("<a></a>", "name"), None);
+    }
+}